@@ -0,0 +1,1212 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata, OpenOptions, copy, create_dir_all, hard_link, metadata, remove_file, rename, set_permissions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, NaiveDate};
+use filetime::FileTime;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{info, debug, error, trace, warn};
+use walkdir::WalkDir;
+
+use crate::BackupConfig;
+use crate::checksum::ChecksumAlgo;
+use crate::lock;
+use crate::encryption::{self, ENCRYPTED_SUFFIX};
+use crate::eol::{self, EolReader};
+use crate::events::{self, Event};
+use crate::hook;
+use crate::limiter;
+use crate::manifest::{self, ManifestEntry};
+use crate::metrics;
+use crate::progress::{self, ProgressReader};
+use crate::pruner;
+use crate::retention;
+use crate::sink::{BackupSink, LocalSink};
+use crate::throttle::ThrottledReader;
+use crate::versions::{self, TIMESTAMP_FORMAT};
+
+/// Time to wait before retrying a failed copy the first time (it doubles at every retry)
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Free space left on the destination after every copy, on top of the size of the source
+const FREE_SPACE_MARGIN: u64 = 1024 * 1024;
+
+/// Infix of the names of the temporary files, between the name of the file they replace and the PID of their writer
+const TEMP_INFIX: &str = ".fwb-tmp.";
+
+/// Extension appended to the name of compressed backups
+pub(crate) const COMPRESSED_SUFFIX: &str = ".gz";
+
+/// Size of the chunks copied at a time when copying by hand (throttled copies, say), without [`BackupConfig::buffer_size`]
+const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Size of the chunks compared when checking that a backup holds the content of a source
+const COMPARISON_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Format of the names of the directories of the days, with [`BackupConfig::destination_by_date`]
+const DATE_DIRECTORY_FORMAT: &str = "%Y-%m-%d";
+
+/// Extension appended to the name of the backups moved aside by [`BackupConfig::quarantine_on_error`]
+pub(crate) const QUARANTINE_SUFFIX: &str = ".corrupt";
+
+/// Extension appended to the name of the source for the link to its newest backup, with [`BackupConfig::latest_link`]
+pub(crate) const LATEST_SUFFIX: &str = ".latest";
+
+/// Extension appended to the name of the backups about to be overwritten, with [`BackupConfig::keep_previous`]
+const PREVIOUS_SUFFIX: &str = ".prev";
+
+/// What is known about the last backup of a file.
+pub(crate) struct LastBackup {
+	/// Content hash of the backup, unknown if the source couldn't be hashed
+	hash: Option<Vec<u8>>,
+	/// When the backup was made
+	time: Instant
+}
+
+/// What is known about the backups made so far.
+#[derive(Default)]
+pub(crate) struct History {
+	/// The last backup of each file, keyed by the path of its unversioned backup
+	last_backups: HashMap<PathBuf, LastBackup>,
+	/// A backup of each content hash in each destination, to link new backups to with [`BackupConfig::dedup`]
+	by_hash: HashMap<(PathBuf, Vec<u8>), PathBuf>,
+	/// Timestamp and counter of the last versioned upload of each file, keyed as `last_backups`
+	uploaded_versions: HashMap<PathBuf, (String, u32)>,
+	/// Identity of each source when last seen, to detect rotations and truncations
+	identities: HashMap<PathBuf, FileIdentity>,
+	/// What became of the backups of each source, keyed by the source and its destination
+	pub(crate) tallies: HashMap<(PathBuf, PathBuf), SourceTally>,
+	/// Size of the last copy, until [`History::record`] counts it
+	copied_bytes: Option<u64>,
+	/// Bytes not copied since startup, the sources being unchanged
+	pub(crate) unchanged_bytes: u64,
+	/// Bytes not stored since startup, the backups being linked to identical ones with [`BackupConfig::dedup`]
+	pub(crate) deduplicated_bytes: u64
+}
+
+/// What became of the backups of a source (of each file, for directories) since startup.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SourceTally {
+	pub(crate) copied: usize,
+	pub(crate) failed: usize,
+	/// Backups skipped, the file being unchanged, throttled or out of the size limits
+	pub(crate) skipped: usize,
+	/// When the last copy was made, and its size
+	pub(crate) last_copy: Option<(DateTime<Local>, u64)>
+}
+
+/// What tells a file apart from a rotated or truncated version of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileIdentity {
+	size: u64,
+	/// Device and inode numbers, where available
+	inode: Option<(u64, u64)>
+}
+
+impl FileIdentity {
+	/// Identity of the file described by `metadata`.
+	fn of(metadata: &Metadata) -> FileIdentity {
+		#[cfg(unix)]
+		let inode = {
+			use std::os::unix::fs::MetadataExt;
+			Some((metadata.dev(), metadata.ino()))
+		};
+		#[cfg(not(unix))]
+		let inode = None;
+		FileIdentity { size: metadata.len(), inode }
+	}
+
+	/// Check whether a file that was `self` became `current` by being replaced (as by a log rotation) or truncated.
+	fn is_replaced_by(&self, current: &FileIdentity) -> bool {
+		current.size < self.size || (self.inode.is_some() && current.inode != self.inode)
+	}
+}
+
+impl History {
+	/// An empty history, for a fresh start.
+	pub(crate) fn new() -> History {
+		History::default()
+	}
+
+	/// Count the `outcome` of a backup of `source` (or of a file inside it) into `destination`.
+	pub(crate) fn record(&mut self, source: &Path, destination: &Path, outcome: Outcome) {
+		let tally = self.tallies.entry((source.to_path_buf(), destination.to_path_buf())).or_default();
+		match outcome {
+			Outcome::Copied => {
+				tally.copied += 1;
+				tally.last_copy = Some((Local::now(), self.copied_bytes.take().unwrap_or(0)));
+			},
+			Outcome::Failed => tally.failed += 1,
+			Outcome::Unchanged => tally.skipped += 1
+		}
+	}
+}
+
+/// How a backup attempt ended.
+///
+/// The variants are ordered by importance, so that the outcome of a group of backups is the
+/// maximum of the single outcomes: failed if any failed, otherwise copied if any was copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Outcome {
+	/// There was no change since the last backup, so nothing was copied
+	Unchanged,
+	/// The source has been copied
+	Copied,
+	/// The copy failed (the reason has already been logged)
+	Failed
+}
+
+/// Copy `source` into `destination_dir` (part of the destination `root` of its watch) and apply the
+/// retention policy afterwards.
+///
+/// The copy is skipped when the content of `source` matches its last backup: `history` caches the
+/// hash of the last backup so that it doesn't have to be re-read. It's skipped as well when the
+/// last backup is more recent than [`BackupConfig::min_interval`]. With [`BackupConfig::dedup`] it's
+/// linked to an identical backup found in `root`, so that every destination stays a copy of its own.
+pub(crate) fn backup(source: &Path, root: &Path, destination_dir: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	backup_named(source, source, root, destination_dir, config, history)
+}
+
+/// Copy `source` into `destination_dir` as [`backup`] does, naming the backup after the file name of `named`.
+///
+/// `named` only has to share the directory of `source`: with [`BackupConfig::flatten`] the file
+/// name also holds the directories of `source` inside its tree.
+pub(crate) fn backup_named(source: &Path, named: &Path, root: &Path, destination_dir: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	// Compressing small files costs more than it saves, and can even make them larger
+	let min_compressed_size = config.compress_min_size.filter(|_| config.compress);
+	let uncompressed;
+	let config = match min_compressed_size {
+		Some(min_size) if metadata(source).is_ok_and(|metadata| metadata.len() < min_size) => {
+			uncompressed = BackupConfig { compress: false, ..config.clone() };
+			&uncompressed
+		},
+		_ => config
+	};
+	// Plain backups always have the name of the source, the cache is keyed by that name whatever the naming scheme
+	// ("named" is named after a confirmed file so the unwrap is secure)
+	let latest_backup = destination_dir.join(named.file_name().unwrap());
+	if is_throttled(source, &latest_backup, config, history) || is_out_of_size_limits(source, config) {
+		return Outcome::Unchanged;
+	}
+
+	if let Ok(metadata) = metadata(source) {
+		let identity = FileIdentity::of(&metadata);
+		let previous = history.identities.insert(source.to_path_buf(), identity);
+		if previous.is_some_and(|previous| previous.is_replaced_by(&identity)) {
+			// Full copies start from scratch anyway, and atomic saves replace the file every time
+			if config.append || previous.is_some_and(|previous| identity.size < previous.size) {
+				info!("`{:?}` has been rotated or truncated, backing it up from the start", source);
+			} else {
+				debug!("`{:?}` has been replaced", source);
+			}
+			// Appending to the backup of the previous file would mix the two
+			if config.append && !config.dry_run {
+				if let Err(error) = remove_file(&latest_backup) {
+					debug!("Could not remove `{:?}`: {:?}", latest_backup, error);
+				}
+			}
+		}
+	}
+
+	// Appending is meant to avoid reading the whole source: it's only compared by size
+	if config.append && is_fully_appended(source, &latest_backup) {
+		debug!("No new content, skipping backup");
+		skipped_unchanged(source, config, history);
+		return Outcome::Unchanged;
+	}
+	let hash = if config.append {
+		None
+	} else {
+		match hash_file(source, config) {
+			Ok(hash) => Some(hash),
+			Err(error) => {
+				debug!("Could not hash `{:?}`: {:?}", source, error);
+				None
+			}
+		}
+	};
+
+	if let Some(hash) = &hash {
+		let unchanged = match history.last_backups.get(&latest_backup) {
+			Some(last_backup) => last_backup.hash.as_ref() == Some(hash),
+			// Only plain backups can be compared against directly, otherwise rely on the cache
+			None if !config.versioned && !config.compress && config.encryption.is_none() && config.dest_template.is_none()
+				&& config.normalize_eol.is_none() => {
+				hash_file(&latest_backup, config).map(|previous_hash| previous_hash == *hash).unwrap_or(false)
+			},
+			// Unless asked to open the existing backups, on startup (normalized backups never match their sources)
+			None if config.verify_on_startup && config.normalize_eol.is_none() => {
+				stored_backup_hash(named, destination_dir, config).as_ref() == Some(hash)
+			},
+			None => false
+		};
+		if unchanged {
+			debug!("No change detected, skipping backup");
+			skipped_unchanged(source, config, history);
+			return Outcome::Unchanged;
+		}
+	}
+
+	let destination_file_path = backup_file_path(destination_dir, named, config);
+	debug!("Copying `{:?}` into `{:?}`", source, destination_file_path);
+	if let Some(count) = config.keep_previous.filter(|_| !config.versioned && !config.dry_run) {
+		keep_previous(&destination_file_path, count);
+	}
+	let linked = match &hash {
+		Some(hash) if config.dedup => {
+			link_duplicate(source, history.by_hash.get(&(root.to_path_buf(), hash.clone())), &destination_file_path, config)
+		},
+		_ => None
+	};
+	let start = Instant::now();
+	let result = if let Some(bytes) = linked {
+		Ok(bytes)
+	} else if config.dry_run {
+		metadata(source).map(|metadata| {
+			info!("DRY RUN: would copy `{:?}` -> `{:?}`, {} bytes", source, destination_file_path, metadata.len());
+			metadata.len()
+		})
+	} else {
+		create_parent_dirs(destination_dir, &destination_file_path)
+			.and_then(|_| check_free_space(source, named, root, destination_dir, &destination_file_path, config))
+			.and_then(|_| {
+				// Templates can put the backups into subdirectories
+				let sink = LocalSink::new(destination_dir, config);
+				let name = destination_file_path.strip_prefix(destination_dir).unwrap_or(&destination_file_path);
+				with_retries(source, config, || store_verified(source, &sink, name, config, hash.as_deref()))
+			})
+	};
+	let bytes = match result {
+		Ok(bytes) => bytes,
+		Err(error) => {
+			debug!("{:?}", error);
+			let message = format!("{}{}", error, copy_error_hint(&error));
+			error!("Copy of `{:?}` into `{:?}` failed. Reason: {}", source, destination_dir, message);
+			report_failure(source, message, config);
+			return Outcome::Failed;
+		}
+	};
+	if linked.is_none() && !config.dry_run {
+		progress::log_throughput(source, &destination_file_path.to_string_lossy(), bytes, start.elapsed());
+	}
+	if linked.is_some() && !config.dry_run {
+		history.deduplicated_bytes += bytes;
+		if config.metrics_addr.is_some() {
+			metrics::deduplicated(bytes);
+		}
+	}
+	if min_compressed_size.is_some() && !config.versioned && !config.dry_run {
+		remove_other_variant(destination_dir, named, config);
+	}
+
+	if config.preserve_metadata && !config.dry_run {
+		preserve_metadata(source, &destination_file_path);
+	}
+
+	if config.latest_link && config.versioned && !config.dry_run {
+		update_latest_link(named, destination_dir, &destination_file_path);
+	}
+
+	if config.json && !config.dry_run {
+		events::emit(&Event::Backup { source, destination: &destination_file_path, bytes });
+	}
+	if (config.notify || config.webhook.is_some()) && !config.dry_run {
+		limiter::backed_up(source, &destination_file_path.to_string_lossy(), bytes, config);
+	}
+	if config.on_change_command.is_some() && !config.dry_run {
+		hook::backed_up(source, &destination_file_path.to_string_lossy(), bytes, config);
+	}
+	if config.metrics_addr.is_some() && !config.dry_run {
+		metrics::backed_up(bytes);
+	}
+
+	if config.keep.is_some() || config.keep_per_day.is_some() || config.max_size.is_some() {
+		pruner::prune(root, destination_dir, named, config);
+	}
+
+	if config.manifest && !config.dry_run {
+		let entry = ManifestEntry {
+			source: source.to_path_buf(),
+			// Relative to the destination, where templates can put subdirectories
+			backup_file: destination_file_path.strip_prefix(destination_dir).unwrap_or(&destination_file_path).to_string_lossy().into_owned(),
+			timestamp: Local::now().to_rfc3339(),
+			bytes,
+			compressed: config.compress,
+			// The hash of the content would let anyone check guesses of an encrypted one
+			sha256: hash.as_deref().filter(|_| config.encryption.is_none() && ChecksumAlgo::of(config) == ChecksumAlgo::Sha256).map(hex)
+		};
+		if let Err(error) = manifest::record(destination_dir, entry) {
+			debug!("{:?}", error);
+			warn!("Could not update the manifest of `{:?}`. Reason: {}", destination_dir, error);
+		}
+	}
+
+	if config.dedup {
+		// The backup that was there before has been replaced, whatever its hash
+		history.by_hash.retain(|_, backup| *backup != destination_file_path);
+		if let Some(hash) = &hash {
+			history.by_hash.insert((root.to_path_buf(), hash.clone()), destination_file_path.clone());
+		}
+	}
+	history.last_backups.insert(latest_backup, LastBackup { hash, time: Instant::now() });
+	history.copied_bytes = Some(bytes);
+	Outcome::Copied
+}
+
+/// Upload `source` to `sink`, under `directory` (a relative path with `/` separators, empty for the top level).
+///
+/// Like with [`backup`], the upload is skipped when the content of `source` matches its last upload,
+/// or when it's more recent than [`BackupConfig::min_interval`]. The options working on the stored
+/// backups (retention, compression, deduplication, verification...) don't apply to remote sinks.
+pub(crate) fn upload(source: &Path, sink: &dyn BackupSink, directory: &str, config: &BackupConfig, history: &mut History) -> Outcome {
+	upload_named(source, source, sink, directory, config, history)
+}
+
+/// Upload `source` to `sink` as [`upload`] does, naming the backup after the file name of `named`.
+pub(crate) fn upload_named(source: &Path, named: &Path, sink: &dyn BackupSink, directory: &str, config: &BackupConfig, history: &mut History) -> Outcome {
+	// The cache is keyed by the location of the unversioned backup, as for local backups
+	// ("named" is named after a confirmed file so the unwrap is secure)
+	let latest_backup = PathBuf::from(sink.location(Path::new(&remote_name(directory, &named.file_name().unwrap().to_string_lossy()))));
+	if is_throttled(source, &latest_backup, config, history) || is_out_of_size_limits(source, config) {
+		return Outcome::Unchanged;
+	}
+
+	let hash = match hash_file(source, config) {
+		Ok(hash) => Some(hash),
+		Err(error) => {
+			debug!("Could not hash `{:?}`: {:?}", source, error);
+			None
+		}
+	};
+	let unchanged = history.last_backups.get(&latest_backup).is_some_and(|last_backup| hash.is_some() && last_backup.hash == hash);
+	if unchanged {
+		debug!("No change detected, skipping upload");
+		skipped_unchanged(source, config, history);
+		return Outcome::Unchanged;
+	}
+
+	// Listing the bucket to find free names would cost a request per upload: the counter follows the last upload
+	// made by this process in the same second, the ones made before it started having older timestamps
+	let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+	let counter = match history.uploaded_versions.get(&latest_backup) {
+		Some((last_timestamp, last_counter)) if *last_timestamp == timestamp && config.dest_template.is_none() => last_counter + 1,
+		_ => 0
+	};
+	let name = remote_name(directory, &backup_file_name(named, config, "", &timestamp, counter));
+	let location = sink.location(Path::new(&name));
+	debug!("Uploading `{:?}` to `{}`", source, location);
+	let start = Instant::now();
+	let result = if config.dry_run {
+		metadata(source).map(|metadata| {
+			info!("DRY RUN: would upload `{:?}` -> `{}`, {} bytes", source, location, metadata.len());
+			metadata.len()
+		})
+	} else {
+		with_retries(source, config, || sink.store(source, Path::new(&name)))
+	};
+	let bytes = match result {
+		Ok(bytes) => bytes,
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Upload of `{:?}` to `{}` failed. Reason: {}", source, location, error);
+			report_failure(source, error.to_string(), config);
+			return Outcome::Failed;
+		}
+	};
+
+	if !config.dry_run {
+		progress::log_throughput(source, &location, bytes, start.elapsed());
+		if config.json {
+			events::emit(&Event::Backup { source, destination: Path::new(&location), bytes });
+		}
+		if config.notify || config.webhook.is_some() {
+			limiter::backed_up(source, &location, bytes, config);
+		}
+		if config.on_change_command.is_some() {
+			hook::backed_up(source, &location, bytes, config);
+		}
+		if config.metrics_addr.is_some() {
+			metrics::backed_up(bytes);
+		}
+	}
+	if config.versioned {
+		history.uploaded_versions.insert(latest_backup.clone(), (timestamp, counter));
+	}
+	history.last_backups.insert(latest_backup, LastBackup { hash, time: Instant::now() });
+	history.copied_bytes = Some(bytes);
+	Outcome::Copied
+}
+
+/// Name of the backup `name` inside the remote `directory`.
+fn remote_name(directory: &str, name: &str) -> String {
+	if directory.is_empty() {
+		name.to_string()
+	} else {
+		format!("{}/{}", directory, name)
+	}
+}
+
+/// Check whether the last backup of `source` (whose unversioned backup is `latest_backup`) is more
+/// recent than [`BackupConfig::min_interval`], so that this one has to be skipped.
+fn is_throttled(source: &Path, latest_backup: &Path, config: &BackupConfig, history: &History) -> bool {
+	if let (Some(min_interval), Some(last_backup)) = (config.min_interval, history.last_backups.get(latest_backup)) {
+		if last_backup.time.elapsed() < min_interval {
+			trace!("Last backup of `{:?}` less than {:?} ago, skipping backup", source, min_interval);
+			skipped(source, "throttled", config);
+			return true;
+		}
+	}
+	false
+}
+
+/// Check whether `source` is larger than [`BackupConfig::max_file_size`] or smaller than
+/// [`BackupConfig::min_file_size`], so that it has to be skipped.
+fn is_out_of_size_limits(source: &Path, config: &BackupConfig) -> bool {
+	if config.max_file_size.is_none() && config.min_file_size.is_none() {
+		return false;
+	}
+	// The copy will report a source that can't be read
+	let size = match metadata(source) {
+		Ok(metadata) => metadata.len(),
+		Err(_) => return false
+	};
+	let reason = match (config.max_file_size, config.min_file_size) {
+		(Some(max_file_size), _) if size > max_file_size => format!("larger than the maximum of {}", progress::format_bytes(max_file_size)),
+		(_, Some(min_file_size)) if size < min_file_size => format!("smaller than the minimum of {}", progress::format_bytes(min_file_size)),
+		_ => return false
+	};
+	info!("Skipping `{:?}` ({}): {}", source, progress::format_bytes(size), reason);
+	skipped(source, "size", config);
+	true
+}
+
+/// Report the skipped backup of `source` on the event stream and in the metrics, if enabled.
+fn skipped(source: &Path, reason: &str, config: &BackupConfig) {
+	if config.json {
+		events::emit(&Event::Skipped { source, reason });
+	}
+	if config.metrics_addr.is_some() {
+		metrics::skipped();
+	}
+}
+
+/// Report the backup of `source` skipped as unchanged as [`skipped`] does, counting its size as saved.
+fn skipped_unchanged(source: &Path, config: &BackupConfig, history: &mut History) {
+	// The size of the source is what a copy would have cost
+	let size = metadata(source).map(|metadata| metadata.len()).unwrap_or(0);
+	history.unchanged_bytes += size;
+	if config.metrics_addr.is_some() {
+		metrics::unchanged(size);
+	}
+	skipped(source, "unchanged", config);
+}
+
+/// Report the failed backup of `source` on the event stream, with a notification, to the webhook
+/// and in the metrics, if enabled.
+fn report_failure(source: &Path, message: String, config: &BackupConfig) {
+	if config.metrics_addr.is_some() {
+		metrics::failed();
+	}
+	if config.notify || config.webhook.is_some() {
+		limiter::failed(source, &message, config);
+	}
+	if config.json {
+		events::emit(&Event::Error { source, message });
+	}
+}
+
+/// Check that `destination_dir` has room for the backup of `source` (into `destination`), plus
+/// [`FREE_SPACE_MARGIN`], so that a copy never fills the destination or stops halfway.
+///
+/// With [`BackupConfig::evict_when_full`] the oldest versioned backups of `source` (named after `named`)
+/// are deleted to make room, those of the other days of `root` included.
+fn check_free_space(source: &Path, named: &Path, root: &Path, destination_dir: &Path, destination: &Path, config: &BackupConfig) -> io::Result<()> {
+	let source_size = metadata(source)?.len();
+	// Only the new content is written when appending
+	let size = if config.append {
+		source_size.saturating_sub(metadata(destination).map(|metadata| metadata.len()).unwrap_or(0))
+	} else {
+		source_size
+	};
+	let needed = size.saturating_add(FREE_SPACE_MARGIN);
+	let available = match fs2::available_space(destination_dir) {
+		Ok(available) => available,
+		// Not knowing is no reason to skip the backup
+		Err(error) => {
+			debug!("Could not get the free space of `{:?}`: {:?}", destination_dir, error);
+			return Ok(());
+		}
+	};
+	if available >= needed {
+		return Ok(());
+	}
+
+	if config.evict_when_full && retention::make_room(root, destination_dir, named, needed, config) {
+		return Ok(());
+	}
+	Err(io::Error::new(
+		ErrorKind::StorageFull,
+		format!("not enough free space on the destination, {} bytes needed but {} available", needed, available)
+	))
+}
+
+/// Create the directories between `destination_dir` and the backup `destination`, put into
+/// subdirectories by [`BackupConfig::dest_template`].
+fn create_parent_dirs(destination_dir: &Path, destination: &Path) -> io::Result<()> {
+	match destination.parent() {
+		Some(parent) if parent != destination_dir => create_dir_all(parent),
+		_ => Ok(())
+	}
+}
+
+/// Keep the backup `destination`, about to be overwritten, as `<name>.prev`: the previous ones are
+/// renamed to `<name>.prev.1`, `<name>.prev.2`... and only the `count` most recent are kept.
+///
+/// `destination` stays in place (`<name>.prev` is a hard link to it, or a copy), so that a failed
+/// backup doesn't leave it missing. Failures are only reported: they never stop the backup.
+fn keep_previous(destination: &Path, count: usize) {
+	if !destination.is_file() {
+		return;
+	}
+	let previous = |index: usize| {
+		let mut name = destination.as_os_str().to_owned();
+		name.push(PREVIOUS_SUFFIX);
+		if index > 0 {
+			name.push(format!(".{}", index));
+		}
+		PathBuf::from(name)
+	};
+	// The oldest one is overwritten by the next oldest
+	for index in (1..count).rev() {
+		let (from, to) = (previous(index - 1), previous(index));
+		if from.exists() {
+			if let Err(error) = rename(&from, &to) {
+				debug!("{:?}", error);
+				warn!("Could not rename `{:?}` to `{:?}`. Reason: {}", from, to, error);
+			}
+		}
+	}
+
+	let latest = previous(0);
+	// Linking over an existing file isn't possible
+	if let Err(error) = remove_file(&latest) {
+		if error.kind() != ErrorKind::NotFound {
+			debug!("Could not remove `{:?}`: {:?}", latest, error);
+		}
+	}
+	let result = hard_link(destination, &latest).or_else(|error| {
+		debug!("Could not link `{:?}` to `{:?}` ({}), copying it instead", latest, destination, error);
+		copy(destination, &latest).map(|_| ())
+	});
+	match result {
+		Ok(()) => debug!("Kept the previous backup as `{:?}`", latest),
+		Err(error) => {
+			debug!("{:?}", error);
+			warn!("Could not keep the previous backup `{:?}`. Reason: {}", destination, error);
+		}
+	}
+}
+
+/// Hard link `destination` to `existing`, a backup with the same hash as `source`, returning its size.
+///
+/// Nothing is linked (and the backup has to be copied) when there is no such backup, when its
+/// content isn't the one of `source` after all, or when linking fails: it may have been changed or
+/// deleted meanwhile, or the filesystem may not support hard links.
+fn link_duplicate(source: &Path, existing: Option<&PathBuf>, destination: &Path, config: &BackupConfig) -> Option<u64> {
+	let existing = existing.filter(|existing| existing.as_path() != destination)?;
+	let size = metadata(existing).ok()?.len();
+	// The hash may be a short one, and the backup may have been overwritten since
+	match is_backup_of(existing, source, config) {
+		Ok(true) => (),
+		Ok(false) => {
+			debug!("`{:?}` isn't a backup of `{:?}` anymore, not linking to it", existing, source);
+			return None;
+		},
+		Err(error) => {
+			debug!("Could not compare `{:?}` to `{:?}`: {:?}", existing, source, error);
+			return None;
+		}
+	}
+	if config.dry_run {
+		info!("DRY RUN: would link `{:?}` -> `{:?}`, {} bytes", destination, existing, size);
+		return Some(size);
+	}
+
+	// Linking over an existing file isn't possible
+	if let Err(error) = remove_file(destination) {
+		if error.kind() != ErrorKind::NotFound {
+			debug!("Could not remove `{:?}` to link it: {:?}", destination, error);
+			return None;
+		}
+	}
+	match hard_link(existing, destination) {
+		Ok(()) => {
+			debug!("Linked `{:?}` to the identical backup `{:?}`", destination, existing);
+			Some(size)
+		},
+		Err(error) => {
+			debug!("Could not link `{:?}` to `{:?}`, copying it instead: {:?}", destination, existing, error);
+			None
+		}
+	}
+}
+
+/// Run `store` (storing the backup of `source`), retrying up to [`BackupConfig::retries`] times with an
+/// exponential backoff (starting from [`RETRY_BASE_DELAY`]), to get through transient failures of the destination.
+fn with_retries<F: FnMut() -> io::Result<u64>>(source: &Path, config: &BackupConfig, mut store: F) -> io::Result<u64> {
+	let mut delay = RETRY_BASE_DELAY;
+	let mut attempt = 0;
+	loop {
+		match store() {
+			Err(error) if attempt < config.retries => {
+				attempt += 1;
+				warn!("Backup of `{:?}` failed: {}. Retrying in {:?} ({} of {})", source, error, delay, attempt, config.retries);
+				thread::sleep(delay);
+				delay *= 2;
+			},
+			result => return result
+		}
+	}
+}
+
+/// Store `source` into `sink` as `name` and, if enabled, verify that the backup matches `source`.
+///
+/// `source_hash` is the hash of `source` when it's already known. A backup that doesn't
+/// match is stored once more before giving up.
+fn store_verified(source: &Path, sink: &LocalSink, name: &Path, config: &BackupConfig, source_hash: Option<&[u8]>) -> io::Result<u64> {
+	let destination = &sink.path(name);
+	let bytes = match sink.store(source, name) {
+		Ok(bytes) => bytes,
+		// Appends write to the backup itself, that may be left with only part of the new content
+		Err(error) => {
+			if config.append {
+				quarantine(destination, config);
+			}
+			return Err(error);
+		}
+	};
+	if !config.verify {
+		return Ok(bytes);
+	}
+
+	let source_hash = match source_hash {
+		Some(source_hash) => source_hash.to_vec(),
+		None => hash_file(source, config)?
+	};
+	if backup_hash(destination, config)? == source_hash {
+		debug!("Backup `{:?}` verified", destination);
+		return Ok(bytes);
+	}
+
+	error!("Backup `{:?}` doesn't match `{:?}`, copying it again", destination, source);
+	let bytes = sink.store(source, name)?;
+	if backup_hash(destination, config)? == source_hash {
+		debug!("Backup `{:?}` verified", destination);
+		Ok(bytes)
+	} else {
+		quarantine(destination, config);
+		Err(io::Error::new(ErrorKind::InvalidData, "the backup doesn't match the source"))
+	}
+}
+
+/// Remove the backup of `source` in `destination_dir` stored the other way (compressed or not) than
+/// `config` says, left behind by a backup made on the other side of [`BackupConfig::compress_min_size`].
+fn remove_other_variant(destination_dir: &Path, source: &Path, config: &BackupConfig) {
+	let other = backup_file_path(destination_dir, source, &BackupConfig { compress: !config.compress, ..config.clone() });
+	if !other.exists() {
+		return;
+	}
+	match remove_file(&other) {
+		Ok(()) => debug!("Removed `{:?}`, replaced by a backup stored the other way", other),
+		Err(error) => {
+			debug!("{:?}", error);
+			warn!("Could not remove the outdated backup `{:?}`. Reason: {}", other, error);
+		}
+	}
+}
+
+/// Move the backup `destination`, left incomplete or wrong by a failed copy, aside to a
+/// [`QUARANTINE_SUFFIX`] file with [`BackupConfig::quarantine_on_error`].
+fn quarantine(destination: &Path, config: &BackupConfig) {
+	if !config.quarantine_on_error || !destination.is_file() {
+		return;
+	}
+	let mut quarantine_name = destination.as_os_str().to_owned();
+	quarantine_name.push(QUARANTINE_SUFFIX);
+	let quarantine_path = PathBuf::from(quarantine_name);
+	match replace(destination, &quarantine_path) {
+		Ok(()) => warn!("Moved the bad backup `{:?}` aside to `{:?}`", destination, quarantine_path),
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not move the bad backup `{:?}` aside. Reason: {}", destination, error);
+		}
+	}
+}
+
+/// Point the `<name>.latest` link of `source` in `destination_dir` to its newest backup, `backup`.
+///
+/// The new link is created aside and renamed over the previous one, so the link always exists
+/// once made. Where links can't be created, a copy of `backup` is made instead.
+fn update_latest_link(source: &Path, destination_dir: &Path, backup: &Path) {
+	// "source" is a confirmed file, and "backup" is named after it, so the unwraps are secure
+	let mut link_name = source.file_name().unwrap().to_owned();
+	link_name.push(LATEST_SUFFIX);
+	let link = destination_dir.join(link_name);
+	// Relative, so that the destination can be moved around
+	let target = backup.file_name().unwrap();
+	let result = write_atomically(&link, |temp_path| match symlink_file(Path::new(target), temp_path) {
+		Err(error) if cfg!(not(unix)) => {
+			debug!("Could not link `{:?}` to `{:?}` ({}), copying it instead", temp_path, backup, error);
+			copy(backup, temp_path).map(|_| ())
+		},
+		result => result
+	});
+	if let Err(error) = result {
+		debug!("{:?}", error);
+		warn!("Could not point `{:?}` to the latest backup of `{:?}`. Reason: {}", link, source, error);
+	}
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+	std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+	std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink_file(_target: &Path, _link: &Path) -> io::Result<()> {
+	Err(io::Error::other("symbolic links aren't supported"))
+}
+
+/// Copy (or compress, or encrypt) `source` into `destination`, returning the size of `source`.
+pub(crate) fn store(source: &Path, destination: &Path, config: &BackupConfig) -> io::Result<u64> {
+	if let Some(passphrase) = &config.encryption {
+		return encryption::encrypt(source_reader(source, config)?, destination, passphrase, config.compress)
+			.map(|(original_size, encrypted_size)| {
+				debug!("Encrypted {} bytes into {} bytes", original_size, encrypted_size);
+				original_size
+			});
+	}
+	if config.compress {
+		return compress(source_reader(source, config)?, destination).map(|(original_size, compressed_size)| {
+			debug!("Compressed {} bytes into {} bytes", original_size, compressed_size);
+			original_size
+		});
+	}
+
+	// Normalized text files don't have the size of their source, unlike copies (appends are never normalized)
+	if config.normalize_eol.is_some() && !config.append {
+		return write_atomically(destination, |temp_path| {
+			let mut destination_file = File::create(temp_path)?;
+			let written = copy_buffered(&mut source_reader(source, config)?, &mut destination_file, config)?;
+			destination_file.sync_all()?;
+			set_permissions(temp_path, metadata(source)?.permissions())?;
+			Ok(written)
+		}).inspect(|written| debug!("Copied {} bytes", written));
+	}
+
+	// Other than appends, backups are written to a temporary file renamed into place: an interrupted
+	// copy never leaves a truncated backup, and hard links to the previous backup (from deduplication)
+	// are replaced rather than written through
+	if config.append {
+		append(source, destination, config)
+	} else if config.max_bytes_per_sec.is_some() || config.progress || config.buffer_size.is_some() {
+		write_atomically(destination, |temp_path| copy_file(source, temp_path, config))
+			.inspect(|filesize| debug!("Copied {} bytes", filesize))
+	} else {
+		write_atomically(destination, |temp_path| {
+			let filesize = copy(source, temp_path)?;
+			OpenOptions::new().write(true).open(temp_path)?.sync_all()?;
+			Ok(filesize)
+		}).inspect(|filesize| debug!("Copied {} bytes", filesize))
+	}
+}
+
+/// Directory the backups made today into `destination_dir` go to: a subdirectory named after the day
+/// with [`BackupConfig::destination_by_date`], otherwise `destination_dir` itself.
+pub(crate) fn dated_directory(destination_dir: &Path, config: &BackupConfig) -> PathBuf {
+	if config.destination_by_date {
+		destination_dir.join(Local::now().format(DATE_DIRECTORY_FORMAT).to_string())
+	} else {
+		destination_dir.to_path_buf()
+	}
+}
+
+/// Check whether `name` is the name of a directory of the day (see [`dated_directory`]).
+pub(crate) fn is_dated_directory(name: &OsStr) -> bool {
+	name.to_str().is_some_and(|name| NaiveDate::parse_from_str(name, DATE_DIRECTORY_FORMAT).is_ok())
+}
+
+/// Create `destination_dir` (and its parents) if needed, returning whether it succeeded.
+pub(crate) fn create_destination_dir(destination_dir: &Path, config: &BackupConfig) -> bool {
+	if config.dry_run {
+		if !destination_dir.is_dir() {
+			info!("DRY RUN: would create directory `{:?}`", destination_dir);
+		}
+		return true;
+	}
+	match create_dir_all(destination_dir) {
+		Ok(()) => true,
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Destination directory `{:?}` setup failed. Reason: {}", destination_dir, error);
+			false
+		}
+	}
+}
+
+/// Path of the temporary file `destination` is written to before being renamed into place:
+/// `.<name>.fwb-tmp.<PID>`, so that the leftovers of a crashed process can be told apart from the files
+/// being written, and from the files of the user.
+pub(crate) fn temp_path(destination: &Path) -> PathBuf {
+	let mut temp_name = OsString::from(".");
+	temp_name.push(destination.file_name().unwrap_or(destination.as_os_str()));
+	temp_name.push(format!("{}{}", TEMP_INFIX, process::id()));
+	destination.with_file_name(temp_name)
+}
+
+/// Check whether `file_name` is the name of a temporary file (see [`temp_path`]).
+pub(crate) fn is_temp_file(file_name: &str) -> bool {
+	temp_file_writer(file_name).is_some()
+}
+
+/// PID of the process writing the temporary file named `file_name`, unless it's not one.
+fn temp_file_writer(file_name: &str) -> Option<u32> {
+	let (_, pid) = file_name.strip_prefix('.')?.rsplit_once(TEMP_INFIX)?;
+	pid.parse().ok()
+}
+
+/// Write `destination` through `write`, that writes (and flushes) the whole content into the given
+/// temporary file, renamed over `destination` once complete.
+pub(crate) fn write_atomically<T, F: FnOnce(&Path) -> io::Result<T>>(destination: &Path, write: F) -> io::Result<T> {
+	let temp_path = temp_path(destination);
+	let result = write(&temp_path).and_then(|written| replace(&temp_path, destination).map(|_| written));
+	if result.is_err() {
+		// Best effort: the temporary file may not even exist
+		let _ = remove_file(&temp_path);
+	}
+	result
+}
+
+/// Delete the temporary files left in `destination_dir` (and in its whole tree with `recursive`) by
+/// processes that aren't running anymore.
+pub(crate) fn remove_stray_temp_files(destination_dir: &Path, recursive: bool, config: &BackupConfig) {
+	let entries = WalkDir::new(destination_dir)
+		.max_depth(if recursive { usize::MAX } else { 1 })
+		.into_iter()
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.file_type().is_file());
+	for entry in entries {
+		let writer = entry.file_name().to_str().and_then(temp_file_writer);
+		match writer {
+			Some(pid) if pid != process::id() && !lock::is_running(pid) => (),
+			_ => continue
+		}
+		if config.dry_run {
+			info!("DRY RUN: would delete the leftover temporary file `{:?}`", entry.path());
+			continue;
+		}
+		match remove_file(entry.path()) {
+			Ok(()) => info!("Deleted the leftover temporary file `{:?}`", entry.path()),
+			Err(error) => {
+				debug!("{:?}", error);
+				warn!("Could not delete the leftover temporary file `{:?}`. Reason: {}", entry.path(), error);
+			}
+		}
+	}
+}
+
+/// Check whether `backup` is as long as `source`, which for a file that only grows means it's up to date.
+fn is_fully_appended(source: &Path, backup: &Path) -> bool {
+	match (metadata(source), metadata(backup)) {
+		(Ok(source_metadata), Ok(backup_metadata)) => source_metadata.len() == backup_metadata.len(),
+		_ => false
+	}
+}
+
+/// Reader of the content of `source`, reading at most [`BackupConfig::max_bytes_per_sec`] and
+/// logging its progress with [`BackupConfig::progress`].
+fn source_reader(source: &Path, config: &BackupConfig) -> io::Result<Box<dyn Read>> {
+	let file = File::open(source)?;
+	let size = file.metadata()?.len();
+	let reader = partial_reader(file, source, size, config);
+	match config.normalize_eol {
+		// Binary files are copied as they are
+		Some(ending) if eol::is_text(source)? => Ok(Box::new(EolReader::new(reader, ending))),
+		_ => Ok(reader)
+	}
+}
+
+/// Reader of the next `size` bytes of `file` (the open `source`), as for [`source_reader`].
+fn partial_reader(file: File, source: &Path, size: u64, config: &BackupConfig) -> Box<dyn Read> {
+	let reader: Box<dyn Read> = match config.max_bytes_per_sec {
+		Some(rate) => Box::new(ThrottledReader::new(file.take(size), rate)),
+		None => Box::new(file.take(size))
+	};
+	if config.progress {
+		Box::new(ProgressReader::new(reader, source, size))
+	} else {
+		reader
+	}
+}
+
+/// Append to `destination` the content of `source` past its length, returning the number of bytes appended.
+///
+/// A source shorter than its backup has been truncated (or replaced): it's copied whole again.
+fn append(source: &Path, destination: &Path, config: &BackupConfig) -> io::Result<u64> {
+	let mut source_file = File::open(source)?;
+	let source_len = source_file.metadata()?.len();
+	let offset = match metadata(destination) {
+		Ok(metadata) => metadata.len(),
+		Err(error) if error.kind() == ErrorKind::NotFound => 0,
+		Err(error) => return Err(error)
+	};
+	if source_len < offset {
+		info!("`{:?}` is shorter than its backup, copying it whole again", source);
+		return copy(source, destination).inspect(|filesize| debug!("Copied {} bytes", filesize));
+	}
+
+	source_file.seek(SeekFrom::Start(offset))?;
+	let mut destination_file = OpenOptions::new().create(true).append(true).open(destination)?;
+	let appended = copy_buffered(&mut partial_reader(source_file, source, source_len - offset, config), &mut destination_file, config)?;
+	destination_file.sync_all()?;
+	debug!("Appended {} bytes", appended);
+	Ok(appended)
+}
+
+/// Copy `source` into `destination` (permissions included, like [`std::fs::copy`]) through
+/// [`source_reader`], returning the number of bytes copied.
+fn copy_file(source: &Path, destination: &Path, config: &BackupConfig) -> io::Result<u64> {
+	let source_file = File::open(source)?;
+	let source_metadata = source_file.metadata()?;
+	let mut destination_file = File::create(destination)?;
+	let copied = copy_buffered(&mut partial_reader(source_file, source, source_metadata.len(), config), &mut destination_file, config)?;
+	destination_file.sync_all()?;
+
+	// The source may have been truncated meanwhile: the next write will trigger another backup
+	let written = destination_file.metadata()?.len();
+	if written != copied || copied != source_metadata.len() {
+		return Err(io::Error::new(
+			ErrorKind::UnexpectedEof,
+			format!("copied {} bytes out of {}, {} written", copied, source_metadata.len(), written)
+		));
+	}
+	set_permissions(destination, source_metadata.permissions())?;
+	Ok(copied)
+}
+
+/// Copy everything `reader` reads into `writer`, [`BackupConfig::buffer_size`] bytes at a time
+/// (or [`DEFAULT_BUFFER_SIZE`]), returning the number of bytes copied.
+fn copy_buffered<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W, config: &BackupConfig) -> io::Result<u64> {
+	let mut buffer = vec![0; config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE)];
+	let mut copied = 0;
+	loop {
+		let read = match reader.read(&mut buffer) {
+			Ok(0) => return Ok(copied),
+			Ok(read) => read,
+			Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+			Err(error) => return Err(error)
+		};
+		writer.write_all(&buffer[..read])?;
+		copied += read as u64;
+	}
+}
+
+/// Format `bytes` (a hash) in hexadecimal.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compute the hash of the content of the file at `path`, with [`ChecksumAlgo::of`] `config`.
+fn hash_file(path: &Path, config: &BackupConfig) -> io::Result<Vec<u8>> {
+	ChecksumAlgo::of(config).hash_reader(File::open(path)?)
+}
+
+/// Check whether the original content of the backup at `path` is the content of `source`, byte for byte.
+fn is_backup_of(path: &Path, source: &Path, config: &BackupConfig) -> io::Result<bool> {
+	let mut backup = versions::open_backup(path, config.compress, config.encryption.is_some(), config.encryption.as_ref())?;
+	let mut source = File::open(source)?;
+	let (mut backup_chunk, mut source_chunk) = (vec![0; COMPARISON_CHUNK_SIZE], vec![0; COMPARISON_CHUNK_SIZE]);
+	loop {
+		let read = read_chunk(&mut source, &mut source_chunk)?;
+		if read_chunk(&mut backup, &mut backup_chunk)? != read || backup_chunk[..read] != source_chunk[..read] {
+			return Ok(false);
+		}
+		if read == 0 {
+			return Ok(true);
+		}
+	}
+}
+
+/// Fill `chunk` from `reader`, short only at its end, returning the number of bytes read.
+fn read_chunk<R: Read>(reader: &mut R, chunk: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+	while filled < chunk.len() {
+		match reader.read(&mut chunk[filled..]) {
+			Ok(0) => break,
+			Ok(read) => filled += read,
+			Err(error) if error.kind() == ErrorKind::Interrupted => (),
+			Err(error) => return Err(error)
+		}
+	}
+	Ok(filled)
+}
+
+/// Compute the hash of the original content of the backup at `path`.
+fn backup_hash(path: &Path, config: &BackupConfig) -> io::Result<Vec<u8>> {
+	ChecksumAlgo::of(config).hash_reader(versions::open_backup(path, config.compress, config.encryption.is_some(), config.encryption.as_ref())?)
+}
+
+/// Compute the hash of the original content of the most recent backup of `source` in
+/// `destination_dir`, if there is one (backups named after a template can't be found).
+fn stored_backup_hash(source: &Path, destination_dir: &Path, config: &BackupConfig) -> Option<Vec<u8>> {
+	if config.versioned {
+		let versions = versions::list(destination_dir, source).ok()?;
+		ChecksumAlgo::of(config).hash_reader(versions.last()?.open(config.encryption.as_ref()).ok()?).ok()
+	} else if config.dest_template.is_none() {
+		backup_hash(&backup_file_path(destination_dir, source, config), config).ok()
+	} else {
+		None
+	}
+}
+
+/// Gzip the content `reader` yields into `destination`, returning the original and the compressed sizes.
+///
+/// The archive is written to a temporary file next to `destination` and renamed into place only
+/// once complete, so that a crash mid-compression never leaves a truncated archive behind.
+fn compress<R: Read>(mut reader: R, destination: &Path) -> io::Result<(u64, u64)> {
+	write_atomically(destination, |temp_path| {
+		let mut encoder = GzEncoder::new(File::create(temp_path)?, Compression::default());
+		let original_size = io::copy(&mut reader, &mut encoder)?;
+		let compressed_file = encoder.finish()?;
+		compressed_file.sync_all()?;
+		Ok((original_size, compressed_file.metadata()?.len()))
+	})
+}
+
+/// Move the complete file `temp_path` over `destination`.
+///
+/// Renaming is atomic, but fails when the two paths are on different devices (or the destination
+/// filesystem doesn't support it, as some network shares): in that case the content is copied
+/// over and flushed to disk before removing `temp_path`.
+pub(crate) fn replace(temp_path: &Path, destination: &Path) -> io::Result<()> {
+	match rename(temp_path, destination) {
+		Err(error) if error.kind() == ErrorKind::CrossesDevices => {
+			debug!("Could not rename `{:?}` into `{:?}` ({}), copying it instead", temp_path, destination, error);
+			let mut temp_file = File::open(temp_path)?;
+			let mut destination_file = File::create(destination)?;
+			io::copy(&mut temp_file, &mut destination_file)?;
+			destination_file.sync_all()?;
+			remove_file(temp_path)
+		},
+		result => result
+	}
+}
+
+/// Give `destination` the modification time (and, on Unix, the permissions) of `source`.
+///
+/// The backup is already complete at this point, so failures are only reported.
+fn preserve_metadata(source: &Path, destination: &Path) {
+	let metadata = match metadata(source) {
+		Ok(metadata) => metadata,
+		Err(error) => {
+			debug!("{:?}", error);
+			warn!("Could not read the metadata of `{:?}`. Reason: {}", source, error);
+			return;
+		}
+	};
+
+	let mtime = FileTime::from_last_modification_time(&metadata);
+	if let Err(error) = filetime::set_file_mtime(destination, mtime) {
+		debug!("{:?}", error);
+		warn!("Could not set the modification time of `{:?}`. Reason: {}", destination, error);
+	}
+
+	#[cfg(unix)]
+	{
+		if let Err(error) = set_permissions(destination, metadata.permissions()) {
+			debug!("{:?}", error);
+			warn!("Could not set the permissions of `{:?}`. Reason: {}", destination, error);
+		}
+	}
+}
+
+/// Explanation of the most common reasons for a copy to fail, to append to the error message.
+fn copy_error_hint(error: &io::Error) -> &'static str {
+	match error.kind() {
+		ErrorKind::StorageFull => " (no space left on the destination device)",
+		ErrorKind::ReadOnlyFilesystem => " (the destination is on a read-only filesystem)",
+		ErrorKind::PermissionDenied => " (check the permissions of the source and the destination)",
+		_ => ""
+	}
+}
+
+/// Build the path of the backup file for `source` inside `destination_dir`.
+///
+/// The name is the one of the source, unless [`BackupConfig::dest_template`] is set, or
+/// [`BackupConfig::versioned`] is: then the name becomes `<stem>.<YYYY-MM-DD_HH-MM-SS>.<ext>` (or
+/// `<stem>.<YYYY-MM-DD_HH-MM-SS>` for files without extension) and a `-<N>` counter is appended
+/// to the timestamp if backups were made in the same second already, following the highest of their counters so
+/// that the names keep the order of the backups whatever was pruned in between.
+/// Compressed backups also get the `.gz` extension, and encrypted ones the `.enc` extension.
+pub(crate) fn backup_file_path(destination_dir: &Path, source: &Path, config: &BackupConfig) -> PathBuf {
+	let mut suffix = String::new();
+	if config.compress {
+		suffix.push_str(COMPRESSED_SUFFIX);
+	}
+	if config.encryption.is_some() {
+		suffix.push_str(ENCRYPTED_SUFFIX);
+	}
+
+	// Plain names are kept as they are, even if they aren't valid Unicode
+	// ("source" is a confirmed file so the unwrap is secure)
+	if !config.versioned && config.dest_template.is_none() {
+		let mut name = source.file_name().unwrap().to_owned();
+		name.push(&suffix);
+		return destination_dir.join(name);
+	}
+	let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+	let counter = if config.dest_template.is_none() { next_counter(destination_dir, source, &timestamp) } else { 0 };
+	destination_dir.join(backup_file_name(source, config, suffix.as_str(), &timestamp, counter))
+}
+
+/// Counter of the next versioned backup of `source` made in `destination_dir` at `timestamp` (0 for the first one).
+fn next_counter(destination_dir: &Path, source: &Path, timestamp: &str) -> u32 {
+	versions::list(destination_dir, source).unwrap_or_default().iter()
+		.filter(|version| version.timestamp.format(TIMESTAMP_FORMAT).to_string() == timestamp)
+		.map(|version| version.counter + 1)
+		.max()
+		.unwrap_or(0)
+}
+
+/// Build the name of the backup file for `source`, ending with `suffix`, as described in [`backup_file_path`].
+///
+/// Versioned names hold `timestamp`, and `counter` unless it's 0.
+fn backup_file_name(source: &Path, config: &BackupConfig, suffix: &str, timestamp: &str, counter: u32) -> String {
+	// "source" is a confirmed file so the unwrap is secure
+	let file_name = source.file_name().unwrap();
+	if let Some(template) = &config.dest_template {
+		return format!("{}{}", template.expand(source), suffix);
+	}
+	if !config.versioned {
+		return format!("{}{}", file_name.to_string_lossy(), suffix);
+	}
+
+	let stem = source.file_stem().unwrap_or(file_name).to_string_lossy();
+	let extension = source.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+	if counter == 0 {
+		format!("{}.{}{}{}", stem, timestamp, extension, suffix)
+	} else {
+		format!("{}.{}-{}{}{}", stem, timestamp, counter, extension, suffix)
+	}
+}
@@ -0,0 +1,94 @@
+//! The hashes of the contents, telling unchanged sources and duplicates apart and verifying the backups.
+
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::BackupConfig;
+
+/// Size of the chunks hashed at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An algorithm hashing the contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgo {
+	/// BLAKE3, cryptographic and fast
+	Blake3,
+	/// SHA-256, cryptographic, the one recorded in the manifests
+	Sha256,
+	/// XXH3 (64 bits), the fastest, but not cryptographic
+	Xxhash
+}
+
+impl ChecksumAlgo {
+	/// The algorithm named `name` (`blake3`, `sha256` or `xxhash`, case-insensitive).
+	pub fn parse(name: &str) -> Option<ChecksumAlgo> {
+		match name.to_lowercase().as_str() {
+			"blake3" => Some(ChecksumAlgo::Blake3),
+			"sha256" | "sha-256" => Some(ChecksumAlgo::Sha256),
+			"xxhash" | "xxh3" => Some(ChecksumAlgo::Xxhash),
+			_ => None
+		}
+	}
+
+	/// The algorithm of [`BackupConfig::checksum_algo`], by default a fast one to compare the sources
+	/// with their last backup, unless verifying, encrypting or recording the SHA-256 in the manifest.
+	pub(crate) fn of(config: &BackupConfig) -> ChecksumAlgo {
+		config.checksum_algo.unwrap_or(if config.verify || config.encryption.is_some() || config.manifest {
+			ChecksumAlgo::Sha256
+		} else {
+			ChecksumAlgo::Xxhash
+		})
+	}
+
+	/// Compute the hash of everything `reader` yields.
+	pub fn hash_reader<R: Read>(self, mut reader: R) -> io::Result<Vec<u8>> {
+		let mut hasher = Hasher::new(self);
+		let mut buffer = vec![0; CHUNK_SIZE];
+		loop {
+			let read = match reader.read(&mut buffer) {
+				Ok(0) => return Ok(hasher.finalize()),
+				Ok(read) => read,
+				Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+				Err(error) => return Err(error)
+			};
+			hasher.update(&buffer[..read]);
+		}
+	}
+}
+
+/// A hash being computed.
+enum Hasher {
+	Blake3(Box<blake3::Hasher>),
+	Sha256(Sha256),
+	Xxhash(Box<Xxh3>)
+}
+
+impl Hasher {
+	fn new(algo: ChecksumAlgo) -> Hasher {
+		match algo {
+			ChecksumAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+			ChecksumAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+			ChecksumAlgo::Xxhash => Hasher::Xxhash(Box::new(Xxh3::new()))
+		}
+	}
+
+	fn update(&mut self, bytes: &[u8]) {
+		match self {
+			Hasher::Blake3(hasher) => {
+				hasher.update(bytes);
+			},
+			Hasher::Sha256(hasher) => hasher.update(bytes),
+			Hasher::Xxhash(hasher) => hasher.update(bytes)
+		}
+	}
+
+	fn finalize(self) -> Vec<u8> {
+		match self {
+			Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+			Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+			Hasher::Xxhash(hasher) => hasher.digest().to_be_bytes().to_vec()
+		}
+	}
+}
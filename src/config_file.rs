@@ -0,0 +1,131 @@
+//! Loading of the watches from a TOML configuration file.
+//!
+//! The file contains one `[[watch]]` table for every source to back up:
+//!
+//! ```toml
+//! [[watch]]
+//! source = "/home/me/notes.txt"
+//! destination = "/mnt/backup"
+//! # Optional, in milliseconds
+//! debounce = 500
+//! # Optional, overriding the command line options for this source only
+//! versioned = true
+//! keep = 10
+//! # In bytes
+//! max_size = 1048576
+//! ```
+//!
+//! A source can be backed up into several destinations at once, with `destination = ["/mnt/backup", "s3://bucket/notes"]`.
+//!
+//! Sources sharing the same destination can also be listed in a plain file, one per line (see [`load_sources`]).
+//! Either file is read again when the process receives `SIGHUP` (see [`WatchesFile`]).
+
+use std::fs::read_to_string;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{BackupError, Watch};
+
+/// The file the watches are read from, kept to read them again on reload.
+#[derive(Debug, Clone)]
+pub enum WatchesFile {
+	/// A TOML configuration file, see [`load`]
+	Config(PathBuf),
+	/// A file listing sources (see [`load_sources`]) backed up into every one of `destinations`, along with `sources`
+	Sources {
+		path: PathBuf,
+		sources: Vec<PathBuf>,
+		destinations: Vec<PathBuf>
+	}
+}
+
+impl WatchesFile {
+	/// Path of the file.
+	pub fn path(&self) -> &Path {
+		match self {
+			WatchesFile::Config(path) => path,
+			WatchesFile::Sources { path, .. } => path
+		}
+	}
+
+	/// Read the watches from the file.
+	pub fn load(&self) -> Result<Vec<Watch>, BackupError> {
+		match self {
+			WatchesFile::Config(path) => load(path),
+			WatchesFile::Sources { path, sources, destinations } => {
+				let listed_sources = load_sources(path)?;
+				let watches = sources.iter().cloned()
+					.chain(listed_sources)
+					.flat_map(|source| destinations.iter().map(move |destination| Watch::new(source.clone(), destination.clone())))
+					.collect();
+				Ok(watches)
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	#[serde(default)]
+	watch: Vec<WatchEntry>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchEntry {
+	source: PathBuf,
+	destination: Destinations,
+	debounce: Option<u64>,
+	versioned: Option<bool>,
+	keep: Option<NonZeroUsize>,
+	max_size: Option<NonZeroU64>
+}
+
+/// The destination of a watch, or the list of its destinations.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Destinations {
+	One(PathBuf),
+	Many(Vec<PathBuf>)
+}
+
+/// Read the watches described by the configuration file at `path`.
+pub fn load(path: &Path) -> Result<Vec<Watch>, BackupError> {
+	let content = read_to_string(path).map_err(|error| BackupError::ConfigUnreadable(path.to_path_buf(), error))?;
+	let config: ConfigFile = toml::from_str(&content).map_err(|error| BackupError::ConfigInvalid(path.to_path_buf(), error))?;
+
+	// Every destination of a source gets its own watch, backed up independently of the others
+	let watches = config.watch.into_iter()
+		.flat_map(|entry| {
+			let destinations = match entry.destination {
+				Destinations::One(destination) => vec![destination],
+				Destinations::Many(destinations) => destinations
+			};
+			let watch = Watch {
+				source: entry.source,
+				destination: PathBuf::new(),
+				debounce: entry.debounce.map(Duration::from_millis),
+				versioned: entry.versioned,
+				keep: entry.keep.map(NonZeroUsize::get),
+				max_size: entry.max_size.map(NonZeroU64::get)
+			};
+			destinations.into_iter().map(move |destination| Watch { destination, ..watch.clone() })
+		})
+		.collect();
+	Ok(watches)
+}
+
+/// Read the sources listed in the file at `path`, one per line: blank lines and lines starting with `#` are skipped.
+pub fn load_sources(path: &Path) -> Result<Vec<PathBuf>, BackupError> {
+	let content = read_to_string(path).map_err(|error| BackupError::ConfigUnreadable(path.to_path_buf(), error))?;
+	let sources = content.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(PathBuf::from)
+		.collect();
+	Ok(sources)
+}
@@ -0,0 +1,172 @@
+//! The control socket of [`BackupConfig::control_socket`](crate::BackupConfig::control_socket),
+//! answering the queries of a running instance.
+//!
+//! The protocol is line-based: every line sent is a command (`status` or `backup-now`), answered
+//! with lines of text followed by an empty line.
+
+use std::fs::{Permissions, remove_file, set_permissions, symlink_metadata};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, TryIter, channel};
+use std::thread;
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+use log::{debug, info, warn};
+
+use crate::BackupError;
+use crate::backup::History;
+use crate::progress::format_bytes;
+
+/// Permissions of the socket: only the user running the process can send it commands
+const SOCKET_MODE: u32 = 0o600;
+
+/// A command understood by the control socket.
+pub(crate) enum Command {
+	/// Tell the uptime, the watched sources and their last backups
+	Status,
+	/// Back up every source right away, as on startup: the unchanged sources are skipped
+	BackupNow
+}
+
+impl Command {
+	/// The command named `name`.
+	fn parse(name: &str) -> Option<Command> {
+		match name {
+			"status" => Some(Command::Status),
+			"backup-now" => Some(Command::BackupNow),
+			_ => None
+		}
+	}
+}
+
+/// A command received by the control socket, waiting to be handled by the watch loop.
+pub(crate) struct Request {
+	pub(crate) command: Command,
+	reply: Sender<String>
+}
+
+impl Request {
+	/// Answer the request with `text`.
+	pub(crate) fn reply(self, text: String) {
+		// The client may be gone already
+		if self.reply.send(text).is_err() {
+			debug!("The control client left before the reply");
+		}
+	}
+}
+
+/// The listening control socket, removed once dropped.
+pub(crate) struct ControlSocket {
+	path: PathBuf,
+	requests: Receiver<Request>
+}
+
+impl ControlSocket {
+	/// The requests received since the last call.
+	pub(crate) fn requests(&self) -> TryIter<'_, Request> {
+		self.requests.try_iter()
+	}
+}
+
+impl Drop for ControlSocket {
+	fn drop(&mut self) {
+		if let Err(error) = remove_file(&self.path) {
+			debug!("Could not remove the control socket `{:?}`: {:?}", self.path, error);
+		}
+	}
+}
+
+/// Listen on the socket at `path`, answering its clients on threads of their own.
+///
+/// A socket left behind by a process that is gone is replaced; one still in use is an error, and so is
+/// anything else than a socket, never removed. Only the user running the process can connect to it.
+pub(crate) fn listen(path: &Path) -> Result<ControlSocket, BackupError> {
+	let failed = |error: io::Error| BackupError::ControlSocketFailed(path.to_path_buf(), error);
+	match symlink_metadata(path) {
+		Ok(metadata) if !metadata.file_type().is_socket() => {
+			return Err(failed(io::Error::new(ErrorKind::AlreadyExists, "it exists already, and isn't a socket")));
+		},
+		Ok(_) => {
+			if UnixStream::connect(path).is_ok() {
+				return Err(failed(io::Error::new(ErrorKind::AddrInUse, "another process is listening on it")));
+			}
+			remove_file(path).map_err(failed)?;
+		},
+		Err(error) if error.kind() == ErrorKind::NotFound => (),
+		Err(error) => return Err(failed(error))
+	}
+	let listener = UnixListener::bind(path).map_err(failed)?;
+	if let Err(error) = set_permissions(path, Permissions::from_mode(SOCKET_MODE)) {
+		let _ = remove_file(path);
+		return Err(failed(error));
+	}
+	info!("Listening for commands on `{:?}`", path);
+	let (sender, requests) = channel();
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					let sender = sender.clone();
+					thread::spawn(move || {
+						if let Err(error) = serve(stream, &sender) {
+							debug!("Control client error: {:?}", error);
+						}
+					});
+				},
+				Err(error) => {
+					debug!("{:?}", error);
+					warn!("Could not accept a control client. Reason: {}", error);
+				}
+			}
+		}
+	});
+	Ok(ControlSocket { path: path.to_path_buf(), requests })
+}
+
+/// Answer the commands of the client `stream`, handled through `sender` by the watch loop.
+fn serve(stream: UnixStream, sender: &Sender<Request>) -> io::Result<()> {
+	let mut writer = stream.try_clone()?;
+	for line in BufReader::new(stream).lines() {
+		let line = line?;
+		let name = line.trim();
+		if name.is_empty() {
+			continue;
+		}
+		let answer = match Command::parse(name) {
+			Some(command) => {
+				let (reply, answer) = channel();
+				if sender.send(Request { command, reply }).is_err() {
+					// The watch loop is over
+					return Ok(());
+				}
+				match answer.recv() {
+					Ok(answer) => answer,
+					Err(_) => return Ok(())
+				}
+			},
+			None => format!("error: unknown command `{}`, expected status or backup-now", name)
+		};
+		writeln!(writer, "{}\n", answer)?;
+	}
+	Ok(())
+}
+
+/// The answer to [`Command::Status`]: the `uptime`, and the backups of each of the `sources` (with their destination).
+pub(crate) fn status(uptime: Duration, sources: &[(&Path, &Path)], history: &History) -> String {
+	let mut lines = vec![format!("uptime: {}s", uptime.as_secs()), format!("sources: {}", sources.len())];
+	for (source, destination) in sources {
+		let tally = history.tallies.get(&(source.to_path_buf(), destination.to_path_buf())).copied().unwrap_or_default();
+		let last_copy = match tally.last_copy {
+			Some((time, bytes)) => format!("last backup at {} ({})", time.to_rfc3339_opts(SecondsFormat::Secs, false), format_bytes(bytes)),
+			None => "no backup yet".to_string()
+		};
+		lines.push(format!(
+			"{} -> {}: {} ok, {} failed, {} skipped, {}",
+			source.display(), destination.display(), tally.copied, tally.failed, tally.skipped, last_copy
+		));
+	}
+	lines.join("\n")
+}
@@ -0,0 +1,97 @@
+//! Backups encrypted with a passphrase, as [age](https://age-encryption.org) files.
+//!
+//! Every backup gets its own random salt and file key, so encrypted backups can't be compared with
+//! each other without the passphrase. Being standard age files, they can also be decrypted with
+//! `age --decrypt`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::iter;
+use std::path::Path;
+
+use age::{DecryptError, Decryptor, Encryptor};
+use age::scrypt;
+use age::secrecy::SecretString;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::backup;
+
+/// Extension appended to the name of encrypted backups (after the one of compressed backups)
+pub(crate) const ENCRYPTED_SUFFIX: &str = ".enc";
+
+/// Cost of the key derivation (scrypt with N = 2^WORK_FACTOR, about a fifth of a second): it's paid
+/// for every backup, so it's lower than the one second age targets by default
+const WORK_FACTOR: u8 = 16;
+
+/// Highest key derivation cost accepted when decrypting, so that a forged backup can't take hours to open
+const MAX_WORK_FACTOR: u8 = 22;
+
+/// Passphrase the backups are encrypted with.
+#[derive(Clone)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+	/// Wrap `passphrase`, so that it never shows up in the logs.
+	pub fn new(passphrase: String) -> Passphrase {
+		Passphrase(passphrase)
+	}
+
+	fn secret(&self) -> SecretString {
+		SecretString::from(self.0.clone())
+	}
+}
+
+impl fmt::Debug for Passphrase {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Passphrase(..)")
+	}
+}
+
+/// Encrypt the content `reader` yields (gzipping it first with `compress`) into `destination`,
+/// and return the original and the stored sizes.
+///
+/// As for compression, the backup is written to a temporary file renamed into place once complete.
+pub(crate) fn encrypt<R: Read>(mut reader: R, destination: &Path, passphrase: &Passphrase, compress: bool) -> io::Result<(u64, u64)> {
+	backup::write_atomically(destination, |temp_path| {
+		let mut recipient = scrypt::Recipient::new(passphrase.secret());
+		recipient.set_work_factor(WORK_FACTOR);
+		let encryptor = Encryptor::with_recipients(iter::once(&recipient as _)).map_err(io::Error::other)?;
+		let mut writer = encryptor.wrap_output(File::create(temp_path)?)?;
+
+		let original_size = if compress {
+			let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+			let original_size = io::copy(&mut reader, &mut encoder)?;
+			encoder.finish()?;
+			original_size
+		} else {
+			io::copy(&mut reader, &mut writer)?
+		};
+		let encrypted_file = writer.finish()?;
+		encrypted_file.sync_all()?;
+		Ok((original_size, encrypted_file.metadata()?.len()))
+	})
+}
+
+/// Reader of the decrypted content of `file`, an encrypted backup.
+///
+/// A wrong passphrase is reported as a [`ErrorKind::PermissionDenied`] error.
+pub(crate) fn decrypt(file: File, passphrase: &Passphrase) -> io::Result<impl Read> {
+	let mut identity = scrypt::Identity::new(passphrase.secret());
+	identity.set_max_work_factor(MAX_WORK_FACTOR);
+	Decryptor::new_buffered(BufReader::new(file))
+		.and_then(|decryptor| decryptor.decrypt(iter::once(&identity as _)))
+		.map_err(|error| match error {
+			DecryptError::Io(error) => error,
+			DecryptError::DecryptionFailed | DecryptError::NoMatchingKeys | DecryptError::KeyDecryptionFailed => {
+				io::Error::new(ErrorKind::PermissionDenied, "wrong passphrase")
+			},
+			error => io::Error::new(ErrorKind::InvalidData, error.to_string())
+		})
+}
+
+/// Error reading an encrypted backup without a passphrase.
+pub(crate) fn missing_passphrase() -> io::Error {
+	io::Error::new(ErrorKind::InvalidInput, "the backup is encrypted, and no passphrase was given")
+}
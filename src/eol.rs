@@ -0,0 +1,117 @@
+//! Normalization of the line endings of the text files being backed up.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How many bytes at the start of a file are checked to tell text from binary
+const TEXT_SAMPLE_SIZE: u64 = 8192;
+
+/// Size of the chunks read from the source
+const CHUNK_SIZE: usize = 8192;
+
+/// A style of line endings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+	/// `\n`, as on Unix
+	Lf,
+	/// `\r\n`, as on Windows
+	Crlf
+}
+
+impl LineEnding {
+	/// The style named `name` (`lf` or `crlf`, case-insensitive).
+	pub fn parse(name: &str) -> Option<LineEnding> {
+		match name.to_lowercase().as_str() {
+			"lf" => Some(LineEnding::Lf),
+			"crlf" => Some(LineEnding::Crlf),
+			_ => None
+		}
+	}
+
+	fn bytes(self) -> &'static [u8] {
+		match self {
+			LineEnding::Lf => b"\n",
+			LineEnding::Crlf => b"\r\n"
+		}
+	}
+}
+
+/// Check whether `source` looks like a text file: no NUL byte in its first [`TEXT_SAMPLE_SIZE`] bytes.
+pub(crate) fn is_text(source: &Path) -> io::Result<bool> {
+	let mut sample = Vec::new();
+	File::open(source)?.take(TEXT_SAMPLE_SIZE).read_to_end(&mut sample)?;
+	Ok(!sample.contains(&0))
+}
+
+/// A reader turning the `\n` and `\r\n` line endings read from `inner` into `ending`.
+///
+/// Lone `\r` are left as they are.
+pub(crate) struct EolReader<R> {
+	inner: R,
+	ending: LineEnding,
+	/// Whether the last byte read from `inner` was a `\r`, not written yet
+	pending_cr: bool,
+	/// Normalized bytes not returned yet, from `position` on
+	normalized: Vec<u8>,
+	position: usize
+}
+
+impl<R: Read> EolReader<R> {
+	pub(crate) fn new(inner: R, ending: LineEnding) -> EolReader<R> {
+		EolReader { inner, ending, pending_cr: false, normalized: Vec::new(), position: 0 }
+	}
+
+	/// Read and normalize the next chunk of `inner`, returning whether there was anything left.
+	fn fill(&mut self) -> io::Result<bool> {
+		let mut chunk = [0; CHUNK_SIZE];
+		self.normalized.clear();
+		self.position = 0;
+		// Retried until some output comes, or the end: a chunk made of a single `\r` has none yet
+		while self.normalized.is_empty() {
+			let read = self.inner.read(&mut chunk)?;
+			if read == 0 {
+				if self.pending_cr {
+					self.pending_cr = false;
+					self.normalized.push(b'\r');
+				}
+				break;
+			}
+			for &byte in &chunk[..read] {
+				match byte {
+					b'\n' => {
+						self.pending_cr = false;
+						self.normalized.extend_from_slice(self.ending.bytes());
+					},
+					b'\r' => {
+						if self.pending_cr {
+							self.normalized.push(b'\r');
+						}
+						self.pending_cr = true;
+					},
+					_ => {
+						if self.pending_cr {
+							self.pending_cr = false;
+							self.normalized.push(b'\r');
+						}
+						self.normalized.push(byte);
+					}
+				}
+			}
+		}
+		Ok(!self.normalized.is_empty())
+	}
+}
+
+impl<R: Read> Read for EolReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.position == self.normalized.len() && !self.fill()? {
+			return Ok(0);
+		}
+		let available = &self.normalized[self.position..];
+		let count = available.len().min(buf.len());
+		buf[..count].copy_from_slice(&available[..count]);
+		self.position += count;
+		Ok(count)
+	}
+}
@@ -0,0 +1,116 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Reasons why [`run`](crate::run) can stop before watching anything.
+#[derive(Debug)]
+pub enum BackupError {
+	/// No source was given at all
+	NoSource,
+	/// The source file does not exist
+	SourceNotFound(PathBuf),
+	/// The source file exists but can't be read
+	SourceUnreadable(PathBuf, io::Error),
+	/// The destination directory couldn't be created
+	DestSetupFailed(PathBuf, io::Error),
+	/// The destination isn't usable, for the given reason
+	InvalidDestination(PathBuf, String),
+	/// The destination is an existing file, not a directory
+	DestinationIsFile(PathBuf),
+	/// The destination is the watched directory itself
+	DestinationIsSource(PathBuf),
+	/// The backup of the source file would be the source file itself
+	BackupIsSource(PathBuf),
+	/// Another process (with the given PID) is already backing up the source, holding the lock file
+	AlreadyRunning(PathBuf, u32, PathBuf),
+	/// The destination directory couldn't be read
+	DestUnreadable(PathBuf, io::Error),
+	/// There is no versioned backup of the source (with the given version, if any)
+	NoBackup(PathBuf, Option<String>),
+	/// Copying a backup back over its source failed
+	RestoreFailed(PathBuf, io::Error),
+	/// The configuration file couldn't be read
+	ConfigUnreadable(PathBuf, io::Error),
+	/// The configuration file isn't valid
+	ConfigInvalid(PathBuf, toml::de::Error),
+	/// The template for the names of the backup files isn't valid, for the given reason
+	InvalidTemplate(String, String),
+	/// The metrics couldn't be served on the given address, for the given reason
+	MetricsUnavailable(String, String),
+	/// The control socket couldn't be set up
+	ControlSocketFailed(PathBuf, io::Error),
+	/// Another process (with the given PID) is running with the same PID file
+	PidFileInUse(PathBuf, u32),
+	/// The PID file couldn't be written
+	PidFileFailed(PathBuf, io::Error),
+	/// The source couldn't be watched for changes
+	WatchFailed(PathBuf, notify::Error),
+	/// The backup of the source failed (only returned when backing up once)
+	BackupFailed(PathBuf)
+}
+
+impl fmt::Display for BackupError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BackupError::NoSource => write!(f, "No source file to watch"),
+			BackupError::SourceNotFound(path) => write!(f, "File `{}` not found", path.display()),
+			BackupError::SourceUnreadable(path, error) => write!(f, "Error accessing file `{}`: {}", path.display(), error),
+			BackupError::DestSetupFailed(path, error) => write!(f, "Destination directory `{}` setup failed: {}", path.display(), error),
+			BackupError::InvalidDestination(path, reason) => write!(f, "Invalid destination `{}`: {}", path.display(), reason),
+			BackupError::DestinationIsFile(path) => write!(f, "Destination `{}` is a file: the destination has to be a directory", path.display()),
+			BackupError::DestinationIsSource(path) => write!(f, "Destination `{}` is the watched directory itself: choose a directory outside of it", path.display()),
+			BackupError::BackupIsSource(path) => write!(
+				f, "The backup of `{}` would overwrite the file itself: choose another destination directory, or use --versioned",
+				path.display()
+			),
+			BackupError::AlreadyRunning(path, pid, lock_path) => write!(
+				f, "`{}` is already being backed up by process {}, holding the lock on `{}`",
+				path.display(), pid, lock_path.display()
+			),
+			BackupError::DestUnreadable(path, error) => write!(f, "Error reading destination directory `{}`: {}", path.display(), error),
+			BackupError::NoBackup(path, None) => write!(f, "No versioned backup of `{}` found", path.display()),
+			BackupError::NoBackup(path, Some(version)) => write!(f, "No backup of `{}` with version `{}` found", path.display(), version),
+			BackupError::RestoreFailed(path, error) => write!(f, "Restore of `{}` failed: {}", path.display(), error),
+			BackupError::ConfigUnreadable(path, error) => write!(f, "Error reading configuration file `{}`: {}", path.display(), error),
+			BackupError::ConfigInvalid(path, error) => write!(f, "Invalid configuration file `{}`: {}", path.display(), error),
+			BackupError::InvalidTemplate(template, reason) => write!(f, "Invalid destination template `{}`: {}", template, reason),
+			BackupError::MetricsUnavailable(address, reason) => write!(f, "Could not serve the metrics on `{}`: {}", address, reason),
+			BackupError::ControlSocketFailed(path, error) => write!(f, "Could not listen on the control socket `{}`: {}", path.display(), error),
+			BackupError::PidFileInUse(path, pid) => write!(
+				f, "Process {} is already running with PID file `{}`",
+				pid, path.display()
+			),
+			BackupError::PidFileFailed(path, error) => write!(f, "Could not write PID file `{}`: {}", path.display(), error),
+			BackupError::WatchFailed(path, error) => write!(f, "Could not watch `{}` for changes: {}", path.display(), watch_error_reason(error)),
+			BackupError::BackupFailed(path) => write!(f, "Backup of `{}` failed", path.display())
+		}
+	}
+}
+
+impl Error for BackupError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			BackupError::SourceUnreadable(_, error)
+			| BackupError::DestSetupFailed(_, error)
+			| BackupError::DestUnreadable(_, error)
+			| BackupError::RestoreFailed(_, error)
+			| BackupError::ConfigUnreadable(_, error)
+			| BackupError::ControlSocketFailed(_, error)
+			| BackupError::PidFileFailed(_, error) => Some(error),
+			BackupError::ConfigInvalid(_, error) => Some(error),
+			BackupError::WatchFailed(_, error) => Some(error),
+			_ => None
+		}
+	}
+}
+
+/// Why watching failed: notify doesn't describe its errors itself.
+fn watch_error_reason(error: &notify::Error) -> String {
+	match error {
+		notify::Error::Generic(reason) => reason.clone(),
+		notify::Error::Io(error) => error.to_string(),
+		notify::Error::PathNotFound => "path not found".to_string(),
+		notify::Error::WatchNotFound => "watch not found".to_string()
+	}
+}
@@ -0,0 +1,60 @@
+//! Machine-readable stream of the backup events, one JSON object per line on the standard output.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::Local;
+use log::debug;
+use serde::Serialize;
+
+/// Something that happened to a source, as reported on the event stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub(crate) enum Event<'a> {
+	/// The source has been copied into `destination`
+	Backup {
+		source: &'a Path,
+		destination: &'a Path,
+		bytes: u64
+	},
+	/// The source didn't need to be copied
+	Skipped {
+		source: &'a Path,
+		reason: &'a str
+	},
+	/// The backup of the source failed
+	Error {
+		source: &'a Path,
+		message: String
+	}
+}
+
+/// Event together with the moment it happened.
+#[derive(Serialize)]
+struct TimestampedEvent<'a> {
+	#[serde(flatten)]
+	event: &'a Event<'a>,
+	timestamp: String
+}
+
+/// Write `event` on the standard output as a single line of JSON.
+pub(crate) fn emit(event: &Event) {
+	let event = TimestampedEvent {
+		event,
+		timestamp: Local::now().to_rfc3339()
+	};
+	let line = match serde_json::to_string(&event) {
+		Ok(line) => line,
+		Err(error) => {
+			debug!("Could not serialize {:?}: {:?}", event.event, error);
+			return;
+		}
+	};
+
+	// The stream is consumed by other programs, so every event has to get there right away
+	let stdout = io::stdout();
+	let mut stdout = stdout.lock();
+	if let Err(error) = writeln!(stdout, "{}", line).and_then(|_| stdout.flush()) {
+		debug!("Could not write event: {:?}", error);
+	}
+}
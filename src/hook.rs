@@ -0,0 +1,177 @@
+//! The command run after each backup with [`BackupConfig::on_change_command`], for the integrations
+//! not built in (uploading elsewhere, rebuilding something...).
+//!
+//! The commands run one at a time in the background, in the order of the backups, so that a burst of
+//! backups never starts a burst of processes. None of them runs forever: they're killed after
+//! [`BackupConfig::on_change_timeout`] (or [`DEFAULT_TIMEOUT`]), and when stopping after [`STOP_TIMEOUT`].
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{SyncSender, TrySendError, sync_channel};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+
+use crate::BackupConfig;
+
+/// How often a running hook is checked for completion
+const HOOK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a hook can run without [`BackupConfig::on_change_timeout`]
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long stopping waits for the hooks left to run: then the running one is killed, and the others skipped
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many commands can wait to be run: when they're slower than the backups, the newer ones are dropped
+const QUEUE_SIZE: usize = 100;
+
+/// Commands waiting to be run by the hook thread, and the thread itself, once started
+static QUEUE: Mutex<Option<(SyncSender<Hook>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// When the hook thread has to be done by, once stopping
+static STOP_DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// A command to run after a backup.
+struct Hook {
+	command_line: String,
+	source: PathBuf,
+	backup: String,
+	bytes: u64,
+	timeout: Option<Duration>
+}
+
+/// Start the thread running the commands queued by [`backed_up`].
+pub(crate) fn start() {
+	let mut queue = lock_queue();
+	if queue.is_some() {
+		debug!("The hook thread is already running");
+		return;
+	}
+	*lock_stop_deadline() = None;
+	let (sender, receiver) = sync_channel::<Hook>(QUEUE_SIZE);
+	let thread = thread::spawn(move || {
+		for hook in receiver {
+			if is_past_stop_deadline() {
+				warn!("Stopping: `{}` is skipped after the backup of `{:?}`", hook.command_line, hook.source);
+				continue;
+			}
+			run(&hook);
+		}
+	});
+	*queue = Some((sender, thread));
+}
+
+/// Wait for the queued commands to run (up to [`STOP_TIMEOUT`]), and stop the hook thread.
+pub(crate) fn stop() {
+	let queue = lock_queue().take();
+	if let Some((sender, thread)) = queue {
+		*lock_stop_deadline() = Some(Instant::now() + STOP_TIMEOUT);
+		// The thread stops once the queue is empty and closed
+		drop(sender);
+		if thread.join().is_err() {
+			debug!("The hook thread panicked");
+		}
+	}
+}
+
+/// Lock the queue of the commands.
+fn lock_queue() -> MutexGuard<'static, Option<(SyncSender<Hook>, JoinHandle<()>)>> {
+	// A poisoned lock only means that another command panicked: the queue is still fine
+	QUEUE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Lock the deadline of the hook thread.
+fn lock_stop_deadline() -> MutexGuard<'static, Option<Instant>> {
+	// Only ever replaced as a whole, so a poisoned lock still holds a deadline that makes sense
+	STOP_DEADLINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Check whether the hook thread, stopping, is out of time.
+fn is_past_stop_deadline() -> bool {
+	lock_stop_deadline().is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Queue [`BackupConfig::on_change_command`] for the backup of `source` into `backup`, of `bytes` bytes,
+/// if the hook thread is running.
+///
+/// The command runs in the background: its exit status is only logged, killing it once it runs
+/// for longer than [`BackupConfig::on_change_timeout`].
+pub(crate) fn backed_up(source: &Path, backup: &str, bytes: u64, config: &BackupConfig) {
+	let command_line = match &config.on_change_command {
+		Some(command_line) => command_line.clone(),
+		None => return
+	};
+	let queue = lock_queue();
+	let sender = match &*queue {
+		Some((sender, _)) => sender,
+		None => return
+	};
+	let hook = Hook {
+		command_line,
+		source: source.to_path_buf(),
+		backup: backup.to_string(),
+		bytes,
+		timeout: config.on_change_timeout
+	};
+	match sender.try_send(hook) {
+		Ok(()) => (),
+		Err(TrySendError::Full(hook)) => warn!("Too many commands waiting to run: `{}` is skipped after the backup of `{:?}`", hook.command_line, source),
+		Err(TrySendError::Disconnected(_)) => debug!("The hook thread has stopped")
+	}
+}
+
+/// Run `hook` and wait for it, logging its exit status.
+fn run(hook: &Hook) {
+	let child = shell(&hook.command_line)
+		.env("FWB_BACKUP_SOURCE", &hook.source)
+		.env("FWB_BACKUP", &hook.backup)
+		.env("FWB_BACKUP_BYTES", hook.bytes.to_string())
+		.spawn();
+	let child = match child {
+		Ok(child) => child,
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not run `{}` after the backup of `{:?}`. Reason: {}", hook.command_line, hook.source, error);
+			return;
+		}
+	};
+	let timeout = hook.timeout.unwrap_or(DEFAULT_TIMEOUT);
+	match wait(child, timeout) {
+		Ok(Some(status)) if status.success() => debug!("`{}` succeeded after the backup of `{:?}`", hook.command_line, hook.source),
+		Ok(Some(status)) => warn!("`{}` failed after the backup of `{:?}` ({})", hook.command_line, hook.source, status),
+		Ok(None) if is_past_stop_deadline() => warn!("`{}`, run after the backup of `{:?}`, was killed when stopping", hook.command_line, hook.source),
+		Ok(None) => warn!("`{}`, run after the backup of `{:?}`, was killed after {:?}", hook.command_line, hook.source, timeout),
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not wait for `{}` after the backup of `{:?}`. Reason: {}", hook.command_line, hook.source, error);
+		}
+	}
+}
+
+/// The command running `command_line` with the shell of the platform.
+fn shell(command_line: &str) -> Command {
+	let mut command = if cfg!(windows) { Command::new("cmd") } else { Command::new("sh") };
+	command.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(command_line);
+	command
+}
+
+/// Wait for `child` to exit, killing it (and returning `None`) once it runs for longer than `timeout`,
+/// or once stopping is out of time.
+fn wait(mut child: Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+	let start = Instant::now();
+	loop {
+		if let Some(status) = child.try_wait()? {
+			return Ok(Some(status));
+		}
+		if start.elapsed() >= timeout || is_past_stop_deadline() {
+			child.kill()?;
+			// Reaped, not to leave a zombie behind
+			child.wait()?;
+			return Ok(None);
+		}
+		thread::sleep(HOOK_CHECK_INTERVAL);
+	}
+}
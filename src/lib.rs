@@ -0,0 +1,1305 @@
+//! Whenever a file changes, copy its content to a backup file.
+//!
+//! The [`run`] function validates the configured sources, makes a first backup of each one and
+//! then watches them, backing them up again on every write, until a shutdown is requested.
+//! Sources can be single files or directories, whose whole tree is mirrored into the destination.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{File, canonicalize, create_dir_all, metadata, read, read_dir, remove_file, symlink_metadata};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use globset::GlobSet;
+use log::{info, debug, error, trace, warn};
+use notify::{DebouncedEvent, RecursiveMode};
+
+mod backup;
+mod checksum;
+#[cfg(unix)]
+mod control;
+mod encryption;
+mod eol;
+mod error;
+mod events;
+mod hook;
+mod limiter;
+mod lock;
+mod metrics;
+mod mirror;
+mod notifications;
+mod patterns;
+mod progress;
+mod pruner;
+mod retention;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod sink;
+mod template;
+mod throttle;
+mod watcher;
+mod webhook;
+
+pub mod config_file;
+pub mod manifest;
+pub mod paths;
+pub mod restore;
+pub mod stats;
+pub mod versions;
+
+pub use crate::checksum::ChecksumAlgo;
+pub use crate::config_file::WatchesFile;
+pub use crate::encryption::Passphrase;
+pub use crate::eol::LineEnding;
+pub use crate::error::BackupError;
+pub use crate::template::DestTemplate;
+pub use crate::watcher::TriggerEvent;
+
+use crate::backup::{History, Outcome};
+use crate::lock::{Lock, PidFile};
+use crate::patterns::SourcePattern;
+use crate::sink::BackupSink;
+use crate::watcher::{Change, SourceWatcher, exhausted_limit};
+
+/// How often the watch loop checks whether a shutdown was requested
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long to wait before checking whether a lost source came back the first time (it doubles at every check)
+const LOST_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two checks of a lost source
+const LOST_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How often the missing sources are checked for with [`BackupConfig::wait_for_source`]
+const WAIT_FOR_SOURCE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the sources are polled once the inotify limits are reached
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A source file together with the directory its backups go to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+	/// File to watch, or directory whose whole tree is watched
+	pub source: PathBuf,
+	/// Directory in which the backups are stored, or URL of a remote destination (`s3://bucket/prefix`, `sftp://user@host/path`)
+	pub destination: PathBuf,
+	/// Debounce for this source only, overriding [`BackupConfig::debounce`]
+	pub debounce: Option<Duration>,
+	/// Whether this source gets versioned backups, overriding [`BackupConfig::versioned`]
+	pub versioned: Option<bool>,
+	/// How many versioned backups to keep for this source, overriding [`BackupConfig::keep`]
+	pub keep: Option<usize>,
+	/// How many bytes the versioned backups of this source may take, overriding [`BackupConfig::max_size`]
+	pub max_size: Option<u64>
+}
+
+impl Watch {
+	/// Watch `source`, backing it up into `destination` with the global settings.
+	pub fn new(source: PathBuf, destination: PathBuf) -> Watch {
+		Watch {
+			source,
+			destination,
+			debounce: None,
+			versioned: None,
+			keep: None,
+			max_size: None
+		}
+	}
+}
+
+/// Everything [`run`] needs to know about what to back up and how.
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+	/// Files to watch and where to back them up
+	pub watches: Vec<Watch>,
+	/// Time to wait for a file to settle before backing it up
+	pub debounce: Duration,
+	/// Minimum time between two backups of the same file, later changes are skipped
+	pub min_interval: Option<Duration>,
+	/// Quiet period the changes are collected for before backing them up, each changed file once
+	pub coalesce: Option<Duration>,
+	/// Events that trigger the backup of the file they're about
+	pub trigger_events: Vec<TriggerEvent>,
+	/// Poll the sources at this interval instead of relying on the notifications of the platform
+	pub poll: Option<Duration>,
+	/// Whether every backup gets its own timestamped file
+	pub versioned: bool,
+	/// Template for the names of the backup files, replacing the source file name
+	pub dest_template: Option<DestTemplate>,
+	/// Whether the backups are stored in a subdirectory of the destination named after the day they're made (`2024-01-15`)
+	pub destination_by_date: bool,
+	/// How many versioned backups to keep for each source
+	pub keep: Option<usize>,
+	/// How many versioned backups to keep for each source and each day (the most recent ones of the day)
+	pub keep_per_day: Option<usize>,
+	/// How many bytes the versioned backups of each source may take in total (oldest ones are deleted first)
+	pub max_size: Option<u64>,
+	/// Whether a `<name>.latest` link to the newest versioned backup of each source is kept next to the backups
+	pub latest_link: bool,
+	/// How many previous backups to keep as `<name>.prev`, `<name>.prev.1`... when overwriting an unversioned backup
+	pub keep_previous: Option<usize>,
+	/// Whether backups are gzipped
+	pub compress: bool,
+	/// Size below which the files are stored uncompressed despite [`BackupConfig::compress`]
+	pub compress_min_size: Option<u64>,
+	/// Passphrase the backups are encrypted with, if they are
+	pub encryption: Option<Passphrase>,
+	/// Whether the sources only ever grow, so that only their new content is appended to the backups
+	pub append: bool,
+	/// Whether backups get the modification time (and permissions, on Unix) of their source
+	pub preserve_metadata: bool,
+	/// Whether backups identical to an existing one are hard linked to it instead of copied
+	pub dedup: bool,
+	/// Whether every backup is recorded in the manifest of its directory
+	pub manifest: bool,
+	/// Whether every backup is read back and compared against its source
+	pub verify: bool,
+	/// The algorithm hashing the contents, picked from the other settings if `None`
+	pub checksum_algo: Option<ChecksumAlgo>,
+	/// Maximum reading speed of the copies, in bytes per second
+	pub max_bytes_per_sec: Option<u64>,
+	/// Size of the chunks the files are copied by, copying them by hand instead of leaving it to the system
+	pub buffer_size: Option<usize>,
+	/// Whether the progress of long copies is logged every few seconds
+	pub progress: bool,
+	/// Whether the oldest versioned backups of a source are deleted when the destination has no room for a new one
+	pub evict_when_full: bool,
+	/// How many times a failed copy is retried before giving up
+	pub retries: u32,
+	/// Line endings the text files are converted to in their backups (binary files, with a NUL byte near their start,
+	/// are copied as they are). Appended backups are never converted
+	pub normalize_eol: Option<LineEnding>,
+	/// Whether a backup left incomplete by a failed append, or not matching its source with [`BackupConfig::verify`],
+	/// is moved aside to a `.corrupt` file instead of being left in place (other copies never leave incomplete backups)
+	pub quarantine_on_error: bool,
+	/// Whether files removed from a watched directory are removed from its backup too
+	pub mirror_deletes: bool,
+	/// Whether the whole tree of the watched directories is backed up, rather than only the files at their top level
+	pub recursive: bool,
+	/// The separator joining the directories of the files of a watched tree to their names, to back up the whole
+	/// tree into its destination directory alone (like `sub_dir_config.toml`) rather than mirror it.
+	/// The separator (and `%`) found in the names themselves is percent-encoded, so that two files never share a backup
+	pub flatten: Option<String>,
+	/// Whether sources that are symbolic links are resolved to their targets, watched in their place.
+	/// Otherwise the links themselves are watched, and backed up under their own names with the content of their targets.
+	/// Links inside watched directories are followed too, instead of being skipped.
+	pub follow_symlinks: bool,
+	/// Paths inside watched directories (relative to them) that are never backed up
+	pub exclude: GlobSet,
+	/// Size in bytes above which files aren't backed up
+	pub max_file_size: Option<u64>,
+	/// Size in bytes under which files aren't backed up
+	pub min_file_size: Option<u64>,
+	/// Extensions (lowercase, without the dot) of the only files of watched directories that are backed up, if any
+	pub include_extensions: Vec<String>,
+	/// Whether to only log the copies and deletions that would be made, without changing anything on disk
+	pub dry_run: bool,
+	/// Whether the sources are backed up on startup, before watching them
+	pub initial_copy: bool,
+	/// Whether the sources are backed up one last time when the watch stops, not to lose the changes whose events
+	/// were still being debounced
+	pub final_backup: bool,
+	/// Whether the backups made by previous runs are read back on startup, to only back up the files that changed since
+	pub verify_on_startup: bool,
+	/// How long to wait on startup for the sources that don't exist yet, instead of failing right away
+	pub wait_for_source: Option<Duration>,
+	/// Whether to stop after the first backup instead of watching the sources
+	pub once: bool,
+	/// How long to watch the sources before stopping, as when asked to terminate
+	pub max_runtime: Option<Duration>,
+	/// How long to watch the sources without any change before stopping, as when asked to terminate
+	pub idle_timeout: Option<Duration>,
+	/// How often to log how many backups of each source succeeded, failed or were skipped (always logged when stopping)
+	pub summary_interval: Option<Duration>,
+	/// How often the versioned backups are pruned by a thread of their own, instead of right after each backup
+	pub prune_interval: Option<Duration>,
+	/// Whether to write every backup event as a line of JSON on the standard output
+	pub json: bool,
+	/// Whether to show a desktop notification for the first backup and for failures
+	pub notify: bool,
+	/// URL every backup (and failed backup) is reported to with a POST request
+	pub webhook: Option<String>,
+	/// Command run (by the shell) after every backup, with `FWB_BACKUP_SOURCE`, `FWB_BACKUP` and `FWB_BACKUP_BYTES` in its environment
+	pub on_change_command: Option<String>,
+	/// How long [`BackupConfig::on_change_command`] can run before being killed, 10 minutes if `None`
+	pub on_change_timeout: Option<Duration>,
+	/// Minimum time between two desktop notifications or webhook reports: the backups of the interval are summarized
+	pub notify_interval: Option<Duration>,
+	/// Address (`HOST:PORT`) to serve the Prometheus metrics on
+	pub metrics_addr: Option<String>,
+	/// Unix socket answering the `status` and `backup-now` commands
+	pub control_socket: Option<PathBuf>,
+	/// File the PID of the process is written to while it runs; another process running with the same file is refused
+	pub pid_file: Option<PathBuf>,
+	/// Private key for the SFTP destinations, instead of the SSH agent and the default keys
+	pub ssh_key: Option<PathBuf>,
+	/// File the watches were read from, read again on `SIGHUP` to start watching the new sources and stop watching the removed ones
+	pub watches_file: Option<WatchesFile>
+}
+
+impl BackupConfig {
+	/// Create a configuration for `watches` with the default settings.
+	pub fn new(watches: Vec<Watch>) -> BackupConfig {
+		BackupConfig {
+			watches,
+			debounce: Duration::from_secs(1),
+			min_interval: None,
+			coalesce: None,
+			trigger_events: TriggerEvent::DEFAULT.to_vec(),
+			poll: None,
+			versioned: false,
+			dest_template: None,
+			destination_by_date: false,
+			keep: None,
+			keep_per_day: None,
+			max_size: None,
+			latest_link: false,
+			keep_previous: None,
+			compress: false,
+			compress_min_size: None,
+			encryption: None,
+			append: false,
+			preserve_metadata: false,
+			dedup: false,
+			manifest: false,
+			verify: false,
+			checksum_algo: None,
+			max_bytes_per_sec: None,
+			buffer_size: None,
+			progress: false,
+			evict_when_full: false,
+			retries: 3,
+			normalize_eol: None,
+			quarantine_on_error: false,
+			mirror_deletes: false,
+			recursive: true,
+			flatten: None,
+			follow_symlinks: false,
+			exclude: GlobSet::empty(),
+			include_extensions: Vec::new(),
+			max_file_size: None,
+			min_file_size: None,
+			dry_run: false,
+			initial_copy: true,
+			final_backup: true,
+			verify_on_startup: false,
+			wait_for_source: None,
+			once: false,
+			max_runtime: None,
+			idle_timeout: None,
+			summary_interval: None,
+			prune_interval: None,
+			json: false,
+			notify: false,
+			webhook: None,
+			on_change_command: None,
+			on_change_timeout: None,
+			notify_interval: None,
+			metrics_addr: None,
+			control_socket: None,
+			pid_file: None,
+			ssh_key: None,
+			watches_file: None
+		}
+	}
+}
+
+/// A validated source, ready to be backed up and watched.
+struct Source {
+	/// Canonical path of the source, matching the paths reported by the watcher
+	path: PathBuf,
+	/// Whether the source is a directory whose whole tree is mirrored
+	is_dir: bool,
+	/// Canonical path of the destination directory (the URL as given, for remote destinations)
+	destination: PathBuf,
+	/// Where the backups are uploaded, for remote destinations
+	sink: Option<Box<dyn BackupSink>>,
+	/// Canonical destinations (of any source) living inside the tree of a directory source, never backed up
+	nested_destinations: Vec<PathBuf>,
+	/// Debounce of the watcher the source is registered with
+	debounce: Duration,
+	/// The watch the source comes from, to tell whether it's still configured on reload
+	watch: Watch,
+	/// Settings the source is backed up with: the global ones, with the overrides of its watch
+	config: BackupConfig
+}
+
+impl Source {
+	/// Back up the source file, into its destination directory or to its remote sink.
+	fn backup(&self, history: &mut History) -> Outcome {
+		let outcome = self.store(history);
+		history.record(&self.path, &self.destination, outcome);
+		outcome
+	}
+
+	/// Copy the source file, as [`Source::backup`] does, without counting the outcome.
+	fn store(&self, history: &mut History) -> Outcome {
+		let config = &self.config;
+		match &self.sink {
+			Some(sink) => backup::upload(&self.path, sink.as_ref(), "", config, history),
+			None => {
+				// The directory of the day is only created with its first backup
+				let destination_dir = backup::dated_directory(&self.destination, config);
+				if !backup::create_destination_dir(&destination_dir, config) {
+					return Outcome::Failed;
+				}
+				backup::backup(&self.path, &self.destination, &destination_dir, config, history)
+			}
+		}
+	}
+
+	/// Back up what changed in the whole source since its last backup, before exiting.
+	fn final_backup(&self, history: &mut History) -> Outcome {
+		if self.is_dir {
+			mirror::backup_tree(self, &self.path, &self.config, history)
+		} else {
+			self.backup(history)
+		}
+	}
+
+	/// Back up the whole source, be it a file or a directory tree.
+	fn initial_copy(&self, history: &mut History) -> Outcome {
+		let config = &self.config;
+		debug!("Initial copy of `{:?}`", self.path);
+		let outcome = if self.is_dir && config.verify_on_startup {
+			let (outcome, tally) = mirror::backup_tree_counting(self, &self.path, config, history);
+			info!(
+				"Checked the {} file(s) of `{:?}`: {} backed up (missing or changed), {} failed",
+				tally.files, self.path, tally.copied, tally.failed
+			);
+			outcome
+		} else if self.is_dir {
+			mirror::backup_tree(self, &self.path, config, history)
+		} else {
+			self.backup(history)
+		};
+		if outcome != Outcome::Failed && config.metrics_addr.is_some() && !config.dry_run {
+			// Unchanged sources are backed up already
+			metrics::source_backed_up(&self.path);
+		}
+		outcome
+	}
+
+	/// Path the watcher has to watch for the source, and how.
+	///
+	/// The parent directory is watched rather than the file itself: editors saving by renaming a
+	/// temporary file over the original replace the watched file, and with it the watch
+	/// (the source is canonical, so it always has a parent). Directories are watched whole, unless
+	/// [`BackupConfig::recursive`] is off.
+	fn watched_directory(&self, config: &BackupConfig) -> (PathBuf, RecursiveMode) {
+		if self.is_dir {
+			let mode = if config.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+			(self.path.clone(), mode)
+		} else {
+			(self.path.parent().unwrap().to_path_buf(), RecursiveMode::NonRecursive)
+		}
+	}
+
+	/// React to `path` being written to (or moved into place).
+	fn updated(&self, path: &Path, history: &mut History) {
+		let config = &self.config;
+		let outcome = if self.is_dir {
+			if !mirror::contains(self, path, config) || mirror::is_excluded(&self.path, path, config) {
+				return;
+			}
+			mirror::backup_entry(self, path, config, history)
+		} else if self.path == path {
+			self.backup(history)
+		} else {
+			return;
+		};
+		if outcome == Outcome::Copied && config.metrics_addr.is_some() && !config.dry_run {
+			metrics::source_backed_up(&self.path);
+		}
+	}
+
+	/// React to `path` being removed (or moved away).
+	fn removed(&self, path: &Path) {
+		let config = &self.config;
+		if self.is_dir {
+			if config.mirror_deletes && mirror::contains(self, path, config) && !mirror::is_excluded(&self.path, path, config) {
+				mirror::remove_entry(self, path, config);
+			}
+		} else if self.path == path {
+			info!("Source `{:?}` has been removed, waiting for it to come back", self.path);
+		}
+	}
+}
+
+/// Back up the configured sources and keep watching them until the process is asked to terminate
+/// (or stop right after the first backup with [`BackupConfig::once`]).
+///
+/// On Unix, `SIGHUP` reads the watches again from [`BackupConfig::watches_file`].
+pub fn run(config: BackupConfig) -> Result<(), BackupError> {
+	// Removed once done, however run ends
+	let _pid_file = match &config.pid_file {
+		Some(path) => Some(PidFile::create(path)?),
+		None => None
+	};
+
+	if let Some(timeout) = config.wait_for_source {
+		wait_for_sources(&config.watches, timeout);
+	}
+
+	// The files matching the patterns are watched like the others, and the new ones once they show up
+	let watches = patterns::expand_watches(&config.watches);
+	let mut source_patterns = patterns::patterns(&config.watches);
+
+	// Fail early if none of the paths link to an existing file the user has read access to;
+	// the sources that are valid will be watched anyway
+	let (valid_sources, mut errors) = validate_watches(&watches, &config);
+
+	// When nothing can be watched the last error is returned to the caller, the others are only reported
+	let last_error = if valid_sources.is_empty() { errors.pop() } else { None };
+	for error in &errors {
+		error!("{}", error);
+	}
+	if let Some(error) = last_error {
+		return Err(error);
+	}
+	if valid_sources.is_empty() {
+		return Err(BackupError::NoSource);
+	}
+
+	let mut sources: Vec<Source> = Vec::new();
+	// Released (removing the lock files) when no source uses them anymore, and on return
+	let mut locks: HashMap<(PathBuf, PathBuf), Lock> = HashMap::new();
+	let mut last_setup_error = None;
+	for (path, is_dir, watch) in valid_sources {
+		match setup_source(path, is_dir, watch, &sources, &mut locks, &config) {
+			Ok(source) => sources.push(source),
+			// A destination that can't be set up (say, an unmounted share) doesn't stop the other destinations of the same source
+			Err(error @ BackupError::DestSetupFailed(..)) if has_other_destinations(watch, &watches) => {
+				error!("{}", error);
+				last_setup_error = Some(error);
+			},
+			Err(error) => return Err(error)
+		}
+	}
+	if let (true, Some(error)) = (sources.is_empty(), last_setup_error) {
+		return Err(error);
+	}
+
+	if let Some(address) = &config.metrics_addr {
+		metrics::serve(address)?;
+	}
+	// Removed once done, however run ends
+	#[cfg(unix)]
+	let control_socket = match &config.control_socket {
+		Some(path) => Some(control::listen(path)?),
+		None => None
+	};
+	#[cfg(not(unix))]
+	if config.control_socket.is_some() {
+		warn!("The control socket is only available on Unix");
+	}
+
+	if let Some(url) = &config.webhook {
+		info!("Reporting the backups to `{}`", url);
+		webhook::start(url);
+	}
+	if let Some(interval) = config.notify_interval.filter(|_| config.notify || config.webhook.is_some()) {
+		info!("Reporting the backups at most once every {:?}", interval);
+		limiter::start(interval, &config);
+	}
+
+	if let Some(interval) = config.prune_interval {
+		info!("Pruning the old backups every {:?}", interval);
+		pruner::start(interval);
+	}
+
+	if config.on_change_command.is_some() {
+		hook::start();
+	}
+
+	if sources.iter().any(|source| source.sink.is_some()) {
+		let ignored = local_only_options(&config);
+		if !ignored.is_empty() {
+			warn!("Ignoring {} for remote destinations", ignored.join(", "));
+		}
+	}
+
+	find_nested_destinations(&mut sources);
+
+	// Last backup of each file, used to skip backups of unchanged files
+	let mut history = History::new();
+
+	// Make the first copy, just to start with a balanced state
+	let mut failed_source = None;
+	if !config.initial_copy {
+		info!("Skipping the initial copy, only changes from now on are backed up");
+	}
+	for source in sources.iter().filter(|_| config.initial_copy) {
+		if source.initial_copy(&mut history) == Outcome::Failed {
+			failed_source = Some(source.path.clone());
+		}
+	}
+
+	if config.once {
+		stop_reporting(&config);
+		return match failed_source {
+			Some(source) => Err(BackupError::BackupFailed(source)),
+			None => Ok(())
+		};
+	}
+
+	// The debounce is a property of the watcher, so sources sharing it share the same watcher.
+	// Every watcher has its own channel, forwarded to a common one tagging the events with the
+	// debounce, so that each event is handled only for the sources registered with that watcher.
+	let (tx, rx) = channel();
+	if let Some(interval) = config.poll {
+		info!("Polling the sources every {:?}", interval);
+	}
+	let mut watchers: Watchers = HashMap::new();
+	for source in &sources {
+		if let Err(error) = watch_source(source, &mut watchers, &tx, &config) {
+			stop_reporting(&config);
+			return Err(error);
+		}
+	}
+	// Stop the watch loop when the user (or a service manager) asks us to terminate
+	let shutdown = Arc::new(AtomicBool::new(false));
+	let handler_shutdown = shutdown.clone();
+	if let Err(error) = ctrlc::set_handler(move || handler_shutdown.store(true, Ordering::SeqCst)) {
+		debug!("{:?}", error);
+		error!("Could not install the shutdown handler. Reason: {}", error);
+	}
+	let reload = Arc::new(AtomicBool::new(false));
+	#[cfg(unix)]
+	for (signal, flag) in [(signal_hook::consts::SIGTERM, &shutdown), (signal_hook::consts::SIGHUP, &reload)] {
+		if let Err(error) = signal_hook::flag::register(signal, flag.clone()) {
+			debug!("{:?}", error);
+			error!("Could not install the handler of signal {}. Reason: {}", signal, error);
+		}
+	}
+
+	// With a coalescing window, the changes waiting for the end of the quiet period (only the last
+	// one of each path, tagged with the debounce of its watcher) and when the last one came
+	let mut pending: Vec<(Duration, Change)> = Vec::new();
+	let mut last_change = Instant::now();
+	// Sources whose watched directory disappeared, waiting for it to come back
+	let mut lost: Vec<Lost> = Vec::new();
+	let started = Instant::now();
+	let mut last_activity = Instant::now();
+	let mut last_summary = Instant::now();
+	while !shutdown.load(Ordering::SeqCst) {
+		if config.max_runtime.is_some_and(|max_runtime| started.elapsed() >= max_runtime) {
+			info!("Watched the sources for {:?}, stopping", started.elapsed());
+			break;
+		}
+		if config.idle_timeout.is_some_and(|idle_timeout| last_activity.elapsed() >= idle_timeout) {
+			info!("No change for {:?}, stopping", last_activity.elapsed());
+			break;
+		}
+		// The watchers keep running during the reload: their events are queued and handled right after
+		if reload.swap(false, Ordering::SeqCst) {
+			reload_watches(&mut sources, &mut source_patterns, &mut locks, &mut watchers, &tx, &config, &mut history);
+		}
+		let timeout = match config.coalesce {
+			Some(window) if !pending.is_empty() => window.saturating_sub(last_change.elapsed()).min(SHUTDOWN_CHECK_INTERVAL),
+			_ => SHUTDOWN_CHECK_INTERVAL
+		};
+		match rx.recv_timeout(timeout) {
+			Ok((debounce, event)) => {
+				trace!("Event: {:?}", event);
+				let changes = Change::of(event, &config.trigger_events);
+				if !changes.is_empty() {
+					last_activity = Instant::now();
+				}
+				if changes.iter().any(|change| matches!(change, Change::Removed(_))) {
+					find_lost_sources(&sources, &mut lost, &mut watchers, &config);
+				}
+				for change in changes.iter().filter(|_| !source_patterns.is_empty()) {
+					if let Change::Updated(path) = change {
+						adopt_new_match(path, &source_patterns, &mut sources, &mut locks, &mut watchers, &tx, &config, &mut history);
+					}
+				}
+				for change in changes {
+					if config.coalesce.is_some() {
+						pending.retain(|(pending_debounce, pending_change)| *pending_debounce != debounce || pending_change.path() != change.path());
+						pending.push((debounce, change));
+						last_change = Instant::now();
+					} else {
+						apply_change(&sources, debounce, &change, &mut history);
+					}
+				}
+			},
+			Err(RecvTimeoutError::Timeout) => (),
+			Err(e) => error!("Watch error. {:?}", e)
+		}
+		if !lost.is_empty() {
+			recover_lost_sources(&sources, &mut lost, &mut watchers, &tx, &config, &mut history);
+		}
+		#[cfg(unix)]
+		for request in control_socket.iter().flat_map(|control_socket| control_socket.requests()) {
+			handle_request(request, &sources, started, &mut history);
+		}
+		if config.summary_interval.is_some_and(|interval| last_summary.elapsed() >= interval) {
+			log_summary(&history);
+			last_summary = Instant::now();
+		}
+		if let Some(window) = config.coalesce {
+			if !pending.is_empty() && last_change.elapsed() >= window {
+				debug!("No change for {:?}, handling the {} pending one(s)", window, pending.len());
+				for (debounce, change) in pending.drain(..) {
+					apply_change(&sources, debounce, &change, &mut history);
+				}
+			}
+		}
+	}
+
+	info!("Shutting down");
+	// Not to lose the last changes
+	for (debounce, change) in pending.drain(..) {
+		apply_change(&sources, debounce, &change, &mut history);
+	}
+	if config.final_backup {
+		// The files that didn't change since their last backup are skipped
+		debug!("Backing up the latest state of the sources");
+		for source in sources.iter().filter(|source| source.path.exists()) {
+			source.final_backup(&mut history);
+		}
+	}
+	log_summary(&history);
+	stop_reporting(&config);
+	drop(watchers);
+	drop(locks);
+	Ok(())
+}
+
+/// Check the configured sources and destinations without watching nor backing up anything,
+/// returning the problems found (none if [`run`] can back up every source).
+///
+/// The sources must be readable, and the local destinations writable (or creatable); the remote
+/// ones are connected to. Nothing is created, apart from a probe file removed right away.
+pub fn check(config: &BackupConfig) -> Vec<BackupError> {
+	let watches = patterns::expand_watches(&config.watches);
+	let (valid_sources, mut errors) = validate_watches(&watches, config);
+	for (path, is_dir, watch) in valid_sources {
+		let config = &watch_config(watch, config);
+		if let Some(sink) = sink::remote_sink(&watch.destination, config) {
+			if let Err(error) = sink {
+				errors.push(error);
+			}
+			continue;
+		}
+		if let Err(error) = check_destination(&path, is_dir, &watch.destination, config) {
+			errors.push(error);
+		}
+	}
+	errors
+}
+
+/// Check that the file system watcher can be set up, by watching the temporary directory
+/// (with the native watcher, unless polling every `poll`).
+pub fn probe_watcher(poll: Option<Duration>) -> Result<(), BackupError> {
+	let directory = std::env::temp_dir();
+	let failed = |error: notify::Error| {
+		debug!("{:?}", error);
+		BackupError::WatchFailed(directory.clone(), error)
+	};
+	let (tx, _rx) = channel();
+	let mut watcher = SourceWatcher::new(tx, Duration::from_millis(100), poll).map_err(failed)?;
+	watcher.watch(&directory, RecursiveMode::NonRecursive).map_err(failed)
+}
+
+/// Check that the validated source `path` can be backed up into the local `destination_dir`, as [`setup_source`] would.
+fn check_destination(path: &Path, is_dir: bool, destination_dir: &Path, config: &BackupConfig) -> Result<(), BackupError> {
+	if destination_dir.is_file() {
+		return Err(BackupError::DestinationIsFile(destination_dir.to_path_buf()));
+	}
+	// A missing destination is created by the first run: its closest existing parent must be writable then
+	let absolute_dir = paths::absolute_path(destination_dir);
+	let existing_dir = absolute_dir.ancestors().find(|dir| dir.exists()).unwrap_or(&absolute_dir);
+	let probe = backup::temp_path(&existing_dir.join(".file-watcher-backup-check"));
+	// Fails as well when that parent is a file
+	let result = File::create(&probe).and_then(|_| remove_file(&probe));
+	if let Err(error) = result {
+		debug!("{:?}", error);
+		return Err(BackupError::DestSetupFailed(destination_dir.to_path_buf(), error));
+	}
+
+	if let Ok(destination) = canonicalize(destination_dir) {
+		if is_dir && destination == path {
+			return Err(BackupError::DestinationIsSource(path.to_path_buf()));
+		}
+		if !is_dir && backup::backup_file_path(&backup::dated_directory(&destination, config), path, config) == path {
+			return Err(BackupError::BackupIsSource(path.to_path_buf()));
+		}
+	}
+	info!("Destination dir `{:?}` checked", destination_dir);
+	Ok(())
+}
+
+/// Log how many backups of each source succeeded, failed or were skipped since startup.
+fn log_summary(history: &History) {
+	let mut tallies: Vec<_> = history.tallies.iter().collect();
+	if tallies.is_empty() {
+		info!("No backup made so far");
+		return;
+	}
+	tallies.sort_by_key(|(key, _)| *key);
+	info!("Backups since startup:");
+	for ((source, destination), tally) in tallies {
+		info!("`{:?}` into `{}`: {} ok, {} failed, {} skipped", source, destination.display(), tally.copied, tally.failed, tally.skipped);
+	}
+	info!(
+		"Saved {} by skipping the unchanged files, and {} by linking the duplicates",
+		progress::format_bytes(history.unchanged_bytes), progress::format_bytes(history.deduplicated_bytes)
+	);
+}
+
+/// Answer the `request` received by the control socket, about the `sources` watched since `started`.
+#[cfg(unix)]
+fn handle_request(request: control::Request, sources: &[Source], started: Instant, history: &mut History) {
+	let answer = match request.command {
+		control::Command::Status => {
+			let watched: Vec<(&Path, &Path)> = sources.iter().map(|source| (source.path.as_path(), source.destination.as_path())).collect();
+			control::status(started.elapsed(), &watched, history)
+		},
+		control::Command::BackupNow => {
+			// Like on startup, the sources unchanged since their last backup are skipped
+			info!("Backing up every source, as asked on the control socket");
+			let outcomes: Vec<Outcome> = sources.iter()
+				.filter(|source| source.path.exists())
+				.map(|source| source.initial_copy(history))
+				.collect();
+			let count = |outcome| outcomes.iter().filter(|&&other| other == outcome).count();
+			format!(
+				"backed up {} source(s): {} copied, {} unchanged, {} failed",
+				outcomes.len(), count(Outcome::Copied), count(Outcome::Unchanged), count(Outcome::Failed)
+			)
+		}
+	};
+	request.reply(answer);
+}
+
+/// Wait for the pending reports of the backups to be sent, the pending prunings to be done and the pending
+/// commands to run, and stop their threads.
+fn stop_reporting(config: &BackupConfig) {
+	// The limiter reports to the webhook, so it's stopped first
+	limiter::stop();
+	webhook::stop();
+	if config.prune_interval.is_some() {
+		pruner::stop();
+	}
+	if config.on_change_command.is_some() {
+		hook::stop();
+	}
+}
+
+/// A source whose watched directory disappeared (the directory itself, or the one of the file),
+/// checked again at growing intervals until it comes back.
+struct Lost {
+	/// The watch of the source, to find it even after a reload
+	watch: Watch,
+	/// When to check again
+	retry_at: Instant,
+	/// How long to wait after that
+	delay: Duration
+}
+
+/// Start tracking the sources whose watched directory doesn't exist anymore: the watch on a removed
+/// directory isn't of any use, even once it's created again.
+fn find_lost_sources(sources: &[Source], lost: &mut Vec<Lost>, watchers: &mut Watchers, config: &BackupConfig) {
+	for source in sources {
+		let (directory, _) = source.watched_directory(config);
+		if directory.exists() || lost.iter().any(|lost| lost.watch == source.watch) {
+			continue;
+		}
+		warn!("`{:?}` is gone, waiting for it to come back to watch it again", directory);
+		if let Some((watcher, watched_dirs)) = watchers.get_mut(&source.debounce) {
+			if watched_dirs.remove(&directory).is_some() {
+				// The watch may be gone already, along with the directory
+				if let Err(error) = watcher.unwatch(&directory) {
+					trace!("{:?}", error);
+				}
+			}
+		}
+		lost.push(Lost { watch: source.watch.clone(), retry_at: Instant::now() + LOST_RETRY_BASE_DELAY, delay: LOST_RETRY_BASE_DELAY });
+	}
+}
+
+/// Watch again the `lost` sources whose watched directory came back, backing them up right away
+/// since their changes in the meantime went unnoticed.
+fn recover_lost_sources(sources: &[Source], lost: &mut Vec<Lost>, watchers: &mut Watchers, tx: &Sender<(Duration, DebouncedEvent)>, config: &BackupConfig, history: &mut History) {
+	let now = Instant::now();
+	lost.retain_mut(|lost| {
+		if lost.retry_at > now {
+			return true;
+		}
+		// Removed by a reload meanwhile
+		let source = match sources.iter().find(|source| source.watch == lost.watch) {
+			Some(source) => source,
+			None => return false
+		};
+		let (directory, _) = source.watched_directory(config);
+		if directory.exists() {
+			match watch_source(source, watchers, tx, config) {
+				Ok(()) => {
+					info!("`{:?}` is back, watching it again", directory);
+					// A file not there yet will show up as created
+					if source.path.exists() {
+						source.initial_copy(history);
+					}
+					return false;
+				},
+				Err(error) => error!("{}. Trying again in {:?}", error, lost.delay)
+			}
+		} else {
+			trace!("`{:?}` still missing, checking again in {:?}", directory, lost.delay);
+		}
+		lost.retry_at = now + lost.delay;
+		lost.delay = (lost.delay * 2).min(LOST_RETRY_MAX_DELAY);
+		true
+	});
+}
+
+/// Handle `change`, reported by the watcher with `debounce`, for the sources registered with that watcher.
+fn apply_change(sources: &[Source], debounce: Duration, change: &Change, history: &mut History) {
+	// Changes are reported with the watched path, each source checks whether they belong to it
+	for source in sources.iter().filter(|source| source.debounce == debounce) {
+		match change {
+			Change::Updated(path) => source.updated(path, history),
+			Change::Removed(path) => source.removed(path)
+		}
+	}
+}
+
+/// The watchers of the sources by debounce, with the directories each one watches (and how).
+type Watchers = HashMap<Duration, (SourceWatcher, HashMap<PathBuf, RecursiveMode>)>;
+
+/// Wait for the sources of `watches` that don't exist yet (or the first file matching them, for
+/// patterns) to show up, for at most `timeout`.
+fn wait_for_sources(watches: &[Watch], timeout: Duration) {
+	let patterns = patterns::patterns(watches);
+	let exists = |watch: &Watch| match patterns.iter().find(|pattern| pattern.watch == *watch) {
+		Some(pattern) => pattern.has_matches(),
+		None => watch.source.exists()
+	};
+	let mut missing: Vec<&Watch> = watches.iter().filter(|watch| !exists(watch)).collect();
+	if missing.is_empty() {
+		return;
+	}
+	for watch in &missing {
+		info!("`{:?}` doesn't exist yet, waiting up to {:?} for it to appear", watch.source, timeout);
+	}
+	let start = Instant::now();
+	while start.elapsed() < timeout {
+		thread::sleep(WAIT_FOR_SOURCE_INTERVAL);
+		missing.retain(|watch| {
+			let appeared = exists(watch);
+			if appeared {
+				info!("`{:?}` appeared after {:?}", watch.source, start.elapsed());
+			}
+			!appeared
+		});
+		if missing.is_empty() {
+			return;
+		}
+	}
+	for watch in missing {
+		warn!("`{:?}` still doesn't exist after {:?}", watch.source, timeout);
+	}
+}
+
+/// Validate the sources of `watches`, returning the canonical path of the valid ones (and whether
+/// they're directories) together with the errors of the others.
+fn validate_watches<'a>(watches: &'a [Watch], config: &BackupConfig) -> (Vec<(PathBuf, bool, &'a Watch)>, Vec<BackupError>) {
+	let mut valid_sources = Vec::new();
+	let mut errors = Vec::new();
+	for watch in watches {
+		debug!("Input path: `{:?}`", watch.source);
+		match validate_source(&watch.source, config.follow_symlinks) {
+			Ok((source, is_dir)) => {
+				info!("Input {} `{:?}` validated", if is_dir { "directory" } else { "file" }, watch.source);
+				valid_sources.push((source, is_dir, watch));
+			},
+			Err(error) => errors.push(error)
+		}
+	}
+	(valid_sources, errors)
+}
+
+/// Set up the destination of the validated source `path` of `watch`, locking it unless one of
+/// `sources` backs up the same source there already.
+fn setup_source(path: PathBuf, is_dir: bool, watch: &Watch, sources: &[Source], locks: &mut HashMap<(PathBuf, PathBuf), Lock>, config: &BackupConfig) -> Result<Source, BackupError> {
+	let destination_dir = watch.destination.as_path();
+	debug!("Destination dir is: {:?}", destination_dir);
+	let config = &watch_config(watch, config);
+
+	// Remote destinations have nothing to create, and nothing to lock
+	if let Some(sink) = sink::remote_sink(destination_dir, config) {
+		let sink = sink?;
+		info!("Backups of `{:?}` are uploaded to `{}`", path, destination_dir.display());
+		return Ok(Source {
+			path,
+			is_dir,
+			destination: destination_dir.to_path_buf(),
+			sink: Some(sink),
+			nested_destinations: Vec::new(),
+			debounce: watch.debounce.unwrap_or(config.debounce),
+			watch: watch.clone(),
+			config: config.clone()
+		});
+	}
+
+	// Backups always go into a directory: "create_dir_all" would fail with a less helpful error
+	if destination_dir.is_file() {
+		return Err(BackupError::DestinationIsFile(destination_dir.to_path_buf()));
+	}
+
+	// The destination is canonicalized as well, to recognize it when it lives inside a watched directory
+	let result = if config.dry_run && !destination_dir.is_dir() {
+		info!("DRY RUN: would create directory `{:?}`", destination_dir);
+		Ok(paths::absolute_path(destination_dir))
+	} else {
+		create_dir_all(destination_dir).and_then(|_| canonicalize(destination_dir))
+	};
+	let destination = match result {
+		Ok(destination) => destination,
+		Err(err) => {
+			debug!("{:?}", err);
+			return Err(BackupError::DestSetupFailed(destination_dir.to_path_buf(), err));
+		}
+	};
+
+	// Mirroring a directory onto itself can't skip its own destination
+	if is_dir && destination == path {
+		return Err(BackupError::DestinationIsSource(path));
+	}
+	// Backing up a file into its own directory, under its own name, would copy it over itself
+	if !is_dir && backup::backup_file_path(&backup::dated_directory(&destination, config), &path, config) == path {
+		return Err(BackupError::BackupIsSource(path));
+	}
+
+	// The same source can be listed twice (say, with different debounces): it's locked once.
+	// Dry runs don't write anything, lock files included.
+	let already_locked = sources.iter().any(|source| source.path == path && source.destination == destination);
+	if !already_locked && !config.dry_run {
+		let lock = Lock::acquire(&path, &destination)?;
+		locks.insert((path.clone(), destination.clone()), lock);
+	}
+	// Only once the destination is locked, not to remove the temporary files of another instance
+	if !already_locked {
+		let nested = config.destination_by_date || config.dest_template.as_ref().is_some_and(DestTemplate::has_directories);
+		backup::remove_stray_temp_files(&destination, (is_dir && config.recursive) || nested, config);
+	}
+
+	info!("Destination dir `{:?}` setup completed", destination_dir);
+	Ok(Source {
+		path,
+		is_dir,
+		destination,
+		sink: None,
+		nested_destinations: Vec::new(),
+		debounce: watch.debounce.unwrap_or(config.debounce),
+		watch: watch.clone(),
+		config: config.clone()
+	})
+}
+
+/// The settings the source of `watch` is backed up with: the global `config`, with the overrides of `watch`.
+fn watch_config(watch: &Watch, config: &BackupConfig) -> BackupConfig {
+	// The watches are of no use to a single source
+	let mut watch_config = BackupConfig { watches: Vec::new(), ..config.clone() };
+	if let Some(versioned) = watch.versioned {
+		watch_config.versioned = versioned;
+	}
+	if watch.keep.is_some() {
+		watch_config.keep = watch.keep;
+	}
+	if watch.max_size.is_some() {
+		watch_config.max_size = watch.max_size;
+	}
+	watch_config
+}
+
+/// Check whether the source of `watch` is backed up into other destinations too.
+fn has_other_destinations(watch: &Watch, watches: &[Watch]) -> bool {
+	watches.iter().any(|other| other.source == watch.source && other.destination != watch.destination)
+}
+
+/// Find the destinations living inside the tree of each directory source.
+///
+/// Backups written inside a watched directory trigger new events, that would back them up again
+/// (and again, filling the disk): every destination found inside a watched tree is left out of it.
+/// This includes the destinations of other sources, which could otherwise feed each other.
+fn find_nested_destinations(sources: &mut [Source]) {
+	let destinations: Vec<PathBuf> = sources.iter().map(|source| source.destination.clone()).collect();
+	for source in sources.iter_mut().filter(|source| source.is_dir) {
+		let nested_destinations: Vec<PathBuf> = destinations.iter()
+			.filter(|destination| destination.starts_with(&source.path))
+			.cloned()
+			.collect();
+		// Warned about once, not on every reload
+		for destination in nested_destinations.iter().filter(|destination| !source.nested_destinations.contains(destination)) {
+			warn!(
+				"Destination `{:?}` is inside the watched directory `{:?}`: it is excluded from the watch, \
+				otherwise every backup would trigger a new one",
+				destination, source.path
+			);
+		}
+		source.nested_destinations = nested_destinations;
+	}
+}
+
+/// Register `source` with the watcher of its debounce, created (sending its events to `tx`) if needed.
+///
+/// Once the inotify limits are reached, the watcher falls back to polling rather than failing.
+fn watch_source(source: &Source, watchers: &mut Watchers, tx: &Sender<(Duration, DebouncedEvent)>, config: &BackupConfig) -> Result<(), BackupError> {
+	let debounce = source.debounce;
+	let watch_failed = |error: notify::Error| {
+		debug!("{:?}", error);
+		BackupError::WatchFailed(source.path.clone(), error)
+	};
+	let (watcher, watched_dirs) = match watchers.entry(debounce) {
+		Entry::Occupied(entry) => entry.into_mut(),
+		Entry::Vacant(entry) => {
+			debug!("Debounce is: {:?}", debounce);
+			let watcher = match new_watcher(debounce, config.poll, tx) {
+				Ok(watcher) => watcher,
+				Err(error) => match exhausted_limit(&error).filter(|_| config.poll.is_none()) {
+					Some(limit) => {
+						warn_exhausted_limit(limit, &error);
+						new_watcher(debounce, Some(FALLBACK_POLL_INTERVAL), tx).map_err(watch_failed)?
+					},
+					None => return Err(watch_failed(error))
+				}
+			};
+			entry.insert((watcher, HashMap::new()))
+		}
+	};
+
+	let (directory, mode) = source.watched_directory(config);
+	if watched_dirs.contains_key(&directory) {
+		return Ok(());
+	}
+	match watcher.watch(&directory, mode) {
+		Ok(()) => (),
+		Err(error) => match exhausted_limit(&error).filter(|_| !watcher.is_polling()) {
+			Some(limit) => {
+				warn_exhausted_limit(limit, &error);
+				// The directories already watched move to the polling watcher as well
+				let mut polling = new_watcher(debounce, Some(FALLBACK_POLL_INTERVAL), tx).map_err(watch_failed)?;
+				for (watched, mode) in watched_dirs.iter() {
+					polling.watch(watched, *mode).map_err(watch_failed)?;
+				}
+				polling.watch(&directory, mode).map_err(watch_failed)?;
+				*watcher = polling;
+			},
+			None => return Err(watch_failed(error))
+		}
+	}
+	watched_dirs.insert(directory, mode);
+	Ok(())
+}
+
+/// Create a watcher with `debounce` (polling every `poll` interval if given), sending its events to `tx`.
+fn new_watcher(debounce: Duration, poll: Option<Duration>, tx: &Sender<(Duration, DebouncedEvent)>) -> notify::Result<SourceWatcher> {
+	let (watcher_tx, watcher_rx) = channel();
+	let tx = tx.clone();
+	thread::spawn(move || {
+		for event in watcher_rx {
+			if tx.send((debounce, event)).is_err() {
+				break;
+			}
+		}
+	});
+	SourceWatcher::new(watcher_tx, debounce, poll)
+}
+
+/// Tell that the inotify `limit` was reached, and how to raise it.
+fn warn_exhausted_limit(limit: &str, error: &notify::Error) {
+	debug!("{:?}", error);
+	warn!(
+		"The inotify limit `{}` is reached, polling every {:?} instead (slower to notice the changes). \
+		Raise it to watch natively again, e.g. with `sudo sysctl {}=524288`",
+		limit, FALLBACK_POLL_INTERVAL, limit
+	);
+}
+
+/// Stop watching the directory of the removed `source`, unless one of the `remaining` sources needs it.
+fn unwatch_source(source: &Source, remaining: &[Source], watchers: &mut Watchers, config: &BackupConfig) {
+	let (directory, _) = source.watched_directory(config);
+	let needed = remaining.iter().any(|other| other.debounce == source.debounce && other.watched_directory(config).0 == directory);
+	if needed {
+		return;
+	}
+	if let Some((watcher, watched_dirs)) = watchers.get_mut(&source.debounce) {
+		if watched_dirs.remove(&directory).is_some() {
+			if let Err(error) = watcher.unwatch(&directory) {
+				debug!("{:?}", error);
+				error!("Error removing path `{:?}` from watcher. {:?}", source.path, error);
+			}
+		}
+	}
+}
+
+/// Start watching (and back up) the new file `path` if it matches one of `patterns`, once for each destination.
+#[allow(clippy::too_many_arguments)]
+fn adopt_new_match(path: &Path, patterns: &[SourcePattern], sources: &mut Vec<Source>, locks: &mut HashMap<(PathBuf, PathBuf), Lock>,
+	watchers: &mut Watchers, tx: &Sender<(Duration, DebouncedEvent)>, config: &BackupConfig, history: &mut History) {
+	for pattern in patterns.iter().filter(|pattern| pattern.matches(path)) {
+		let destination = &pattern.watch.destination;
+		if sources.iter().any(|source| source.path == path && &source.watch.destination == destination) || !path.is_file() {
+			continue;
+		}
+		let watch = Watch { source: path.to_path_buf(), ..pattern.watch.clone() };
+		let source = validate_source(path, config.follow_symlinks)
+			.and_then(|(path, is_dir)| setup_source(path, is_dir, &watch, sources, locks, config))
+			.and_then(|source| watch_source(&source, watchers, tx, config).map(|_| source));
+		match source {
+			Ok(source) => {
+				info!("`{:?}` matches the source pattern `{}`, now watching it", path, pattern.watch.source.display());
+				source.initial_copy(history);
+				sources.push(source);
+			},
+			Err(error) => error!("{}", error)
+		}
+	}
+}
+
+/// Read the watches again from [`BackupConfig::watches_file`]: the sources that aren't listed
+/// anymore stop being watched, the new ones are backed up and watched, the others are left alone.
+/// The patterns among the sources replace `patterns`.
+fn reload_watches(sources: &mut Vec<Source>, patterns: &mut Vec<SourcePattern>, locks: &mut HashMap<(PathBuf, PathBuf), Lock>, watchers: &mut Watchers,
+	tx: &Sender<(Duration, DebouncedEvent)>, config: &BackupConfig, history: &mut History) {
+	let watches_file = match &config.watches_file {
+		Some(watches_file) => watches_file,
+		None => {
+			info!("Nothing to reload, the sources weren't read from a file");
+			return;
+		}
+	};
+	info!("Reloading the sources from `{:?}`", watches_file.path());
+	let watches: Vec<Watch> = match watches_file.load() {
+		// Expanded as on startup, to compare them with the current ones
+		Ok(watches) => watches.into_iter()
+			.map(|watch| Watch {
+				source: paths::expand_path(&watch.source),
+				destination: paths::expand_path(&watch.destination),
+				..watch
+			})
+			.collect(),
+		Err(error) => {
+			error!("{}. Keeping the current sources", error);
+			return;
+		}
+	};
+	*patterns = patterns::patterns(&watches);
+	let watches = patterns::expand_watches(&watches);
+
+	let (kept, removed): (Vec<Source>, Vec<Source>) = sources.drain(..).partition(|source| watches.contains(&source.watch));
+	*sources = kept;
+	for source in &removed {
+		unwatch_source(source, sources, watchers, config);
+		if config.metrics_addr.is_some() {
+			metrics::source_removed(&source.path);
+		}
+		info!("Stopped watching `{:?}`", source.path);
+	}
+	locks.retain(|(path, destination), _| sources.iter().any(|source| &source.path == path && &source.destination == destination));
+
+	let unchanged = sources.len();
+	let new_watches: Vec<Watch> = watches.into_iter()
+		.filter(|watch| !sources.iter().any(|source| &source.watch == watch))
+		.collect();
+	let (valid_sources, errors) = validate_watches(&new_watches, config);
+	for error in &errors {
+		error!("{}", error);
+	}
+	for (path, is_dir, watch) in valid_sources {
+		match setup_source(path, is_dir, watch, sources, locks, config) {
+			Ok(source) => sources.push(source),
+			Err(error) => error!("{}", error)
+		}
+	}
+	find_nested_destinations(sources);
+
+	// Watched before the first backup, not to miss the changes made in the meantime.
+	// Sources that can't be watched are left out, the others keep being backed up.
+	for source in sources.split_off(unchanged) {
+		if let Err(error) = watch_source(&source, watchers, tx, config) {
+			error!("{}. Not backing it up", error);
+			continue;
+		}
+		info!("Now watching `{:?}`, backed up into `{}`", source.path, source.destination.display());
+		if config.initial_copy {
+			source.initial_copy(history);
+		}
+		sources.push(source);
+	}
+	locks.retain(|(path, destination), _| sources.iter().any(|source| &source.path == path && &source.destination == destination));
+	info!(
+		"Sources reloaded: {} added, {} removed, {} unchanged",
+		sources.len() - unchanged, removed.len(), unchanged
+	);
+}
+
+/// Names of the enabled options that only work on local destinations.
+fn local_only_options(config: &BackupConfig) -> Vec<&'static str> {
+	let options = [
+		("--compress", config.compress),
+		("--compress-min-size", config.compress_min_size.is_some()),
+		("--encrypt", config.encryption.is_some()),
+		("--verify", config.verify),
+		("--destination-by-date", config.destination_by_date),
+		("--keep", config.keep.is_some()),
+		("--keep-per-day", config.keep_per_day.is_some()),
+		("--max-size", config.max_size.is_some()),
+		("--prune-interval", config.prune_interval.is_some()),
+		("--latest-link", config.latest_link),
+		("--keep-previous", config.keep_previous.is_some()),
+		("--append", config.append),
+		("--normalize-eol", config.normalize_eol.is_some()),
+		("--preserve-metadata", config.preserve_metadata),
+		("--dedup", config.dedup),
+		("--manifest", config.manifest),
+		("--mirror-deletes", config.mirror_deletes)
+	];
+	options.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect()
+}
+
+/// Check that `src_path` links to an existing file the user can read, or to a directory the user can list.
+///
+/// On success the canonical path of the source is returned, so that it can be matched against
+/// the paths reported by the watcher, together with whether it's a directory.
+fn validate_source(src_path: &Path, follow_symlinks: bool) -> Result<(PathBuf, bool), BackupError> {
+	let result = metadata(src_path).and_then(|metadata| {
+		if metadata.is_dir() {
+			read_dir(src_path).map(|_| true)
+		} else {
+			read(src_path).map(|_| false)
+		}
+	}).and_then(|is_dir| {
+		let path = if follow_symlinks { canonicalize(src_path) } else { canonicalize_link(src_path) };
+		path.map(|path| (path, is_dir))
+	});
+
+	result.map_err(|error| {
+		trace!("{:?}", error);
+		match error.kind() {
+			ErrorKind::NotFound => BackupError::SourceNotFound(src_path.to_path_buf()),
+			_ => BackupError::SourceUnreadable(src_path.to_path_buf(), error)
+		}
+	})
+}
+
+/// Canonicalize `path`, unless it's a symbolic link: only its parent is canonicalized then, to watch the link itself.
+fn canonicalize_link(path: &Path) -> io::Result<PathBuf> {
+	match (path.parent(), path.file_name()) {
+		(Some(parent), Some(name)) if symlink_metadata(path)?.file_type().is_symlink() => {
+			let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+			canonicalize(parent).map(|parent| parent.join(name))
+		},
+		_ => canonicalize(path)
+	}
+}
@@ -0,0 +1,192 @@
+//! Rate limiting of the desktop notifications and webhook reports, with
+//! [`BackupConfig::notify_interval`](crate::BackupConfig::notify_interval).
+//!
+//! The first backup after a quiet period is reported right away; the ones following it within the
+//! interval are only counted, and reported together in a single summary once the interval is over.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::BackupConfig;
+use crate::notifications;
+use crate::webhook;
+
+/// Backups waiting to be reported by the limiter thread, and the thread itself, once started
+static QUEUE: Mutex<Option<(Sender<Notice>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// A backup to report.
+enum Notice {
+	Backup { source: PathBuf, destination: String, bytes: u64 },
+	Failure { source: PathBuf, message: String }
+}
+
+/// Where the backups are reported to.
+#[derive(Clone, Copy)]
+struct Outlets {
+	notify: bool,
+	webhook: bool
+}
+
+/// What happened since the last report.
+#[derive(Default)]
+pub(crate) struct Summary {
+	/// Number of successful backups
+	pub(crate) backups: usize,
+	/// Number of failed backups
+	pub(crate) failures: usize,
+	/// Bytes copied by the successful backups
+	pub(crate) bytes: u64
+}
+
+impl Summary {
+	fn add(&mut self, notice: &Notice) {
+		match notice {
+			Notice::Backup { bytes, .. } => {
+				self.backups += 1;
+				self.bytes += bytes;
+			},
+			Notice::Failure { .. } => self.failures += 1
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		self.backups == 0 && self.failures == 0
+	}
+}
+
+/// Start the thread reporting the backups at most once every `interval`, to the desktop with
+/// [`BackupConfig::notify`] and to the webhook with [`BackupConfig::webhook`].
+pub(crate) fn start(interval: Duration, config: &BackupConfig) {
+	let mut queue = lock_queue();
+	if queue.is_some() {
+		debug!("The limiter thread is already running");
+		return;
+	}
+	let (sender, receiver) = channel();
+	let outlets = Outlets { notify: config.notify, webhook: config.webhook.is_some() };
+	let thread = thread::spawn(move || limit(&receiver, interval, outlets));
+	*queue = Some((sender, thread));
+}
+
+/// Report what is left to report, and stop the limiter thread.
+pub(crate) fn stop() {
+	let queue = lock_queue().take();
+	if let Some((sender, thread)) = queue {
+		// The thread stops once the queue is empty and closed
+		drop(sender);
+		if thread.join().is_err() {
+			debug!("The limiter thread panicked");
+		}
+	}
+}
+
+/// Lock the queue of the backups to report.
+fn lock_queue() -> MutexGuard<'static, Option<(Sender<Notice>, JoinHandle<()>)>> {
+	// A poisoned lock only means that another report panicked: the queue is still fine
+	QUEUE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Report the successful backup of `source` into `destination`, right away without [`BackupConfig::notify_interval`].
+pub(crate) fn backed_up(source: &Path, destination: &str, bytes: u64, config: &BackupConfig) {
+	let notice = Notice::Backup { source: source.to_path_buf(), destination: destination.to_string(), bytes };
+	if config.notify_interval.is_none() {
+		forward(notice, Outlets { notify: config.notify, webhook: config.webhook.is_some() });
+	} else {
+		send(notice);
+	}
+}
+
+/// Report the failed backup of `source`, right away without [`BackupConfig::notify_interval`].
+pub(crate) fn failed(source: &Path, message: &str, config: &BackupConfig) {
+	let notice = Notice::Failure { source: source.to_path_buf(), message: message.to_string() };
+	if config.notify_interval.is_none() {
+		forward(notice, Outlets { notify: config.notify, webhook: config.webhook.is_some() });
+	} else {
+		send(notice);
+	}
+}
+
+/// Queue `notice` for the limiter thread, if it's running.
+fn send(notice: Notice) {
+	if let Some((sender, _)) = &*lock_queue() {
+		if sender.send(notice).is_err() {
+			debug!("The limiter thread has stopped");
+		}
+	}
+}
+
+/// Report the backups received from `receiver` to `outlets`, at most once every `interval`.
+fn limit(receiver: &Receiver<Notice>, interval: Duration, outlets: Outlets) {
+	let mut last_report: Option<Instant> = None;
+	let mut pending = Summary::default();
+	// The only backup of the interval is reported as it is, not as a summary
+	let mut first_pending = None;
+	loop {
+		let received = match last_report.filter(|_| !pending.is_empty()) {
+			Some(last_report) => receiver.recv_timeout((last_report + interval).saturating_duration_since(Instant::now())),
+			None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		};
+		let closed = match received {
+			Ok(notice) => {
+				if pending.is_empty() && last_report.is_none_or(|last_report| last_report.elapsed() >= interval) {
+					forward(notice, outlets);
+					last_report = Some(Instant::now());
+				} else {
+					pending.add(&notice);
+					first_pending.get_or_insert(notice);
+				}
+				continue;
+			},
+			Err(RecvTimeoutError::Timeout) => false,
+			Err(RecvTimeoutError::Disconnected) => true
+		};
+		match first_pending.take() {
+			Some(notice) if pending.backups + pending.failures == 1 => forward(notice, outlets),
+			Some(_) => summarize(&pending, interval, outlets),
+			None => ()
+		}
+		pending = Summary::default();
+		last_report = Some(Instant::now());
+		if closed {
+			break;
+		}
+	}
+}
+
+/// Report a single backup.
+fn forward(notice: Notice, outlets: Outlets) {
+	match notice {
+		Notice::Backup { source, destination, bytes } => {
+			if outlets.notify {
+				notifications::backed_up(&source);
+			}
+			if outlets.webhook {
+				webhook::backed_up(&source, &destination, bytes);
+			}
+		},
+		Notice::Failure { source, message } => {
+			if outlets.notify {
+				notifications::failed(&source, &message);
+			}
+			if outlets.webhook {
+				webhook::failed(&source, &message);
+			}
+		}
+	}
+}
+
+/// Report the backups made during the last `interval`.
+fn summarize(summary: &Summary, interval: Duration, outlets: Outlets) {
+	debug!("{} backup(s) and {} failure(s) in the last {:?}", summary.backups, summary.failures, interval);
+	if outlets.notify {
+		notifications::summary(summary, interval);
+	}
+	if outlets.webhook {
+		webhook::summary(summary, interval);
+	}
+}
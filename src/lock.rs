@@ -0,0 +1,188 @@
+//! Lock files preventing two instances from backing up the same source into the same destination,
+//! and the PID file of the process.
+//!
+//! Both hold the PID of their process, and are locked (with `flock` on Unix) for as long as it holds them:
+//! the lock of a process that crashed is freed with it, so a file left behind is simply taken over.
+
+use std::fs::{File, OpenOptions, metadata, read_to_string, remove_file};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+use log::{debug, error, warn};
+use sha2::{Digest, Sha256};
+
+use crate::BackupError;
+use crate::backup;
+
+/// Time a process is given to write its PID into the file it just created, before the file is taken as stale
+const CLAIM_WRITE_DELAY: Duration = Duration::from_secs(2);
+
+/// Interval between the reads of a file whose PID isn't written yet
+const CLAIM_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Attempts at locking a file, removed by its owner each time between its opening and its locking
+const CLAIM_ATTEMPTS: usize = 3;
+
+/// A lock on a source, held until dropped.
+pub(crate) struct Lock {
+	path: PathBuf,
+	/// Holding the lock while open
+	_file: File
+}
+
+impl Lock {
+	/// Lock the backups of `source` into `destination_dir`, by locking a lock file there holding
+	/// the PID of this process. A lock left behind by a process that isn't running anymore is taken over.
+	pub(crate) fn acquire(source: &Path, destination_dir: &Path) -> Result<Lock, BackupError> {
+		let path = destination_dir.join(lock_file_name(source));
+		match claim(&path) {
+			Ok(file) => Ok(Lock { path, _file: file }),
+			Err(Claim::Owned(pid)) => Err(BackupError::AlreadyRunning(source.to_path_buf(), pid, path)),
+			Err(Claim::Failed(error)) => Err(BackupError::DestSetupFailed(path, error))
+		}
+	}
+}
+
+impl Drop for Lock {
+	fn drop(&mut self) {
+		// Removed while still locked, the file closing afterwards
+		release(&self.path);
+	}
+}
+
+/// A file holding the PID of this process, for the service managers, removed when dropped.
+pub(crate) struct PidFile {
+	path: PathBuf,
+	/// Holding the lock while open
+	_file: File
+}
+
+impl PidFile {
+	/// Write the PID of this process to `path`, taking over the file left behind by a process that isn't running anymore.
+	pub(crate) fn create(path: &Path) -> Result<PidFile, BackupError> {
+		match claim(path) {
+			Ok(file) => Ok(PidFile { path: path.to_path_buf(), _file: file }),
+			Err(Claim::Owned(pid)) => Err(BackupError::PidFileInUse(path.to_path_buf(), pid)),
+			Err(Claim::Failed(error)) => Err(BackupError::PidFileFailed(path.to_path_buf(), error))
+		}
+	}
+}
+
+impl Drop for PidFile {
+	fn drop(&mut self) {
+		release(&self.path);
+	}
+}
+
+/// Why a file holding a PID couldn't be locked.
+enum Claim {
+	/// The file is locked by another running process, with this PID
+	Owned(u32),
+	/// The file couldn't be written, or locked
+	Failed(io::Error)
+}
+
+/// Lock the file `path` (created if needed) and write the PID of this process into it, unless another
+/// process has it locked: the file is returned, holding the lock while open.
+fn claim(path: &Path) -> Result<File, Claim> {
+	let failed = |error: io::Error| {
+		debug!("{:?}", error);
+		Claim::Failed(error)
+	};
+	for _ in 0..CLAIM_ATTEMPTS {
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(failed)?;
+		if let Err(error) = file.try_lock_exclusive() {
+			if error.kind() != fs2::lock_contended_error().kind() {
+				return Err(failed(error));
+			}
+			return match read_owner(path) {
+				Some(pid) => Err(Claim::Owned(pid)),
+				None => Err(Claim::Failed(io::Error::new(ErrorKind::WouldBlock, "locked by another process")))
+			};
+		}
+		// Removed by its owner before we locked it: the lock is the one of a file nobody else sees
+		if !is_same_file(&file, path) {
+			continue;
+		}
+		if file.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+			warn!("Taking over the stale file `{:?}` (the process that created it isn't running)", path);
+		}
+		if let Err(error) = file.set_len(0).and_then(|_| write!(file, "{}", process::id())) {
+			let _ = remove_file(path);
+			return Err(failed(error));
+		}
+		debug!("`{:?}` locked", path);
+		return Ok(file);
+	}
+	Err(Claim::Failed(ErrorKind::AlreadyExists.into()))
+}
+
+/// Check whether the open `file` is (still) the one at `path`.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+	use std::os::unix::fs::MetadataExt;
+
+	match (file.metadata(), metadata(path)) {
+		(Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+		_ => false
+	}
+}
+
+/// Check whether the open `file` is (still) the one at `path`.
+///
+/// On Windows a removed file stays until it's closed, so it always is.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+	true
+}
+
+/// Read the PID held by the file `path`, waiting for it if the file was only just created: the
+/// process creating it may not have written its PID yet.
+fn read_owner(path: &Path) -> Option<u32> {
+	loop {
+		if let Some(pid) = read_to_string(path).ok().and_then(|pid| pid.trim().parse::<u32>().ok()) {
+			return Some(pid);
+		}
+		let age = metadata(path).and_then(|metadata| metadata.modified()).ok().and_then(|modified| modified.elapsed().ok());
+		// Gone, older than the delay, or dated in the future: the wait always ends
+		if age.is_none_or(|age| age >= CLAIM_WRITE_DELAY) {
+			return None;
+		}
+		thread::sleep(CLAIM_CHECK_INTERVAL);
+	}
+}
+
+/// Remove the file `path` locked by [`claim`].
+fn release(path: &Path) {
+	if let Err(error) = remove_file(path) {
+		debug!("{:?}", error);
+		error!("Could not remove `{:?}`. Reason: {}", path, error);
+	}
+}
+
+/// Name of the lock file of `source`: hidden, and unique to the (canonical) path of the source.
+fn lock_file_name(source: &Path) -> String {
+	let hash = Sha256::digest(source.to_string_lossy().as_bytes());
+	format!(".file-watcher-backup-{}.lock", backup::hex(&hash[..8]))
+}
+
+/// Check whether the process `pid` is still running.
+#[cfg(unix)]
+pub(crate) fn is_running(pid: u32) -> bool {
+	// Signal 0 only checks whether the process exists (and could be signaled)
+	let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+	result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Check whether the process `pid` is still running.
+///
+/// There is no portable way to tell, so the owner of a lock is assumed to be alive: the lock file
+/// has to be removed by hand if it's really stale.
+#[cfg(not(unix))]
+pub(crate) fn is_running(_pid: u32) -> bool {
+	true
+}
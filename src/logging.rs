@@ -0,0 +1,208 @@
+//! Loggers and log files not provided by `simplelog`.
+
+use std::fmt;
+use std::fs::{File, OpenOptions, read_dir, remove_file, rename};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{Duration, Local, NaiveDate};
+use log::{LevelFilter, Log, Metadata, Record, debug, info, warn};
+use simplelog::{Config, SharedLogger};
+
+/// Format of the lines of the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+	/// The plain text lines of `simplelog`
+	Text,
+	/// One JSON object per line, with the timestamp, the level, the target and the message
+	Json
+}
+
+/// Reasons why the log file can't be written.
+#[derive(Debug)]
+pub enum LogSetupError {
+	/// No log directory was given, and the home directory of the user is unknown
+	NoHomeDirectory,
+	/// The log directory couldn't be created
+	DirectoryFailed(PathBuf, io::Error),
+	/// The log file couldn't be opened
+	FileFailed(PathBuf, io::Error)
+}
+
+impl fmt::Display for LogSetupError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LogSetupError::NoHomeDirectory => write!(f, "No log directory: the home directory is unknown (use --log-dir)"),
+			LogSetupError::DirectoryFailed(path, error) => write!(f, "Could not create the log directory `{}`: {}", path.display(), error),
+			LogSetupError::FileFailed(path, error) => write!(f, "Could not open the log file `{}`: {}", path.display(), error)
+		}
+	}
+}
+
+/// A logger writing every record as a line of JSON into `W`.
+pub struct JsonLogger<W: Write + Send + 'static> {
+	level: LevelFilter,
+	writable: Mutex<W>
+}
+
+impl<W: Write + Send + 'static> JsonLogger<W> {
+	/// Log the records up to `level` into `writable`.
+	pub fn new(level: LevelFilter, writable: W) -> Box<JsonLogger<W>> {
+		Box::new(JsonLogger { level, writable: Mutex::new(writable) })
+	}
+}
+
+impl<W: Write + Send + 'static> Log for JsonLogger<W> {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		metadata.level() <= self.level
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		let line = serde_json::json!({
+			"timestamp": Local::now().to_rfc3339(),
+			"level": record.level().to_string(),
+			"target": record.target(),
+			"message": record.args().to_string()
+		});
+		// There's nowhere left to report a failure to log
+		if let Ok(mut writable) = self.writable.lock() {
+			let _ = writeln!(writable, "{}", line);
+		}
+	}
+
+	fn flush(&self) {
+		if let Ok(mut writable) = self.writable.lock() {
+			let _ = writable.flush();
+		}
+	}
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+	fn level(&self) -> LevelFilter {
+		self.level
+	}
+
+	fn config(&self) -> Option<&Config> {
+		None
+	}
+
+	fn as_log(self: Box<Self>) -> Box<dyn Log> {
+		Box::new(*self)
+	}
+}
+
+/// A log file rolled over once it grows beyond a maximum size: `<stem>.log` becomes `<stem>.1.log`,
+/// the previous `<stem>.1.log` becomes `<stem>.2.log` and so on, up to a maximum number of files.
+pub struct RotatingFile {
+	path: PathBuf,
+	max_size: u64,
+	keep_files: usize,
+	file: File,
+	size: u64,
+	/// Whether the last write ended a line: files are only rolled over between lines
+	at_line_start: bool
+}
+
+impl RotatingFile {
+	/// Open (appending) the log file at `path`, rolling it over beyond `max_size` bytes and keeping
+	/// at most `keep_files` rolled over files.
+	pub fn open(path: &Path, max_size: u64, keep_files: usize) -> io::Result<RotatingFile> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		let size = file.metadata()?.len();
+		Ok(RotatingFile { path: path.to_path_buf(), max_size, keep_files, file, size, at_line_start: true })
+	}
+
+	/// Path of the `index`-th rolled over file.
+	fn rotated_path(&self, index: usize) -> PathBuf {
+		let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+		self.path.with_file_name(format!("{}.{}.log", stem, index))
+	}
+
+	/// Shift the rolled over files by one, move the current file in first place and start a new one.
+	fn rotate(&mut self) -> io::Result<()> {
+		if self.keep_files == 0 {
+			remove_file(&self.path)?;
+		} else {
+			// The oldest file goes away (renaming over it isn't possible everywhere)
+			let _ = remove_file(self.rotated_path(self.keep_files));
+			for index in (1..self.keep_files).rev() {
+				let from = self.rotated_path(index);
+				if from.exists() {
+					rename(&from, self.rotated_path(index + 1))?;
+				}
+			}
+			rename(&self.path, self.rotated_path(1))?;
+		}
+		self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		self.size = 0;
+		Ok(())
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Failing to roll over is no reason to stop logging: keep writing into the current file
+		if self.at_line_start && self.size >= self.max_size {
+			let _ = self.rotate();
+		}
+		let written = self.file.write(buf)?;
+		self.size += written as u64;
+		self.at_line_start = buf[..written].ends_with(b"\n");
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+/// Delete the log files of `log_dir` (named `<date>.log`, or `<date>.<N>.log` once rolled over)
+/// dated more than `retention_days` days ago.
+pub fn remove_old_logs(log_dir: &Path, retention_days: u32) {
+	let entries = match read_dir(log_dir) {
+		Ok(entries) => entries,
+		Err(error) => {
+			debug!("{:?}", error);
+			warn!("Could not scan `{:?}` for old log files. Reason: {}", log_dir, error);
+			return;
+		}
+	};
+
+	let oldest_kept = Local::now().naive_local().date() - Duration::days(i64::from(retention_days));
+	let mut removed = 0;
+	for entry in entries.filter_map(|entry| entry.ok()) {
+		let date = match entry.file_name().to_str().and_then(log_file_date) {
+			Some(date) => date,
+			None => continue
+		};
+		if date >= oldest_kept {
+			continue;
+		}
+		match remove_file(entry.path()) {
+			Ok(()) => removed += 1,
+			Err(error) => {
+				debug!("{:?}", error);
+				warn!("Could not delete old log file `{:?}`. Reason: {}", entry.path(), error);
+			}
+		}
+	}
+	if removed > 0 {
+		info!("Deleted {} log file(s) older than {} days from `{:?}`", removed, retention_days, log_dir);
+	}
+}
+
+/// Date of the log file named `file_name`, if it's one.
+fn log_file_date(file_name: &str) -> Option<NaiveDate> {
+	let stem = file_name.strip_suffix(".log")?;
+	// Rolled over files have an index after the date
+	let date = match stem.split_once('.') {
+		Some((date, index)) if !index.is_empty() && index.bytes().all(|byte| byte.is_ascii_digit()) => date,
+		Some(_) => return None,
+		None => stem
+	};
+	NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
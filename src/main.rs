@@ -1,161 +1,1640 @@
 // Disable console on windows (https://github.com/rust-lang/rust/pull/37501)
 #![windows_subsystem = "windows"]
 
-use std::fs::{File, copy, read, create_dir_all, OpenOptions};
-use std::io::ErrorKind;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::io;
+use std::iter;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
 use std::time::Duration;
 
 use atty::Stream;
-use chrono::Local;
-use clap::{Arg, App};
+use chrono::{DateTime, Local};
+use clap::{Arg, App, AppSettings, ArgMatches, Shell, SubCommand};
 use dirs::home_dir;
-use log::{info, debug, error, trace};
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use globset::{Glob, GlobSetBuilder};
+use log::{debug, error, trace, warn};
 use simplelog::{CombinedLogger, TermLogger, TerminalMode, WriteLogger, LevelFilter, Config, SharedLogger};
 
+use file_watcher_backup::{BackupConfig, BackupError, ChecksumAlgo, DestTemplate, LineEnding, Passphrase, TriggerEvent, Watch, WatchesFile, check, manifest, paths, probe_watcher, restore, run, stats, versions};
+
 extern crate exitcode;
 
+mod logging;
+
+use crate::logging::{JsonLogger, LogFormat, LogSetupError, RotatingFile};
+
+/// Environment variable holding the passphrase of encrypted backups, so that it doesn't have to be typed
+const PASSPHRASE_VARIABLE: &str = "FILE_WATCHER_BACKUP_PASSPHRASE";
+
+/// Prefix of the environment variables standing for the options, like `FWB_DEBOUNCE` for `--debounce`
+const ENV_PREFIX: &str = "FWB_";
+
+/// The flags that can be set in the environment too (`FWB_VERSIONED=true`); clap reads the options
+/// taking a value from it by itself, except the [`ENV_DEPENDENT_OPTIONS`]
+const ENV_FLAGS: &[&str] = &[
+	"versioned", "destination-by-date", "latest-link", "compress", "encrypt", "append", "preserve-metadata", "dedup",
+	"manifest", "verify", "progress", "quarantine-on-error", "mirror-deletes", "recursive", "no-recursive",
+	"flatten", "follow-symlinks", "evict-when-full", "dry-run", "no-initial-copy", "no-final-backup", "verify-on-startup", "once",
+	"json", "notify", "force-term-log", "no-term-log", "no-file-log"
+];
+
+/// The options taking a value that depend on other ones, with the options they require and the ones they conflict with.
+/// clap would take their values from the environment as given on the command line, and refuse the command line because
+/// of them, so they are read from the environment after parsing, only when the command line allows them.
+/// The sources, the destinations and `--config` are still read by clap, see [`config_file`] and [`with_env_default`]
+const ENV_DEPENDENT_OPTIONS: &[(&str, &[&str], &[&str])] = &[
+	("dest-template", &[], &["versioned", "append"]),
+	("keep", &["versioned"], &[]),
+	("keep-per-day", &["versioned"], &[]),
+	("max-size", &["versioned"], &[]),
+	("compress-min-size", &["compress"], &[]),
+	("normalize-eol", &[], &["append", "verify"]),
+	("flatten-separator", &["flatten"], &[]),
+	("max-runtime", &[], &["once"]),
+	("idle-timeout", &[], &["once"]),
+	("summary-interval", &[], &["once"]),
+	("on-change-timeout", &["on-change-command"], &[]),
+	("control-socket", &[], &["once"]),
+	("log-dir", &[], &["no-file-log"])
+];
+
+/// The options giving the sources and the destinations, instead of --config
+const WATCH_OPTIONS: &[&str] = &["source", "destination", "more-destinations", "sources-from"];
+
+/// Largest accepted --keep-previous: more previous backups are better kept with --versioned
+const MAX_KEEP_PREVIOUS: usize = 10;
+
+/// Largest accepted --buffer-size, not to allocate unreasonable amounts of memory by mistake
+const MAX_BUFFER_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Separator of the directories in the names of the flattened backups, unless given
+const DEFAULT_FLATTEN_SEPARATOR: &str = "_";
+
 fn main() {
 	// Setup CLI arguments
-	let matches = App::new("file-watcher-backup")
+	let matches = build_cli().get_matches_from(args_with_env_flags());
+	let env_options = env_dependent_options(&matches);
+
+	if let Some(completions_matches) = matches.subcommand_matches("completions") {
+		// "shell" is required and restricted to the supported shells, so unwrap() here is safe
+		let shell = completions_matches.value_of("shell").unwrap().parse::<Shell>().unwrap();
+		build_cli().gen_completions_to("file-watcher-backup", shell, &mut io::stdout());
+		std::process::exit(exitcode::OK);
+	}
+
+	// The loggers aren't set up yet, so the only way to report an invalid level is the standard error
+	let log_level = matches.value_of("log-level").map(|level_value| match parse_log_level(level_value) {
+		Some(level) => level,
+		None => {
+			eprintln!("Invalid log level `{}`: expected one of error, warn, info, debug, trace", level_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	// "log-format" has a default value and is restricted to the supported formats
+	let log_format = match matches.value_of("log-format") {
+		Some("json") => LogFormat::Json,
+		_ => LogFormat::Text
+	};
+
+	// "log-max-size" has a default value so unwrap() here is safe
+	let log_max_size_value = matches.value_of("log-max-size").unwrap();
+	let log_max_size = match parse_size(log_max_size_value) {
+		Some(size) if size > 0 => size,
+		_ => {
+			eprintln!("Invalid log max size `{}`: expected a positive number of bytes, optionally followed by K, M, G or T", log_max_size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	// "log-keep-files" has a default value so unwrap() here is safe
+	let log_keep_files_value = matches.value_of("log-keep-files").unwrap();
+	let log_keep_files = match log_keep_files_value.parse::<usize>() {
+		Ok(keep_files) => keep_files,
+		Err(_) => {
+			eprintln!("Invalid number of log files to keep `{}`: expected a non-negative number", log_keep_files_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	// "log-retention-days" has a default value so unwrap() here is safe
+	let log_retention_value = matches.value_of("log-retention-days").unwrap();
+	let log_retention_days = match log_retention_value.parse::<u32>() {
+		Ok(days) if days > 0 => days,
+		_ => {
+			eprintln!("Invalid log retention `{}`: expected a positive number of days", log_retention_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+
+	// First: configure the console logger if we have an attached terminal (or are told to anyway).
+	// The JSON event stream owns the standard output, so in that case logs go to the standard error.
+	let json = matches.is_present("json");
+	let (stream, terminal_mode) = if json {
+		(Stream::Stderr, TerminalMode::Stderr)
+	} else {
+		(Stream::Stdout, TerminalMode::Mixed)
+	};
+	let term_log = !matches.is_present("no-term-log") && (matches.is_present("force-term-log") || atty::is(stream));
+	if term_log {
+		// Terminal
+		let term_level = if matches.is_present("quiet") {
+			LevelFilter::Error
+		} else {
+			log_level.unwrap_or_else(|| verbosity_level(matches.occurrences_of("verbose")))
+		};
+		// Without a terminal (a forced log captured by a supervisor), plain lines are written instead of colored ones
+		let term_logger = if atty::is(stream) { TermLogger::new(term_level, Config::default(), terminal_mode) } else { None };
+		match term_logger {
+			Some(term_logger) => loggers.push(term_logger),
+			None if json => loggers.push(WriteLogger::new(term_level, Config::default(), io::stderr())),
+			None => loggers.push(WriteLogger::new(term_level, Config::default(), io::stdout()))
+		}
+	}
+
+	let log_dir = value_of(&matches, &env_options, "log-dir").map(PathBuf::from);
+	let mut file_logger_error = None;
+	if !matches.is_present("no-file-log") {
+		match create_file_logger(log_level.unwrap_or(LevelFilter::Trace), log_dir.as_deref(), log_format, log_max_size, log_keep_files) {
+			Ok(file_logger) => loggers.push(file_logger),
+			Err(error) => file_logger_error = Some(error)
+		}
+	}
+
+	// Any number of loggers (even none at all) is fine here
+	CombinedLogger::init(loggers).unwrap();
+
+	// Only reported once the other loggers are set up
+	if let Some(error) = &file_logger_error {
+		warn!("{}, logging to the terminal only", error);
+	}
+
+	if file_logger_error.is_none() && !matches.is_present("no-file-log") {
+		// The logger has just been set up there, so the directory is known
+		if let Some(log_dir) = log_directory(log_dir.as_deref()) {
+			logging::remove_old_logs(&log_dir, log_retention_days);
+		}
+	}
+
+	if let Some(restore_matches) = matches.subcommand_matches("restore") {
+		let exit_code = run_restore(restore_matches);
+		log::logger().flush();
+		std::process::exit(exit_code);
+	}
+
+	if let Some(list_matches) = matches.subcommand_matches("list") {
+		let exit_code = run_list(list_matches);
+		log::logger().flush();
+		std::process::exit(exit_code);
+	}
+
+	if let Some(stats_matches) = matches.subcommand_matches("stats") {
+		let exit_code = run_stats(stats_matches);
+		log::logger().flush();
+		std::process::exit(exit_code);
+	}
+
+	if let Some(doctor_matches) = matches.subcommand_matches("doctor") {
+		let exit_code = run_doctor(doctor_matches);
+		log::logger().flush();
+		std::process::exit(exit_code);
+	}
+
+	if let Some(check_matches) = matches.subcommand_matches("check") {
+		let exit_code = run_check(check_matches);
+		log::logger().flush();
+		std::process::exit(exit_code);
+	}
+
+	if matches.occurrences_of("config") > 0 && WATCH_OPTIONS.iter().any(|name| matches.occurrences_of(name) > 0) {
+		error!("--config lists the sources and destinations: FILE, DIR, --destination and --sources-from can't be given with it");
+		std::process::exit(exitcode::USAGE);
+	}
+	// clap can't require FILE by itself, as it may be set in the environment, nor DIR when FWB_CONFIG is ignored
+	if config_file(&matches).is_none() {
+		if sources(&matches).is_empty() && matches.value_of("sources-from").is_none() {
+			error!("No source to watch: expected FILE, --sources-from or --config");
+			std::process::exit(exitcode::USAGE);
+		}
+		if matches.value_of("destination").is_none() {
+			error!("No destination to back up into: expected DIR or --config");
+			std::process::exit(exitcode::USAGE);
+		}
+	}
+
+	// "debounce" has a default value so unwrap() here is safe
+	let debounce_value = matches.value_of("debounce").unwrap();
+	let debounce = match debounce_value.parse::<u64>() {
+		Ok(millis) => Duration::from_millis(millis),
+		Err(error) => {
+			error!("Invalid debounce `{}`: expected a non-negative number of milliseconds", debounce_value);
+			trace!("{:?}", error);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	let min_interval = matches.value_of("min-interval").map(|interval_value| match interval_value.parse::<u64>() {
+		Ok(millis) => Duration::from_millis(millis),
+		Err(_) => {
+			error!("Invalid minimum interval `{}`: expected a non-negative number of milliseconds", interval_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let coalesce = matches.value_of("coalesce-ms").map(|coalesce_value| match coalesce_value.parse::<u64>() {
+		Ok(millis) if millis > 0 => Duration::from_millis(millis),
+		_ => {
+			error!("Invalid coalescing window `{}`: expected a positive number of milliseconds", coalesce_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let poll = matches.value_of("poll").map(|poll_value| match poll_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid poll interval `{}`: expected a positive number of seconds", poll_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let max_runtime = value_of(&matches, &env_options, "max-runtime").map(|runtime_value| match runtime_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid max runtime `{}`: expected a positive number of seconds", runtime_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let wait_for_source = matches.value_of("wait-for-source").map(|wait_value| match wait_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid wait for the sources `{}`: expected a positive number of seconds", wait_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let summary_interval = value_of(&matches, &env_options, "summary-interval").map(|interval_value| match interval_value.parse::<u64>() {
+		Ok(minutes) if minutes > 0 => Duration::from_secs(minutes * 60),
+		_ => {
+			error!("Invalid summary interval `{}`: expected a positive number of minutes", interval_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let prune_interval = matches.value_of("prune-interval").map(|interval_value| match interval_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid prune interval `{}`: expected a positive number of seconds", interval_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let on_change_timeout = value_of(&matches, &env_options, "on-change-timeout").map(|timeout_value| match timeout_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid command timeout `{}`: expected a positive number of seconds", timeout_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let notify_interval = matches.value_of("notify-interval").map(|interval_value| match interval_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid notification interval `{}`: expected a positive number of seconds", interval_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let idle_timeout = value_of(&matches, &env_options, "idle-timeout").map(|timeout_value| match timeout_value.parse::<u64>() {
+		Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+		_ => {
+			error!("Invalid idle timeout `{}`: expected a positive number of seconds", timeout_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let normalize_eol = value_of(&matches, &env_options, "normalize-eol").map(|ending_value| match LineEnding::parse(ending_value) {
+		Some(ending) => ending,
+		None => {
+			error!("Invalid line ending `{}`: expected lf or crlf", ending_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let checksum_algo = matches.value_of("checksum-algo").map(|algo_value| match ChecksumAlgo::parse(algo_value) {
+		Some(algo) => algo,
+		None => {
+			error!("Invalid checksum algorithm `{}`: expected blake3, sha256 or xxhash", algo_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	// "on-events" has a default value so unwrap() here is safe
+	let trigger_events: Vec<TriggerEvent> = matches.value_of("on-events").unwrap()
+		.split(',')
+		.map(str::trim)
+		.filter(|name| !name.is_empty())
+		.map(|name| match TriggerEvent::parse(name) {
+			Some(event) => event,
+			None => {
+				error!("Invalid event `{}`: expected a comma-separated list of write, create, rename, chmod", name);
+				std::process::exit(exitcode::USAGE);
+			}
+		})
+		.collect();
+	if trigger_events.is_empty() {
+		error!("No event triggers a backup: expected a comma-separated list of write, create, rename, chmod");
+		std::process::exit(exitcode::USAGE);
+	}
+
+	let keep = value_of(&matches, &env_options, "keep").map(|keep_value| match keep_value.parse::<usize>() {
+		Ok(keep) if keep > 0 => keep,
+		_ => {
+			error!("Invalid keep `{}`: expected a positive number of backups", keep_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let keep_per_day = value_of(&matches, &env_options, "keep-per-day").map(|keep_value| match keep_value.parse::<usize>() {
+		Ok(keep) if keep > 0 => keep,
+		_ => {
+			error!("Invalid keep per day `{}`: expected a positive number of backups", keep_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	// Without a value, only the last backup is kept
+	let keep_previous = match (matches.is_present("keep-previous"), matches.value_of("keep-previous")) {
+		(false, _) => None,
+		(true, None) => Some(1),
+		(true, Some(count_value)) => match count_value.parse::<usize>() {
+			Ok(count) if (1..=MAX_KEEP_PREVIOUS).contains(&count) => Some(count),
+			_ => {
+				error!("Invalid number of previous backups to keep `{}`: expected a number from 1 to {}", count_value, MAX_KEEP_PREVIOUS);
+				std::process::exit(exitcode::USAGE);
+			}
+		}
+	};
+
+	let max_size = value_of(&matches, &env_options, "max-size").map(|size_value| match parse_size(size_value) {
+		Some(size) if size > 0 => size,
+		_ => {
+			error!("Invalid max size `{}`: expected a positive number of bytes, optionally followed by K, M, G or T", size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let max_bytes_per_sec = matches.value_of("max-bytes-per-sec").map(|rate_value| match parse_size(rate_value) {
+		Some(rate) if rate > 0 => rate,
+		_ => {
+			error!("Invalid max bytes per second `{}`: expected a positive number of bytes, optionally followed by K, M, G or T", rate_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let max_file_size = matches.value_of("max-file-size").map(|size_value| match parse_size(size_value) {
+		Some(size) => size,
+		None => {
+			error!("Invalid max file size `{}`: expected a number of bytes, optionally followed by K, M, G or T", size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let min_file_size = matches.value_of("min-file-size").map(|size_value| match parse_size(size_value) {
+		Some(size) => size,
+		None => {
+			error!("Invalid min file size `{}`: expected a number of bytes, optionally followed by K, M, G or T", size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let compress_min_size = value_of(&matches, &env_options, "compress-min-size").map(|size_value| match parse_size(size_value) {
+		Some(size) => size,
+		None => {
+			error!("Invalid min size to compress `{}`: expected a number of bytes, optionally followed by K, M, G or T", size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let flatten = if matches.is_present("flatten") {
+		let separator = value_of(&matches, &env_options, "flatten-separator").unwrap_or(DEFAULT_FLATTEN_SEPARATOR);
+		// The backups must stay at the top level, and `%` is taken by the encoding of the separator
+		if separator.is_empty() || separator.chars().any(|character| character == '%' || std::path::is_separator(character)) {
+			error!("Invalid flatten separator `{}`: expected some characters other than `%` and the path separators", separator);
+			std::process::exit(exitcode::USAGE);
+		}
+		Some(separator.to_string())
+	} else {
+		None
+	};
+
+	if let (Some(max_file_size), Some(min_file_size)) = (max_file_size, min_file_size) {
+		if min_file_size > max_file_size {
+			error!("The min file size can't be larger than the max file size");
+			std::process::exit(exitcode::USAGE);
+		}
+	}
+
+	let buffer_size = matches.value_of("buffer-size").map(|size_value| match parse_size(size_value) {
+		Some(size) if size > 0 && size <= MAX_BUFFER_SIZE => size as usize,
+		_ => {
+			error!("Invalid buffer size `{}`: expected a positive number of bytes up to 1G, optionally followed by K, M or G", size_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	// "retries" has a default value so unwrap() here is safe
+	let retries_value = matches.value_of("retries").unwrap();
+	let retries = match retries_value.parse::<u32>() {
+		Ok(retries) => retries,
+		Err(_) => {
+			error!("Invalid retries `{}`: expected a non-negative number", retries_value);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	let dest_template = value_of(&matches, &env_options, "dest-template").map(|template| match DestTemplate::parse(template) {
+		Ok(template) => template,
+		Err(error) => {
+			error!("{}", error);
+			std::process::exit(exitcode::USAGE);
+		}
+	});
+
+	let watches_file = match (config_file(&matches), matches.value_of("sources-from")) {
+		(Some(config_path), _) => Some(WatchesFile::Config(PathBuf::from(config_path))),
+		(None, Some(sources_path)) => Some(WatchesFile::Sources {
+			path: PathBuf::from(sources_path),
+			sources: sources(&matches).into_iter().map(PathBuf::from).collect(),
+			destinations: destinations(&matches)
+		}),
+		(None, None) => None
+	};
+	let watches = match &watches_file {
+		Some(watches_file) => match watches_file.load() {
+			Ok(watches) => {
+				debug!("{} source(s) listed in `{}`", watches.len(), watches_file.path().display());
+				watches
+			},
+			Err(error) => {
+				error!("{}", error);
+				std::process::exit(exitcode::CONFIG);
+			}
+		},
+		None => {
+			let destinations = destinations(&matches);
+			sources(&matches).into_iter()
+				.flat_map(|source| destinations.iter().map(move |destination| Watch::new(PathBuf::from(source), destination.clone())))
+				.collect()
+		}
+	};
+
+	let mut exclude = GlobSetBuilder::new();
+	for pattern in matches.values_of("exclude").into_iter().flatten() {
+		// "dir/" stands for the directory and everything inside it
+		let patterns = match pattern.strip_suffix('/') {
+			Some(directory) => vec![directory.to_string(), format!("{}/**", directory)],
+			None => vec![pattern.to_string()]
+		};
+		for pattern in patterns {
+			match Glob::new(&pattern) {
+				Ok(glob) => {
+					exclude.add(glob);
+				},
+				Err(error) => {
+					error!("Invalid exclude pattern `{}`: {}", pattern, error);
+					std::process::exit(exitcode::USAGE);
+				}
+			}
+		}
+	}
+	let exclude = match exclude.build() {
+		Ok(exclude) => exclude,
+		Err(error) => {
+			error!("Invalid exclude patterns: {}", error);
+			std::process::exit(exitcode::USAGE);
+		}
+	};
+
+	// Launched outside of a shell, nobody else expands "~" and the environment variables
+	let watches: Vec<Watch> = watches.into_iter()
+		.map(|watch| {
+			let source = paths::expand_path(&watch.source);
+			let destination = paths::expand_path(&watch.destination);
+			debug!("Resolved `{}` into `{}`", watch.source.display(), paths::absolute_path(&source).display());
+			debug!("Resolved `{}` into `{}`", watch.destination.display(), paths::absolute_path(&destination).display());
+			Watch { source, destination, ..watch }
+		})
+		.collect();
+	// Backups always go into a directory, a file given as destination is a mistake
+	if let Some(watch) = watches.iter().find(|watch| watch.destination.is_file()) {
+		let error = BackupError::DestinationIsFile(watch.destination.clone());
+		error!("{}", error);
+		std::process::exit(exit_code(&error));
+	}
+
+	let mut config = BackupConfig::new(watches);
+	config.debounce = debounce;
+	config.min_interval = min_interval;
+	config.coalesce = coalesce;
+	config.trigger_events = trigger_events;
+	config.poll = poll;
+	config.versioned = matches.is_present("versioned");
+	config.dest_template = dest_template;
+	config.destination_by_date = matches.is_present("destination-by-date");
+	config.keep = keep;
+	config.keep_per_day = keep_per_day;
+	config.max_size = max_size;
+	config.latest_link = matches.is_present("latest-link");
+	config.keep_previous = keep_previous;
+	config.compress = matches.is_present("compress");
+	config.compress_min_size = compress_min_size;
+	if matches.is_present("encrypt") {
+		match read_passphrase(true) {
+			Ok(passphrase) => config.encryption = Some(passphrase),
+			Err(reason) => {
+				error!("No passphrase to encrypt the backups with: {}", reason);
+				std::process::exit(exitcode::USAGE);
+			}
+		}
+	}
+	config.append = matches.is_present("append");
+	config.preserve_metadata = matches.is_present("preserve-metadata");
+	config.dedup = matches.is_present("dedup");
+	config.manifest = matches.is_present("manifest");
+	config.verify = matches.is_present("verify");
+	config.checksum_algo = checksum_algo;
+	config.max_bytes_per_sec = max_bytes_per_sec;
+	config.buffer_size = buffer_size;
+	config.progress = matches.is_present("progress");
+	config.evict_when_full = matches.is_present("evict-when-full");
+	config.retries = retries;
+	config.normalize_eol = normalize_eol;
+	config.quarantine_on_error = matches.is_present("quarantine-on-error");
+	config.mirror_deletes = matches.is_present("mirror-deletes");
+	config.recursive = !matches.is_present("no-recursive");
+	config.flatten = flatten;
+	config.follow_symlinks = matches.is_present("follow-symlinks");
+	config.exclude = exclude;
+	config.max_file_size = max_file_size;
+	config.min_file_size = min_file_size;
+	config.include_extensions = matches.values_of("include-ext").into_iter().flatten()
+		.map(|extension| extension.trim_start_matches('.').to_lowercase())
+		.collect();
+	config.dry_run = matches.is_present("dry-run");
+	config.initial_copy = !matches.is_present("no-initial-copy");
+	config.final_backup = !matches.is_present("no-final-backup");
+	config.verify_on_startup = matches.is_present("verify-on-startup");
+	config.once = matches.is_present("once");
+	config.max_runtime = max_runtime;
+	config.idle_timeout = idle_timeout;
+	config.summary_interval = summary_interval;
+	config.wait_for_source = wait_for_source;
+	config.prune_interval = prune_interval;
+	config.json = json;
+	config.notify = matches.is_present("notify");
+	config.webhook = matches.value_of("webhook").map(str::to_string);
+	config.on_change_command = matches.value_of("on-change-command").map(String::from);
+	config.on_change_timeout = on_change_timeout;
+	config.notify_interval = notify_interval;
+	config.metrics_addr = matches.value_of("metrics-addr").map(str::to_string);
+	config.pid_file = matches.value_of("pid-file").map(|path| paths::expand_path(Path::new(path)));
+	config.control_socket = value_of(&matches, &env_options, "control-socket").map(|path| paths::expand_path(Path::new(path)));
+	config.ssh_key = matches.value_of("ssh-key").map(|key| paths::expand_path(Path::new(key)));
+	config.watches_file = watches_file;
+
+	let exit_code = match run(config) {
+		Ok(()) => exitcode::OK,
+		Err(error) => {
+			error!("{}", error);
+			exit_code(&error)
+		}
+	};
+
+	log::logger().flush();
+	std::process::exit(exit_code);
+}
+
+/// Build the command line interface, shared between the argument parsing and the completion scripts.
+fn build_cli() -> App<'static, 'static> {
+	App::new("file-watcher-backup")
 		.about("Whenever a file changes, copy it's content to a backup file.")
 		.version("0.1.0")
 		.author("niktekusho <https://github.com/nikteksuho")
-		.arg(Arg::with_name("source")
+		.setting(AppSettings::SubcommandsNegateReqs)
+		.setting(AppSettings::VersionlessSubcommands)
+		.arg(with_env_default(Arg::with_name("source")
 			.short("s")
 			.long("source")
 			.value_name("FILE")
-			.help("Source file to watch")
-			.required(true)
+			.help("Source file(s) to watch; directories are watched (and mirrored) whole, and glob patterns (quoted) stand for the files they match")
+			.required_unless_one(&["config", "sources-from"])
 			.index(1)
-			.takes_value(true))
+			.multiple(true)
+			.takes_value(true), "source"))
 		.arg(Arg::with_name("destination")
+			.value_name("DIR")
+			.env("FWB_DESTINATION")
+			.help("Target directory in which the file will be copied, or s3://BUCKET/PREFIX or sftp://[USER@]HOST[:PORT]/PATH to upload it")
+			.required_unless("config")
+			.index(2)
+			.takes_value(true))
+		.arg(Arg::with_name("more-destinations")
 			.short("d")
 			.long("destination")
 			.value_name("DIR")
-			.help("Target directory in which the file will be copied")
-			.required(true)
-			.index(2)
+			.help("Another destination every file is backed up into as well, independently of DIR (can be repeated)")
+			.multiple(true)
+			.number_of_values(1)
+			.takes_value(true))
+		.arg(Arg::with_name("config")
+			.long("config")
+			.env("FWB_CONFIG")
+			.value_name("FILE")
+			.help("TOML file listing the sources to watch and their destinations (instead of FILE and DIR)")
+			.takes_value(true))
+		.arg(Arg::with_name("sources-from")
+			.long("sources-from")
+			.env("FWB_SOURCES_FROM")
+			.value_name("FILE")
+			.help("File listing more sources to watch, one per line (blank lines and lines starting with `#` are skipped), \
+				all backed up into DIR (and the other destinations)")
+			.takes_value(true))
+		.arg(Arg::with_name("versioned")
+			.long("versioned")
+			.help("Keep every backup as a distinct timestamped file instead of overwriting the previous one"))
+		.arg(Arg::with_name("dest-template")
+			.long("dest-template")
+			.value_name("TEMPLATE")
+			.help("Name of the backup files, with the placeholders {name}, {stem}, {ext} (including the dot), {parent} \
+				(the directory of the source) and {date:FORMAT} (strftime-like), e.g. `{stem}-{date:%Y%m%d}{ext}`; \
+				`/` puts them into subdirectories, e.g. `{parent}/{name}`")
+			.conflicts_with("versioned")
+			.takes_value(true))
+		.arg(Arg::with_name("debounce")
+			.long("debounce")
+			.env("FWB_DEBOUNCE")
+			.value_name("MILLIS")
+			.help("Time to wait for the file to settle before backing it up")
+			.default_value("1000")
+			.takes_value(true))
+		.arg(Arg::with_name("min-interval")
+			.long("min-interval")
+			.env("FWB_MIN_INTERVAL")
+			.value_name("MILLIS")
+			.help("Minimum time between two backups of the same file: changes coming sooner are not backed up")
+			.takes_value(true))
+		.arg(Arg::with_name("coalesce-ms")
+			.long("coalesce-ms")
+			.env("FWB_COALESCE_MS")
+			.value_name("MILLIS")
+			.help("Wait for MILLIS without any change before backing up, then back up each changed file once")
+			.takes_value(true))
+		.arg(Arg::with_name("on-events")
+			.long("on-events")
+			.env("FWB_ON_EVENTS")
+			.value_name("EVENTS")
+			.help("Comma-separated events triggering a backup: write, create, rename (files moved into place, as by \
+				atomic saves: leaving it out misses them) and chmod (permission changes)")
+			.default_value("write,create,rename")
+			.takes_value(true))
+		.arg(Arg::with_name("poll")
+			.long("poll")
+			.env("FWB_POLL")
+			.value_name("SECONDS")
+			.help("Check the sources for changes every SECONDS instead of relying on filesystem notifications, \
+				which network shares (NFS, SMB) and some virtualized filesystems never send")
+			.takes_value(true))
+		.arg(Arg::with_name("destination-by-date")
+			.long("destination-by-date")
+			.help("Store the backups in a subdirectory of the destination named after the day they're made, \
+				like `<DIR>/2024-01-15/notes.txt`"))
+		.arg(Arg::with_name("keep")
+			.long("keep")
+			.value_name("N")
+			.help("Number of versioned backups to keep for each source (older ones are deleted)")
+			.requires("versioned")
+			.takes_value(true))
+		.arg(Arg::with_name("keep-per-day")
+			.long("keep-per-day")
+			.value_name("N")
+			.help("Number of versioned backups to keep for each source and each day, the most recent ones of the day \
+				(every day with backups keeps at least one)")
+			.requires("versioned")
+			.takes_value(true))
+		.arg(Arg::with_name("max-size")
+			.long("max-size")
+			.value_name("BYTES")
+			.help("Maximum total size of the versioned backups of each source, with an optional K, M, G or T suffix \
+				(powers of 1024), e.g. `500M`; the oldest backups are deleted first")
+			.requires("versioned")
+			.takes_value(true))
+		.arg(Arg::with_name("prune-interval")
+			.long("prune-interval")
+			.env("FWB_PRUNE_INTERVAL")
+			.value_name("SECONDS")
+			.help("Delete the old backups (--keep, --keep-per-day, --max-size) every SECONDS in the background, \
+				instead of right after each backup")
+			.takes_value(true))
+		.arg(Arg::with_name("keep-previous")
+			.long("keep-previous")
+			.value_name("N")
+			.help("Before overwriting a backup, keep it as `<name>.prev` (and the N - 1 older ones as `<name>.prev.1`... \
+				with --keep-previous=N, up to 10)")
+			.takes_value(true)
+			.min_values(0)
+			.require_equals(true)
+			.conflicts_with_all(&["versioned", "append"]))
+		.arg(Arg::with_name("latest-link")
+			.long("latest-link")
+			.help("Keep a `<name>.latest` symbolic link to the newest versioned backup of each source, \
+				for the scripts needing a stable path (a copy where links aren't supported)")
+			.requires("versioned"))
+		.arg(Arg::with_name("compress")
+			.long("compress")
+			.help("Store the backups gzipped, with an additional `.gz` extension"))
+		.arg(Arg::with_name("compress-min-size")
+			.long("compress-min-size")
+			.value_name("BYTES")
+			.help("Only gzip the files of at least BYTES (with an optional K, M, G or T suffix), storing the smaller ones as they are")
+			.requires("compress")
+			.takes_value(true))
+		.arg(Arg::with_name("encrypt")
+			.long("encrypt")
+			.help("Encrypt the backups with a passphrase (read from $FILE_WATCHER_BACKUP_PASSPHRASE, or typed at startup), \
+				with an additional `.enc` extension; they are age files, that `age --decrypt` can read too"))
+		.arg(Arg::with_name("append")
+			.long("append")
+			.help("For sources that only grow (like logs): append their new content to the backups instead of \
+				copying them whole (a source that shrank is copied whole again)")
+			.conflicts_with_all(&["versioned", "dest-template", "compress", "encrypt", "dedup"]))
+		.arg(Arg::with_name("preserve-metadata")
+			.long("preserve-metadata")
+			.help("Give the backups the modification time (and on Unix the permissions) of their source"))
+		.arg(Arg::with_name("dedup")
+			.long("dedup")
+			.help("Hard link backups identical to one made before instead of copying them again"))
+		.arg(Arg::with_name("manifest")
+			.long("manifest")
+			.help("Record every backup (source, file, time, size and SHA-256) in a `manifest.json` next to it"))
+		.arg(Arg::with_name("verify")
+			.long("verify")
+			.help("Read every backup back and compare it against its source, copying it again on mismatch"))
+		.arg(Arg::with_name("checksum-algo")
+			.long("checksum-algo")
+			.env("FWB_CHECKSUM_ALGO")
+			.value_name("ALGO")
+			.help("Hash the contents with blake3, sha256 or xxhash [default: xxhash, sha256 with --verify, --encrypt or --manifest]")
+			.takes_value(true))
+		.arg(Arg::with_name("max-bytes-per-sec")
+			.long("max-bytes-per-sec")
+			.env("FWB_MAX_BYTES_PER_SEC")
+			.value_name("BYTES")
+			.help("Limit the copies to BYTES per second, with an optional K, M, G or T suffix (powers of 1024), e.g. `1M`")
+			.takes_value(true))
+		.arg(Arg::with_name("buffer-size")
+			.long("buffer-size")
+			.env("FWB_BUFFER_SIZE")
+			.value_name("BYTES")
+			.help("Copy the files by chunks of BYTES, with an optional K, M or G suffix (powers of 1024), e.g. `1M`, \
+				instead of leaving the copy to the system [default for throttled and --progress copies: 256K]")
+			.takes_value(true))
+		.arg(Arg::with_name("progress")
+			.long("progress")
+			.help("Log the progress of the copies every few seconds, for large files"))
+		.arg(Arg::with_name("retries")
+			.long("retries")
+			.env("FWB_RETRIES")
+			.value_name("N")
+			.help("How many times a failed copy is retried (waiting 100ms, then 200ms, 400ms...) before giving up")
+			.default_value("3")
+			.takes_value(true))
+		.arg(Arg::with_name("normalize-eol")
+			.long("normalize-eol")
+			.value_name("STYLE")
+			.help("Convert the line endings of the text files to lf or crlf in their backups (binary files are copied as they are)")
+			.conflicts_with_all(&["append", "verify"])
+			.takes_value(true))
+		.arg(Arg::with_name("quarantine-on-error")
+			.long("quarantine-on-error")
+			.help("Move the backups left incomplete by a failed --append, or failing --verify, aside to a `.corrupt` file"))
+		.arg(Arg::with_name("mirror-deletes")
+			.long("mirror-deletes")
+			.help("Remove files deleted from a watched directory from its backup too"))
+		.arg(Arg::with_name("recursive")
+			.long("recursive")
+			.help("Back up the whole tree of the watched directories (the default)")
+			.overrides_with("no-recursive"))
+		.arg(Arg::with_name("no-recursive")
+			.long("no-recursive")
+			.help("Only back up the files at the top level of the watched directories, not their subdirectories")
+			.overrides_with("recursive"))
+		.arg(Arg::with_name("flatten")
+			.long("flatten")
+			.help("Back up the trees of the watched directories at the top level of their destinations, \
+				naming the files after their relative paths (like `sub_dir_config.toml`) instead of mirroring the subdirectories"))
+		.arg(Arg::with_name("flatten-separator")
+			.long("flatten-separator")
+			.value_name("SEP")
+			.help("Separator of the directories in the names of the flattened backups (`_` by default); \
+				found in the names themselves, it's percent-encoded")
+			.requires("flatten")
+			.takes_value(true))
+		.arg(Arg::with_name("follow-symlinks")
+			.long("follow-symlinks")
+			.help("Watch the targets of the sources that are symbolic links, instead of the links, \
+				and back up the links found in watched directories"))
+		.arg(Arg::with_name("exclude")
+			.long("exclude")
+			.value_name("GLOB")
+			.help("Don't back up the paths of watched directories (relative to them) matching this pattern; \
+				a trailing `/` excludes a whole directory (can be repeated)")
+			.multiple(true)
+			.number_of_values(1)
+			.takes_value(true))
+		.arg(Arg::with_name("evict-when-full")
+			.long("evict-when-full")
+			.help("When the destination has no room for a backup, delete the oldest versions of the same source \
+				(never the most recent one) instead of skipping it")
+			.requires("versioned"))
+		.arg(Arg::with_name("max-file-size")
+			.long("max-file-size")
+			.env("FWB_MAX_FILE_SIZE")
+			.value_name("BYTES")
+			.help("Don't back up the files larger than BYTES, with an optional K, M, G or T suffix (powers of 1024), e.g. `2G`")
+			.takes_value(true))
+		.arg(Arg::with_name("min-file-size")
+			.long("min-file-size")
+			.env("FWB_MIN_FILE_SIZE")
+			.value_name("BYTES")
+			.help("Don't back up the files smaller than BYTES, with an optional K, M, G or T suffix (powers of 1024)")
+			.takes_value(true))
+		.arg(Arg::with_name("include-ext")
+			.long("include-ext")
+			.value_name("EXT")
+			.help("Only back up the files of watched directories with this extension, case-insensitively \
+				(can be repeated); --exclude patterns still apply, and win over it")
+			.multiple(true)
+			.number_of_values(1)
+			.takes_value(true))
+		.arg(Arg::with_name("dry-run")
+			.long("dry-run")
+			.help("Only log the copies and deletions that would be made, without changing anything on disk"))
+		.arg(Arg::with_name("no-initial-copy")
+			.long("no-initial-copy")
+			.help("Don't back up the sources on startup, only when they change afterwards")
+			.conflicts_with("once"))
+		.arg(Arg::with_name("no-final-backup")
+			.long("no-final-backup")
+			.help("Don't back up the latest changes of the sources when exiting, even those still being debounced")
+			.conflicts_with("once"))
+		.arg(Arg::with_name("verify-on-startup")
+			.long("verify-on-startup")
+			.help("On startup, compare every file with its latest backup (versioned, compressed or encrypted ones included) \
+				and only back up the ones missing or changed, reporting how many were")
+			.conflicts_with("no-initial-copy"))
+		.arg(Arg::with_name("once")
+			.long("once")
+			.help("Back up the sources once and exit, without watching them"))
+		.arg(Arg::with_name("max-runtime")
+			.long("max-runtime")
+			.value_name("SECONDS")
+			.help("Stop watching the sources and exit after this long")
+			.conflicts_with("once")
+			.takes_value(true))
+		.arg(Arg::with_name("idle-timeout")
+			.long("idle-timeout")
+			.value_name("SECONDS")
+			.help("Stop watching the sources and exit once none of them changed for this long")
+			.conflicts_with("once")
+			.takes_value(true))
+		.arg(Arg::with_name("wait-for-source")
+			.long("wait-for-source")
+			.env("FWB_WAIT_FOR_SOURCE")
+			.value_name("SECONDS")
+			.help("Wait up to SECONDS on startup for the sources that don't exist yet, instead of failing right away")
+			.takes_value(true))
+		.arg(Arg::with_name("summary-interval")
+			.long("summary-interval")
+			.value_name("MINUTES")
+			.help("Log how many backups of each source succeeded, failed or were skipped every MINUTES, \
+				as when stopping")
+			.conflicts_with("once")
+			.takes_value(true))
+		.arg(Arg::with_name("json")
+			.long("json")
+			.help("Write every backup event as a line of JSON on the standard output (terminal logs go to the standard error)"))
+		.arg(Arg::with_name("notify")
+			.long("notify")
+			.help("Show a desktop notification on the first backup and when backups fail (at most one a minute)"))
+		.arg(Arg::with_name("webhook")
+			.long("webhook")
+			.env("FWB_WEBHOOK")
+			// The URL may hold a token
+			.hide_env_values(true)
+			.value_name("URL")
+			.help("POST a JSON report (source, destination, bytes, timestamp, status) to URL after every backup, successful or not")
+			.takes_value(true))
+		.arg(Arg::with_name("notify-interval")
+			.long("notify-interval")
+			.env("FWB_NOTIFY_INTERVAL")
+			.value_name("SECONDS")
+			.help("Send at most one desktop notification or webhook report every SECONDS: the backups made in between \
+				are reported together, in a single summary")
+			.takes_value(true))
+		.arg(Arg::with_name("on-change-command")
+			.long("on-change-command")
+			.env("FWB_ON_CHANGE_COMMAND")
+			.value_name("CMD")
+			.help("Run CMD with the shell after every backup, in the background, with the source, the backup and its size \
+				in FWB_BACKUP_SOURCE, FWB_BACKUP and FWB_BACKUP_BYTES")
 			.takes_value(true))
-		.get_matches();
+		.arg(Arg::with_name("on-change-timeout")
+			.long("on-change-timeout")
+			.value_name("SECONDS")
+			.help("Kill the --on-change-command still running after SECONDS [default: 600]")
+			.requires("on-change-command")
+			.takes_value(true))
+		.arg(Arg::with_name("metrics-addr")
+			.long("metrics-addr")
+			.env("FWB_METRICS_ADDR")
+			.value_name("HOST:PORT")
+			.help("Serve Prometheus metrics (backups, bytes copied, errors, skipped backups, time since the last backup) on http://HOST:PORT/metrics")
+			.takes_value(true))
+		.arg(Arg::with_name("control-socket")
+			.long("control-socket")
+			.value_name("PATH")
+			.help("Answer the `status` and `backup-now` commands (one per line) on the Unix socket PATH, \
+				only accessible to the user running the process")
+			.conflicts_with("once")
+			.takes_value(true))
+		.arg(Arg::with_name("pid-file")
+			.long("pid-file")
+			.env("FWB_PID_FILE")
+			.value_name("FILE")
+			.help("Write the PID of the process to this file while it runs, refusing to start if another running process wrote it")
+			.takes_value(true))
+		.arg(Arg::with_name("ssh-key")
+			.long("ssh-key")
+			.env("FWB_SSH_KEY")
+			.value_name("FILE")
+			.help("Private key for sftp:// destinations [default: the SSH agent, then ~/.ssh/id_ed25519, id_ecdsa or id_rsa]")
+			.takes_value(true))
+		.arg(Arg::with_name("log-level")
+			.long("log-level")
+			.env("FWB_LOG_LEVEL")
+			.value_name("LEVEL")
+			.help("Verbosity of the logs: error, warn, info, debug or trace, overriding -v [default: info on the terminal, trace in the log file]")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("verbose")
+			.short("v")
+			.long("verbose")
+			.help("Log more on the terminal: debug messages with -v, trace messages with -vv")
+			.multiple(true)
+			.conflicts_with("quiet")
+			.global(true))
+		.arg(Arg::with_name("quiet")
+			.short("q")
+			.long("quiet")
+			.help("Only log errors on the terminal, whatever the --log-level (the log file is unaffected)")
+			.global(true))
+		.arg(Arg::with_name("force-term-log")
+			.long("force-term-log")
+			.help("Log to the standard output (the standard error with --json) even when it isn't a terminal, \
+				as when captured by a supervisor")
+			.global(true))
+		.arg(Arg::with_name("no-term-log")
+			.long("no-term-log")
+			.help("Don't log to the terminal, even when there is one (the log file is unaffected)")
+			.conflicts_with("force-term-log")
+			.global(true))
+		.arg(Arg::with_name("log-dir")
+			.long("log-dir")
+			.value_name("DIR")
+			.help("Directory in which the daily log files are written [default: $HOME/file-watcher-backup]")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("log-format")
+			.long("log-format")
+			.env("FWB_LOG_FORMAT")
+			.value_name("FORMAT")
+			.help("Format of the log file: plain text, or one JSON object per line")
+			.possible_values(&["text", "json"])
+			.default_value("text")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("log-max-size")
+			.long("log-max-size")
+			.env("FWB_LOG_MAX_SIZE")
+			.value_name("BYTES")
+			.help("Size beyond which the log file is rolled over (into `<date>.1.log`, `<date>.2.log`...), \
+				with an optional K, M, G or T suffix")
+			.default_value("10M")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("log-keep-files")
+			.long("log-keep-files")
+			.env("FWB_LOG_KEEP_FILES")
+			.value_name("N")
+			.help("Number of rolled over log files to keep for each day")
+			.default_value("5")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("log-retention-days")
+			.long("log-retention-days")
+			.env("FWB_LOG_RETENTION_DAYS")
+			.value_name("DAYS")
+			.help("Log files older than DAYS are deleted on startup")
+			.default_value("30")
+			.global(true)
+			.takes_value(true))
+		.arg(Arg::with_name("no-file-log")
+			.long("no-file-log")
+			.help("Don't write any log file, only log to the terminal (if any)")
+			.global(true)
+			.conflicts_with("log-dir"))
+		.subcommand(SubCommand::with_name("restore")
+			.about("Copy a versioned backup back over its source (the most recent one by default)")
+			.arg(Arg::with_name("source")
+				.short("s")
+				.long("source")
+				.value_name("FILE")
+				.help("Source file to restore")
+				.required(true)
+				.takes_value(true))
+			.arg(Arg::with_name("destination")
+				.short("d")
+				.long("destination")
+				.value_name("DIR")
+				.help("Directory holding the versioned backups of the source")
+				.required(true)
+				.takes_value(true))
+			.arg(Arg::with_name("version")
+				.long("version")
+				.value_name("TIMESTAMP")
+				.help("Version to restore, as in the name of the backup, e.g. `2019-07-14_21-05-09`")
+				.takes_value(true))
+			.arg(Arg::with_name("save-current")
+				.long("save-current")
+				.help("Back up the current content of the source as a new version before overwriting it")))
+		.subcommand(SubCommand::with_name("list")
+			.about("List the versioned backups of a source, newest first")
+			.arg(Arg::with_name("source")
+				.short("s")
+				.long("source")
+				.value_name("FILE")
+				.help("Source file whose backups are listed")
+				.required(true)
+				.takes_value(true))
+			.arg(Arg::with_name("destination")
+				.short("d")
+				.long("destination")
+				.value_name("DIR")
+				.help("Directory holding the versioned backups of the source")
+				.required(true)
+				.takes_value(true))
+			.arg(Arg::with_name("checksum")
+				.long("checksum")
+				.help("Show the SHA-256 checksum of the content of every backup"))
+			.arg(Arg::with_name("json")
+				.long("json")
+				.help("Print the backups as a JSON array")))
+		.subcommand(SubCommand::with_name("stats")
+			.about("Summarize the backups of a destination directory")
+			.arg(Arg::with_name("destination")
+				.short("d")
+				.long("destination")
+				.value_name("DIR")
+				.help("Directory holding the backups")
+				.required(true)
+				.takes_value(true))
+			.arg(Arg::with_name("json")
+				.long("json")
+				.help("Print the summary as a JSON object")))
+		.subcommand(SubCommand::with_name("check")
+			.about("Check that the sources and destinations of a configuration file are usable, without backing up anything")
+			.arg(Arg::with_name("config")
+				.long("config")
+				.value_name("FILE")
+				.help("TOML file listing the sources to watch and their destinations")
+				.required(true)
+				.takes_value(true)))
+		.subcommand(SubCommand::with_name("doctor")
+			.about("Check the environment (home and log directories, file system watcher), with hints to fix the problems found"))
+		.subcommand(SubCommand::with_name("completions")
+			.about("Print the completion script for SHELL")
+			.setting(AppSettings::Hidden)
+			.arg(Arg::with_name("shell")
+				.value_name("SHELL")
+				.help("Shell to complete the arguments for")
+				.possible_values(&Shell::variants())
+				.required(true)))
+}
 
-	let mut loggers: Vec<Box<SharedLogger>> = Vec::new();
+/// `arg` defaulting to the value of its environment variable, if set: unlike `Arg::env`, which clap adds to the values
+/// of the command line, a default is only taken when the command line gives none.
+fn with_env_default(arg: Arg<'static, 'static>, name: &str) -> Arg<'static, 'static> {
+	match env::var_os(env_variable(name)) {
+		// Built once, the command line lives as long as the program anyway
+		Some(value) => arg.default_value_os(Box::leak(value.into_boxed_os_str())).hide_default_value(true),
+		None => arg
+	}
+}
 
-	// First: configure the console logger if we have an attached terminal
-	if atty::is(Stream::Stdout) {
-		// Terminal
-		loggers.push(TermLogger::new(LevelFilter::Debug, Config::default(), TerminalMode::Mixed).unwrap());
+/// The arguments of the command line, preceded by the [`ENV_FLAGS`] set in the environment (but not on the command line).
+fn args_with_env_flags() -> Vec<OsString> {
+	let mut args: Vec<OsString> = env::args_os().collect();
+	let mut env_flags: Vec<OsString> = Vec::new();
+	for flag in ENV_FLAGS {
+		let long = format!("--{}", flag);
+		// Given twice, a flag is an error
+		if args.iter().any(|arg| *arg == *long) {
+			continue;
+		}
+		let variable = env_variable(flag);
+		let value = match env::var_os(&variable) {
+			Some(value) => value,
+			None => continue
+		};
+		// The loggers aren't set up yet, so the only way to report an invalid value is the standard error
+		match value.to_str().and_then(parse_env_flag) {
+			Some(true) => env_flags.push(long.into()),
+			Some(false) => (),
+			None => {
+				eprintln!("Invalid value `{}` of {}: expected true or false", value.to_string_lossy(), variable);
+				std::process::exit(exitcode::USAGE);
+			}
+		}
 	}
+	args.splice(1..1, env_flags);
+	args
+}
 
-	let file_logger = create_file_logger();
-	if file_logger.is_some() {
-		loggers.push(file_logger.unwrap());
+/// The environment variable standing for the option `name`.
+fn env_variable(name: &str) -> String {
+	format!("{}{}", ENV_PREFIX, name.to_uppercase().replace('-', "_"))
+}
+
+/// Parse the value of a flag set in the environment (`true`, `1`, `yes` or `on`, and the opposites, case-insensitive).
+fn parse_env_flag(value: &str) -> Option<bool> {
+	match value.to_lowercase().as_str() {
+		"true" | "1" | "yes" | "on" => Some(true),
+		"false" | "0" | "no" | "off" | "" => Some(false),
+		_ => None
 	}
+}
 
-	CombinedLogger::init(loggers).unwrap();
+/// Values of the [`ENV_DEPENDENT_OPTIONS`] set in the environment that the command line allows: the options not given
+/// on it, with the options they require (wherever they're set) and none of the ones they conflict with given on it,
+/// or allowed before them.
+fn env_dependent_options(matches: &ArgMatches) -> HashMap<&'static str, String> {
+	let mut values = HashMap::new();
+	for (name, requires, conflicts) in ENV_DEPENDENT_OPTIONS {
+		if matches.is_present(name) {
+			continue;
+		}
+		let value = match env::var(env_variable(name)) {
+			Ok(value) => value,
+			Err(_) => continue
+		};
+		let allowed = requires.iter().all(|other| matches.is_present(other) || values.contains_key(other))
+			&& !conflicts.iter().any(|other| matches.occurrences_of(other) > 0 || values.contains_key(other));
+		if allowed {
+			values.insert(*name, value);
+		}
+	}
+	values
+}
+
+/// The configuration file given on the command line, or else in `FWB_CONFIG` unless the command line gives the sources
+/// or the destinations instead.
+fn config_file<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+	if matches.occurrences_of("config") == 0 && WATCH_OPTIONS.iter().any(|name| matches.occurrences_of(name) > 0) {
+		return None;
+	}
+	matches.value_of("config")
+}
+
+/// Value of the option `name` given on the command line, or else in the environment.
+fn value_of<'a>(matches: &'a ArgMatches, env_options: &'a HashMap<&str, String>, name: &str) -> Option<&'a str> {
+	matches.value_of(name).or_else(|| env_options.get(name).map(String::as_str))
+}
+
+/// Sources given on the command line, or else in `FWB_SOURCE`.
+fn sources<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+	matches.values_of("source").into_iter().flatten().collect()
+}
+
+/// Destinations given on the command line: DIR, then the additional `--destination` ones.
+fn destinations(matches: &ArgMatches) -> Vec<PathBuf> {
+	// Without a configuration file "destination" is required, so unwrap() here is safe
+	let destination = matches.value_of("destination").unwrap();
+	iter::once(destination)
+		.chain(matches.values_of("more-destinations").into_iter().flatten())
+		.map(PathBuf::from)
+		.collect()
+}
 
-	// Since "source" argument is required, unwrap() here is safe
-	let src_path = matches.value_of("source").unwrap();
-	debug!("Input path: `{}`", src_path);
-
-	// Fail early if the path does not link to an existing file or
-	// the user doesn't have read access to it
-	match read(src_path) {
-		Ok(file) => file,
-		Err(error) => match error.kind() {
-			ErrorKind::NotFound => {
-				error!("File `{}` not found", src_path);
-				trace!("{:?}", error);
-				std::process::exit(exitcode::NOINPUT);
+/// Run the `restore` subcommand, returning the exit code.
+fn run_restore(matches: &ArgMatches) -> i32 {
+	// Both are required, so unwrap() here is safe
+	let source = paths::expand_path(Path::new(matches.value_of("source").unwrap()));
+	let destination = paths::expand_path(Path::new(matches.value_of("destination").unwrap()));
+	// Only encrypted backups need the passphrase, that may have to be typed
+	let encrypted = versions::list(&destination, &source).map(|versions| match matches.value_of("version") {
+		Some(label) => versions.iter().any(|version| version.label() == label && version.encrypted),
+		None => versions.last().is_some_and(|version| version.encrypted)
+	}).unwrap_or(false);
+	let passphrase = if encrypted {
+		match read_passphrase(false) {
+			Ok(passphrase) => Some(passphrase),
+			Err(reason) => {
+				error!("No passphrase to decrypt the backup with: {}", reason);
+				return exitcode::USAGE;
 			}
-			other_errors => {
-				error!("Error accessing file `{}`", src_path);
-				trace!("{:?}", other_errors);
-				std::process::exit(exitcode::IOERR);
+		}
+	} else {
+		None
+	};
+
+	match restore::restore(&source, &destination, matches.value_of("version"), matches.is_present("save-current"), passphrase.as_ref()) {
+		Ok(version) => {
+			println!("Restored `{}` to version {}", source.display(), version.label());
+			exitcode::OK
+		},
+		Err(error) => {
+			error!("{}", error);
+			exit_code(&error)
+		}
+	}
+}
+
+/// Run the `list` subcommand, returning the exit code.
+fn run_list(matches: &ArgMatches) -> i32 {
+	// Both are required, so unwrap() here is safe
+	let source = paths::expand_path(Path::new(matches.value_of("source").unwrap()));
+	let destination = paths::expand_path(Path::new(matches.value_of("destination").unwrap()));
+	let mut backups = match versions::list(&destination, &source) {
+		Ok(backups) => backups,
+		Err(error) => {
+			let error = BackupError::DestUnreadable(destination, error);
+			error!("{}", error);
+			return exit_code(&error);
+		}
+	};
+	// Newest first
+	backups.sort_by_key(|backup| Reverse(backup.modified));
+
+	// Checksums recorded in the manifest don't have to be computed again
+	let recorded_checksums: HashMap<String, String> = manifest::read(&destination)
+		.unwrap_or_default()
+		.into_iter()
+		.filter_map(|entry| Some((entry.backup_file, entry.sha256?)))
+		.collect();
+
+	let passphrase = if matches.is_present("checksum") && backups.iter().any(|backup| backup.encrypted) {
+		match read_passphrase(false) {
+			Ok(passphrase) => Some(passphrase),
+			Err(reason) => {
+				error!("No passphrase to decrypt the backups with: {}", reason);
+				return exitcode::USAGE;
 			}
 		}
+	} else {
+		None
 	};
 
-	info!("Input file validated");
+	let mut rows = Vec::new();
+	for backup in &backups {
+		let recorded_checksum = backup.path.file_name().and_then(|name| recorded_checksums.get(&*name.to_string_lossy()));
+		let checksum = if !matches.is_present("checksum") {
+			None
+		} else if let Some(checksum) = recorded_checksum {
+			Some(checksum.clone())
+		} else {
+			match backup.checksum(passphrase.as_ref()) {
+				Ok(checksum) => Some(checksum),
+				Err(error) => {
+					debug!("{:?}", error);
+					error!("Could not read `{:?}`. Reason: {}", backup.path, error);
+					return exitcode::IOERR;
+				}
+			}
+		};
+		rows.push((backup, DateTime::<Local>::from(backup.modified), checksum));
+	}
 
-	let destination_dir_path = matches.value_of("destination").unwrap();
-	debug!("Destination dir is: {}", destination_dir_path);
+	if matches.is_present("json") {
+		let entries: Vec<serde_json::Value> = rows.iter()
+			.map(|(backup, modified, checksum)| {
+				let mut entry = serde_json::json!({
+					"version": backup.label(),
+					"path": backup.path,
+					"modified": modified.to_rfc3339(),
+					"size": backup.size,
+					"compressed": backup.compressed,
+					"encrypted": backup.encrypted
+				});
+				if let Some(checksum) = checksum {
+					entry["sha256"] = checksum.as_str().into();
+				}
+				entry
+			})
+			.collect();
+		println!("{}", serde_json::Value::from(entries));
+	} else if rows.is_empty() {
+		println!("No versioned backup of `{}` in `{}`", source.display(), destination.display());
+	} else {
+		let checksum_header = if matches.is_present("checksum") { "  SHA-256" } else { "" };
+		println!("{:<21}  {:<19}  {:>12}  {:<6}{}", "VERSION", "MODIFIED", "SIZE", "", checksum_header);
+		for (backup, modified, checksum) in &rows {
+			let format = match (backup.compressed, backup.encrypted) {
+				(true, true) => "gz,enc",
+				(true, false) => "gz",
+				(false, true) => "enc",
+				(false, false) => ""
+			};
+			print!("{:<21}  {}  {:>12}  {:<6}", backup.label(), modified.format("%Y-%m-%d %H:%M:%S"), backup.size, format);
+			match checksum {
+				Some(checksum) => println!("  {}", checksum),
+				None => println!()
+			}
+		}
+	}
+	exitcode::OK
+}
+
+/// Run the `check` subcommand, returning the exit code: the one of the first problem found, if any.
+fn run_check(matches: &ArgMatches) -> i32 {
+	// "config" is required, so unwrap() here is safe
+	let watches_file = WatchesFile::Config(PathBuf::from(matches.value_of("config").unwrap()));
+	let watches = match watches_file.load() {
+		Ok(watches) => watches,
+		Err(error) => {
+			error!("{}", error);
+			return exit_code(&error);
+		}
+	};
+	let watches: Vec<Watch> = watches.into_iter()
+		.map(|watch| Watch { source: paths::expand_path(&watch.source), destination: paths::expand_path(&watch.destination), ..watch })
+		.collect();
+	let count = watches.len();
 
-	// Handle only the error part of the result (since the value is void)
-	if let Err(err) = create_dir_all(destination_dir_path) {
-		debug!("{:?}", err);
-		error!("Destination directory `{}` setup failed", destination_dir_path);
-		std::process::exit(exitcode::IOERR);
+	let errors = check(&BackupConfig::new(watches));
+	for error in &errors {
+		error!("{}", error);
 	}
+	match errors.first() {
+		None => {
+			println!("`{}`: {} watch(es) checked, no problem found", watches_file.path().display(), count);
+			exitcode::OK
+		},
+		Some(error) => {
+			println!("`{}`: {} watch(es) checked, {} problem(s) found", watches_file.path().display(), count, errors.len());
+			exit_code(error)
+		}
+	}
+}
 
-	info!("Destination dir `{}` setup completed", destination_dir_path);
+/// Outcome of a check of the `doctor` subcommand.
+enum Diagnosis {
+	Pass(String),
+	/// Not a problem yet, but likely to become one
+	Warn(String, String),
+	/// Backups (or logs) can't work, with the way to fix it
+	Fail(String, String)
+}
 
-	let mut _destination_file_path = PathBuf::from(destination_dir_path);
-	// Here "src_path" is a confirmed file so the unwrap is secure
-	_destination_file_path.push(Path::new(src_path).file_name().unwrap());
+/// Below this many inotify watches, watching large trees is likely to fail (the kernel default is 8192)
+#[cfg(target_os = "linux")]
+const MIN_INOTIFY_WATCHES: u64 = 8192;
 
-	let destination_file_path = _destination_file_path.as_path();
+/// Run the `doctor` subcommand, printing the outcome of every check, returning the exit code:
+/// non-zero when one of them failed.
+fn run_doctor(matches: &ArgMatches) -> i32 {
+	let log_dir = matches.value_of("log-dir").map(PathBuf::from);
+	let mut checks = vec![("Home directory", match home_dir() {
+		Some(home) => Diagnosis::Pass(home.display().to_string()),
+		// Only the default log directory lives there
+		None if log_dir.is_some() => Diagnosis::Warn("not found".to_string(), "set $HOME to use `~` in the paths".to_string()),
+		None => Diagnosis::Fail("not found".to_string(), "set $HOME, or pass --log-dir".to_string())
+	})];
 
-	// Make the first copy, just to start with a balanced state
-	debug!("Initial copy of `{}` into `{:?}`", src_path, destination_file_path);
-	match copy(src_path, destination_file_path) {
-		Ok(filesize) => debug!("Copied {} bytes", filesize),
-		Err(error) => {
-			debug!("{:?}", error);
-			error!("First copy failed:. Reason: {}", error);
-		}
-	};
-
-	let (tx, rx) = channel();
-	let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(1)).unwrap();
-	match watcher.watch(src_path, RecursiveMode::NonRecursive) {
-		Ok(()) => (),
-		Err(error) => error!("Error adding path to watcher. {:?}", error)
-	};
-
-	loop {
-		match rx.recv() {
-			Ok(event) => {
-				match event {
-					notify::DebouncedEvent::Write(path) => {
-						match copy(path, destination_file_path) {
-							Ok(filesize) => debug!("Copied {} bytes", filesize),
-							Err(error) => {
-								debug!("{:?}", error);
-								error!("First copy failed:. Reason: {}", error);
-							}
-						};
-					},
-					_ => continue
-				}
-			},
-			Err(e) => error!("Watch error. {:?}", e)
+	checks.push(("Log directory", match log_directory(log_dir.as_deref()) {
+		None => Diagnosis::Fail("unknown without a home directory".to_string(), "pass --log-dir".to_string()),
+		Some(log_dir) => {
+			let probe = log_dir.join(".file-watcher-backup-doctor");
+			match create_dir_all(&log_dir).and_then(|_| std::fs::write(&probe, b"")).and_then(|_| std::fs::remove_file(&probe)) {
+				Ok(()) => Diagnosis::Pass(format!("`{}` is writable", log_dir.display())),
+				Err(error) => Diagnosis::Fail(
+					format!("`{}` is not writable: {}", log_dir.display(), error),
+					"fix its permissions, pass another --log-dir, or --no-file-log".to_string()
+				)
+			}
+		}
+	}));
+
+	#[cfg(target_os = "linux")]
+	checks.push(("inotify watches", match std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches") {
+		Ok(limit) => match limit.trim().parse::<u64>() {
+			Ok(limit) if limit < MIN_INOTIFY_WATCHES => Diagnosis::Warn(
+				format!("at most {} per user", limit),
+				"raise it with `sysctl fs.inotify.max_user_watches=524288` to watch large directories".to_string()
+			),
+			Ok(limit) => Diagnosis::Pass(format!("at most {} per user", limit)),
+			Err(_) => Diagnosis::Warn(format!("unexpected limit `{}`", limit.trim()), "check /proc/sys/fs/inotify/max_user_watches".to_string())
+		},
+		Err(error) => Diagnosis::Warn(format!("unknown limit: {}", error), "check that inotify is enabled in the kernel".to_string())
+	}));
+
+	checks.push(("File system watcher", match probe_watcher(None) {
+		Ok(()) => Diagnosis::Pass("changes can be watched".to_string()),
+		Err(error) => Diagnosis::Fail(
+			error.to_string(),
+			"raise the inotify limits (fs.inotify.max_user_instances), or watch with --poll <SECONDS>".to_string()
+		)
+	}));
+
+	let mut failed = false;
+	for (name, diagnosis) in &checks {
+		match diagnosis {
+			Diagnosis::Pass(detail) => println!("[ OK ] {}: {}", name, detail),
+			Diagnosis::Warn(detail, hint) => println!("[WARN] {}: {}\n       Hint: {}", name, detail, hint),
+			Diagnosis::Fail(detail, hint) => {
+				failed = true;
+				println!("[FAIL] {}: {}\n       Hint: {}", name, detail, hint);
+			}
 		}
 	}
+	if failed { exitcode::UNAVAILABLE } else { exitcode::OK }
 }
 
-fn create_file_logger() -> Option<Box<WriteLogger<File>>> {
-	// The default log directory for the moment is the $HOME/file-watcher-backup directory of the user
-	let mut _log_path = match home_dir() {
-		Some(path) => path,
-		None => return None
+/// Run the `stats` subcommand, returning the exit code.
+fn run_stats(matches: &ArgMatches) -> i32 {
+	// "destination" is required, so unwrap() here is safe
+	let destination = paths::expand_path(Path::new(matches.value_of("destination").unwrap()));
+	let stats = match stats::compute(&destination) {
+		Ok(stats) => stats,
+		Err(error) => {
+			let error = BackupError::DestUnreadable(destination, error);
+			error!("{}", error);
+			return exit_code(&error);
+		}
 	};
 
-	_log_path.push("file-watcher-backup");
-	match create_dir_all(&_log_path) {
-		Ok(()) => (),
-		Err(_) => return None
-	};
+	if matches.is_present("json") {
+		let (most_backups_source, most_backups) = match &stats.most_backups {
+			Some((source, count)) => (Some(source.clone()), Some(*count)),
+			None => (None, None)
+		};
+		println!("{}", serde_json::json!({
+			"sources": stats.sources,
+			"backups": stats.backups,
+			"bytes": stats.bytes,
+			"oldest": stats.oldest.map(|oldest| oldest.to_rfc3339()),
+			"newest": stats.newest.map(|newest| newest.to_rfc3339()),
+			"most_backups_source": most_backups_source,
+			"most_backups": most_backups,
+			"from_manifest": stats.from_manifest
+		}));
+	} else if stats.backups == 0 {
+		println!("No backup in `{}`", destination.display());
+	} else {
+		let format_time = |time: Option<DateTime<Local>>| time.map_or_else(|| "-".to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+		println!("Sources:       {}", stats.sources);
+		println!("Backups:       {}", stats.backups);
+		println!("Size:          {} bytes", stats.bytes);
+		println!("Oldest backup: {}", format_time(stats.oldest));
+		println!("Newest backup: {}", format_time(stats.newest));
+		if let Some((source, count)) = &stats.most_backups {
+			println!("Most backups:  {} ({})", source.display(), count);
+		}
+		if stats.from_manifest {
+			println!("(as recorded in the manifest)");
+		}
+	}
+	exitcode::OK
+}
+
+/// Read the passphrase of the encrypted backups from [`PASSPHRASE_VARIABLE`] or, when it's not set,
+/// let the user type it (twice with `confirm`, since a mistyped passphrase would make the backups unreadable).
+fn read_passphrase(confirm: bool) -> Result<Passphrase, String> {
+	if let Ok(passphrase) = env::var(PASSPHRASE_VARIABLE) {
+		if passphrase.is_empty() {
+			return Err(format!("${} is empty", PASSPHRASE_VARIABLE));
+		}
+		return Ok(Passphrase::new(passphrase));
+	}
+	if !atty::is(Stream::Stdin) {
+		return Err(format!("set ${} or run in a terminal to type it", PASSPHRASE_VARIABLE));
+	}
+
+	let passphrase = rpassword::prompt_password("Passphrase: ").map_err(|error| error.to_string())?;
+	if passphrase.is_empty() {
+		return Err("the passphrase is empty".to_string());
+	}
+	if confirm && rpassword::prompt_password("Passphrase (again): ").map_err(|error| error.to_string())? != passphrase {
+		return Err("the passphrases don't match".to_string());
+	}
+	Ok(Passphrase::new(passphrase))
+}
+
+/// Exit code reporting `error`: errors are turned into exit codes only here, so that scripts can tell them apart.
+fn exit_code(error: &BackupError) -> i32 {
+	match error {
+		BackupError::NoSource => exitcode::USAGE,
+		BackupError::SourceNotFound(_) => exitcode::NOINPUT,
+		BackupError::SourceUnreadable(..) => exitcode::IOERR,
+		BackupError::DestSetupFailed(..) => exitcode::IOERR,
+		BackupError::InvalidDestination(..) => exitcode::USAGE,
+		BackupError::DestinationIsFile(_) => exitcode::USAGE,
+		BackupError::DestinationIsSource(_) => exitcode::USAGE,
+		BackupError::BackupIsSource(_) => exitcode::USAGE,
+		BackupError::AlreadyRunning(..) => exitcode::TEMPFAIL,
+		BackupError::DestUnreadable(..) => exitcode::IOERR,
+		BackupError::NoBackup(..) => exitcode::NOINPUT,
+		BackupError::RestoreFailed(..) => exitcode::IOERR,
+		BackupError::ConfigUnreadable(..) | BackupError::ConfigInvalid(..) => exitcode::CONFIG,
+		BackupError::InvalidTemplate(..) => exitcode::USAGE,
+		BackupError::MetricsUnavailable(..) => exitcode::UNAVAILABLE,
+		BackupError::ControlSocketFailed(..) => exitcode::UNAVAILABLE,
+		BackupError::PidFileInUse(..) => exitcode::TEMPFAIL,
+		BackupError::PidFileFailed(..) => exitcode::CANTCREAT,
+		BackupError::WatchFailed(..) => exitcode::OSERR,
+		BackupError::BackupFailed(_) => exitcode::IOERR
+	}
+}
+
+/// Directory of the log files: `log_dir` if given, otherwise the $HOME/file-watcher-backup directory of the user.
+fn log_directory(log_dir: Option<&Path>) -> Option<PathBuf> {
+	match log_dir {
+		Some(log_dir) => Some(log_dir.to_path_buf()),
+		None => Some(home_dir()?.join("file-watcher-backup"))
+	}
+}
+
+/// Create the logger writing into the log file of the day, in `log_dir` (see [`log_directory`]).
+fn create_file_logger(level: LevelFilter, log_dir: Option<&Path>, format: LogFormat, max_size: u64, keep_files: usize) -> Result<Box<dyn SharedLogger>, LogSetupError> {
+	let mut log_path = log_directory(log_dir).ok_or(LogSetupError::NoHomeDirectory)?;
+
+	if let Err(error) = create_dir_all(&log_path) {
+		return Err(LogSetupError::DirectoryFailed(log_path, error));
+	}
 
 	let log_file_name = format!("{}.log", Local::now().format("%Y-%m-%d"));
-	_log_path.push(log_file_name);
+	log_path.push(log_file_name);
 
-	let log_file_path = _log_path.as_path();
+	match RotatingFile::open(&log_path, max_size, keep_files) {
+		Ok(file) => Ok(match format {
+			LogFormat::Text => WriteLogger::new(level, Config::default(), file),
+			LogFormat::Json => JsonLogger::new(level, file)
+		}),
+		Err(error) => Err(LogSetupError::FileFailed(log_path, error))
+	}
+}
 
-	match OpenOptions::new().create(true).append(true).open(log_file_path) {
-			Ok(file) => Some(WriteLogger::new(LevelFilter::Trace, Config::default(), file)),
-			Err(_) => return None
+/// Level of the terminal logs for `-v` given `occurrences` times.
+fn verbosity_level(occurrences: u64) -> LevelFilter {
+	match occurrences {
+		0 => LevelFilter::Info,
+		1 => LevelFilter::Debug,
+		_ => LevelFilter::Trace
 	}
 }
+
+/// Parse a log level name (case-insensitive).
+fn parse_log_level(level: &str) -> Option<LevelFilter> {
+	match level.to_lowercase().as_str() {
+		"error" => Some(LevelFilter::Error),
+		"warn" => Some(LevelFilter::Warn),
+		"info" => Some(LevelFilter::Info),
+		"debug" => Some(LevelFilter::Debug),
+		"trace" => Some(LevelFilter::Trace),
+		_ => None
+	}
+}
+
+/// Parse a size in bytes, optionally followed by a binary multiplier (`K`, `M`, `G` or `T`,
+/// case-insensitive, optionally followed by `B`). Sizes that don't fit in 64 bits are rejected.
+fn parse_size(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let digits_end = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+	let (number, suffix) = size.split_at(digits_end);
+	let number: u64 = number.parse().ok()?;
+	let multiplier: u64 = match suffix.to_uppercase().as_str() {
+		"" | "B" => 1,
+		"K" | "KB" => 1 << 10,
+		"M" | "MB" => 1 << 20,
+		"G" | "GB" => 1 << 30,
+		"T" | "TB" => 1 << 40,
+		_ => return None
+	};
+	number.checked_mul(multiplier)
+}
@@ -0,0 +1,65 @@
+//! The manifest describing the backups of a destination directory.
+
+use std::fs::{File, OpenOptions, read_to_string};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::backup;
+
+/// Name of the manifest file, in the directory holding the backups it describes
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Name of the file locked while updating the manifest next to it (the manifest itself is replaced by every update)
+const MANIFEST_LOCK_FILE_NAME: &str = ".manifest.json.lock";
+
+/// A backup, as recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+	/// Path of the file that was backed up
+	pub source: PathBuf,
+	/// Path of the backup file, relative to the directory of the manifest
+	pub backup_file: String,
+	/// When the backup was made (RFC 3339)
+	pub timestamp: String,
+	/// Size of the source
+	pub bytes: u64,
+	/// Whether the backup is gzipped (small files aren't with a minimum size to compress)
+	#[serde(default)]
+	pub compressed: bool,
+	/// SHA-256 hash (in hexadecimal) of the content of the source, if it could be computed
+	pub sha256: Option<String>
+}
+
+/// Read the manifest of `destination_dir` (empty if there is none yet).
+pub fn read(destination_dir: &Path) -> io::Result<Vec<ManifestEntry>> {
+	match read_to_string(destination_dir.join(MANIFEST_FILE_NAME)) {
+		Ok(content) => serde_json::from_str(&content).map_err(|error| io::Error::new(ErrorKind::InvalidData, error)),
+		Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+		Err(error) => Err(error)
+	}
+}
+
+/// Add `entry` to the manifest of `destination_dir`, replacing the previous entry of the same
+/// backup file and dropping the entries of backups that don't exist anymore.
+///
+/// The manifest is rewritten whole into a temporary file first, so that it's never left half-written,
+/// and other updates (by the other destinations sharing the directory, or other instances) wait for it.
+pub(crate) fn record(destination_dir: &Path, entry: ManifestEntry) -> io::Result<()> {
+	// Left in place: removing it would let another update lock a new one while this one is still locked
+	let lock = OpenOptions::new().create(true).truncate(false).write(true).open(destination_dir.join(MANIFEST_LOCK_FILE_NAME))?;
+	lock.lock_exclusive()?;
+	// Unlocked when closed, in any case
+	let mut entries = read(destination_dir)?;
+	entries.retain(|existing| existing.backup_file != entry.backup_file && destination_dir.join(&existing.backup_file).exists());
+	entries.push(entry);
+
+	backup::write_atomically(&destination_dir.join(MANIFEST_FILE_NAME), |temp_path| {
+		let mut temp_file = File::create(temp_path)?;
+		let content = serde_json::to_string_pretty(&entries).map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+		temp_file.write_all(content.as_bytes())?;
+		temp_file.sync_all()
+	})
+}
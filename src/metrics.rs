@@ -0,0 +1,130 @@
+//! Prometheus metrics about the backups, served over HTTP.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use log::{info, debug, warn};
+use tiny_http::{Header, Response, Server};
+
+use crate::BackupError;
+
+/// Backups made
+static BACKUPS: AtomicU64 = AtomicU64::new(0);
+/// Bytes copied by the backups
+static BYTES: AtomicU64 = AtomicU64::new(0);
+/// Backups that failed
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Backups skipped because the source didn't change, or changed too recently
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+/// Bytes not copied because the source didn't change
+static UNCHANGED_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Bytes not stored because the backups were linked to identical ones
+static DEDUPLICATED_BYTES: AtomicU64 = AtomicU64::new(0);
+/// When each source was last backed up
+static LAST_BACKUPS: Mutex<Option<HashMap<PathBuf, Instant>>> = Mutex::new(None);
+
+/// Count a backup of `bytes` bytes.
+pub(crate) fn backed_up(bytes: u64) {
+	BACKUPS.fetch_add(1, Ordering::Relaxed);
+	BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count a failed backup.
+pub(crate) fn failed() {
+	ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a skipped backup.
+pub(crate) fn skipped() {
+	SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count the `bytes` of a source that weren't copied, the source being unchanged.
+pub(crate) fn unchanged(bytes: u64) {
+	UNCHANGED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count the `bytes` of a backup that weren't stored, the backup being linked to an identical one.
+pub(crate) fn deduplicated(bytes: u64) {
+	DEDUPLICATED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Record that the watched `source` (a file or a whole directory) is backed up as of now.
+pub(crate) fn source_backed_up(source: &Path) {
+	// A poisoned lock only means that a scrape panicked: the times are still fine
+	let mut last_backups = LAST_BACKUPS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	last_backups.get_or_insert_with(HashMap::new).insert(source.to_path_buf(), Instant::now());
+}
+
+/// Forget the `source` that isn't watched anymore.
+pub(crate) fn source_removed(source: &Path) {
+	let mut last_backups = LAST_BACKUPS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	if let Some(last_backups) = last_backups.as_mut() {
+		last_backups.remove(source);
+	}
+}
+
+/// Serve the metrics on `address` (as `HOST:PORT`) from a background thread.
+pub(crate) fn serve(address: &str) -> Result<(), BackupError> {
+	let server = Server::http(address).map_err(|error| BackupError::MetricsUnavailable(address.to_string(), error.to_string()))?;
+	info!("Serving the metrics on http://{}/metrics", address);
+	thread::spawn(move || {
+		for request in server.incoming_requests() {
+			debug!("Metrics request: {} {}", request.method(), request.url());
+			let response = if request.url() == "/metrics" {
+				// The header is valid, so unwrap() here is safe
+				let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+				Response::from_string(render()).with_header(content_type)
+			} else {
+				Response::from_string("Not found, the metrics are at /metrics").with_status_code(404)
+			};
+			if let Err(error) = request.respond(response) {
+				debug!("{:?}", error);
+				warn!("Could not send the metrics. Reason: {}", error);
+			}
+		}
+	});
+	Ok(())
+}
+
+/// The metrics, in the Prometheus text format.
+fn render() -> String {
+	let mut text = String::new();
+	let counters = [
+		("backups_total", "Backups made", &BACKUPS),
+		("copied_bytes_total", "Bytes copied by the backups", &BYTES),
+		("errors_total", "Backups that failed", &ERRORS),
+		("skipped_total", "Backups skipped because the source was unchanged, or changed too recently", &SKIPPED),
+		("unchanged_bytes_total", "Bytes not copied because the source was unchanged", &UNCHANGED_BYTES),
+		("deduplicated_bytes_total", "Bytes not stored because the backup was linked to an identical one", &DEDUPLICATED_BYTES)
+	];
+	// Writing into a String never fails, so the results are ignored
+	for (name, help, counter) in counters.iter() {
+		let _ = writeln!(text, "# HELP file_watcher_backup_{} {}", name, help);
+		let _ = writeln!(text, "# TYPE file_watcher_backup_{} counter", name);
+		let _ = writeln!(text, "file_watcher_backup_{} {}", name, counter.load(Ordering::Relaxed));
+	}
+
+	let _ = writeln!(text, "# HELP file_watcher_backup_seconds_since_last_backup Time since each source was last backed up");
+	let _ = writeln!(text, "# TYPE file_watcher_backup_seconds_since_last_backup gauge");
+	let last_backups = LAST_BACKUPS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	for (source, time) in last_backups.iter().flatten() {
+		let _ = writeln!(
+			text,
+			"file_watcher_backup_seconds_since_last_backup{{source=\"{}\"}} {:.3}",
+			escape_label(&source.to_string_lossy()),
+			time.elapsed().as_secs_f64()
+		);
+	}
+	text
+}
+
+/// Escape `value` to be used as the value of a label.
+fn escape_label(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,262 @@
+//! Mirroring of a watched directory tree into its destination.
+
+use std::fs::{metadata, read_dir, remove_dir_all, remove_file, symlink_metadata};
+use std::path::{Path, PathBuf};
+
+use log::{info, debug, error, trace};
+use walkdir::WalkDir;
+
+use crate::{BackupConfig, Source};
+use crate::backup::{self, History, Outcome};
+
+/// Check whether `path` lives in the tree of the directory `source` (at its top level, without
+/// [`BackupConfig::recursive`]) and has to be backed up.
+///
+/// Paths inside a destination are skipped, otherwise backing up into a destination that lives
+/// inside the tree would trigger new events for every backup, forever.
+pub(crate) fn contains(source: &Source, path: &Path, config: &BackupConfig) -> bool {
+	let in_tree = if config.recursive { path.starts_with(&source.path) } else { path.parent() == Some(&source.path) };
+	in_tree && path != source.path && !is_destination(source, path)
+}
+
+/// Check whether `path` lives inside one of the destinations found in the tree of `source`.
+fn is_destination(source: &Source, path: &Path) -> bool {
+	source.nested_destinations.iter().any(|destination| path.starts_with(destination))
+}
+
+/// Check whether `path` (part of the tree of `root`) matches one of the exclude patterns.
+pub(crate) fn is_excluded(root: &Path, path: &Path, config: &BackupConfig) -> bool {
+	let relative_path = path.strip_prefix(root).unwrap_or(path);
+	let excluded = config.exclude.is_match(relative_path);
+	if excluded {
+		trace!("Skipping excluded `{:?}`", path);
+	}
+	excluded
+}
+
+/// Check whether the file `path` has one of the extensions of [`BackupConfig::include_extensions`], if any.
+pub(crate) fn is_included(path: &Path, config: &BackupConfig) -> bool {
+	if config.include_extensions.is_empty() {
+		return true;
+	}
+	let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+	let included = extension.is_some_and(|extension| config.include_extensions.contains(&extension));
+	if !included {
+		trace!("Skipping `{:?}`, its extension isn't included", path);
+	}
+	included
+}
+
+/// How many files of a tree were found, and what became of them.
+#[derive(Debug, Default)]
+pub(crate) struct Tally {
+	pub(crate) files: usize,
+	pub(crate) copied: usize,
+	pub(crate) failed: usize
+}
+
+/// Back up every file of the tree starting at `path` (part of the tree of `source`) into its destination.
+///
+/// Without [`BackupConfig::recursive`] only the files directly inside `path` are backed up.
+pub(crate) fn backup_tree(source: &Source, path: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	backup_tree_counting(source, path, config, history).0
+}
+
+/// Back up the tree starting at `path` as [`backup_tree`] does, counting the files.
+pub(crate) fn backup_tree_counting(source: &Source, path: &Path, config: &BackupConfig, history: &mut History) -> (Outcome, Tally) {
+	let mut outcome = Outcome::Unchanged;
+	let mut tally = Tally::default();
+	let entries = WalkDir::new(path)
+		.max_depth(if config.recursive { usize::MAX } else { 1 })
+		.follow_links(config.follow_symlinks)
+		.into_iter()
+		.filter_entry(|entry| !is_destination(source, entry.path()) && !is_excluded(&source.path, entry.path(), config));
+	for entry in entries {
+		match entry {
+			Ok(entry) => {
+				if entry.file_type().is_file() {
+					let file_outcome = backup_file(source, entry.path(), config, history);
+					tally.files += 1;
+					match file_outcome {
+						Outcome::Copied => tally.copied += 1,
+						Outcome::Failed => tally.failed += 1,
+						Outcome::Unchanged => ()
+					}
+					outcome = outcome.max(file_outcome);
+				}
+			},
+			Err(error) => {
+				debug!("{:?}", error);
+				error!("Could not scan `{:?}`. Reason: {}", path, error);
+				outcome = Outcome::Failed;
+			}
+		}
+	}
+	(outcome, tally)
+}
+
+/// Back up `path` (part of the tree of `source`) into its destination, be it a file or a whole directory.
+pub(crate) fn backup_entry(source: &Source, path: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	// As when walking the tree, links are only backed up (as their targets) when following them
+	let entry_metadata = if config.follow_symlinks { metadata(path) } else { symlink_metadata(path) };
+	match entry_metadata {
+		// A directory moved into the tree brings its content along without further events
+		Ok(metadata) if metadata.is_dir() && config.recursive => backup_tree(source, path, config, history),
+		Ok(metadata) if metadata.is_file() => backup_file(source, path, config, history),
+		Ok(_) => {
+			trace!("Skipping `{:?}`, not a file", path);
+			Outcome::Unchanged
+		},
+		// Already gone again: a later event will take care of it
+		Err(error) => {
+			trace!("Skipping `{:?}`: {:?}", path, error);
+			Outcome::Unchanged
+		}
+	}
+}
+
+/// Remove the backup of `path` (part of the tree of `source`) from its destination.
+pub(crate) fn remove_entry(source: &Source, path: &Path, config: &BackupConfig) {
+	// Remote backups are never deleted
+	if source.sink.is_some() {
+		return;
+	}
+	if let Some(separator) = &config.flatten {
+		remove_flattened(source, path, separator, config);
+		return;
+	}
+	// Gone, a directory is only told by its mirror
+	let mirror = mirrored_path(source, path, config);
+	if symlink_metadata(&mirror).is_ok_and(|metadata| metadata.is_dir()) {
+		remove_backup(&mirror, path, config);
+		return;
+	}
+	// "path" is inside the source directory, so it has a parent
+	let destination_dir = mirrored_path(source, path.parent().unwrap(), config);
+	for backup_path in backup_paths(&destination_dir, path, config) {
+		remove_backup(&backup_path, path, config);
+	}
+}
+
+/// Paths the backup of the file `named` can have in `destination_dir`, named as [`backup::backup_file_path`] does:
+/// with [`BackupConfig::compress`], both compressed and not, as the files smaller than
+/// [`BackupConfig::compress_min_size`] are stored as they are.
+fn backup_paths(destination_dir: &Path, named: &Path, config: &BackupConfig) -> Vec<PathBuf> {
+	let mut paths = vec![backup::backup_file_path(destination_dir, named, config)];
+	if config.compress {
+		paths.push(backup::backup_file_path(destination_dir, named, &BackupConfig { compress: false, ..config.clone() }));
+	}
+	paths
+}
+
+/// Remove the flattened backups of `path` (part of the tree of `source`) from its destination: the
+/// backup of the file (see [`backup_paths`]), or the backups of the files the directory held, named with its name and the separator.
+fn remove_flattened(source: &Source, path: &Path, separator: &str, config: &BackupConfig) {
+	let destination_dir = backup::dated_directory(&source.destination, config);
+	// Callers only pass paths inside the source directory, so unwrap() here is safe
+	let name = flattened_name(path.strip_prefix(&source.path).unwrap(), separator);
+	let backup_paths = backup_paths(&destination_dir, &path.with_file_name(&name), config);
+	let prefix = format!("{}{}", name, separator);
+	let entries = match read_dir(&destination_dir) {
+		Ok(entries) => entries,
+		// Nothing was backed up
+		Err(_) => return
+	};
+	for entry in entries.filter_map(|entry| entry.ok()) {
+		let entry_name = entry.file_name();
+		let entry_name = entry_name.to_string_lossy();
+		if backup_paths.contains(&entry.path()) || entry_name.starts_with(&prefix) {
+			remove_backup(&entry.path(), path, config);
+		}
+	}
+}
+
+/// Remove `mirrored_path`, the backup of the deleted `path`.
+fn remove_backup(mirrored_path: &Path, path: &Path, config: &BackupConfig) {
+	if config.dry_run {
+		if symlink_metadata(mirrored_path).is_ok() {
+			info!("DRY RUN: would remove backup `{:?}` of deleted `{:?}`", mirrored_path, path);
+		}
+		return;
+	}
+	let result = match symlink_metadata(mirrored_path) {
+		Ok(metadata) if metadata.is_dir() => remove_dir_all(mirrored_path),
+		Ok(_) => remove_file(mirrored_path),
+		// Nothing was backed up
+		Err(_) => return
+	};
+
+	match result {
+		Ok(()) => info!("Removed backup `{:?}` of deleted `{:?}`", mirrored_path, path),
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not remove backup `{:?}`. Reason: {}", mirrored_path, error);
+		}
+	}
+}
+
+/// Back up the file `path` into the directory mirroring its parent in the destination of `source`.
+fn backup_file(source: &Source, path: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	// Excludes are checked while walking the tree, since they can match whole directories
+	if !is_included(path, config) {
+		return Outcome::Unchanged;
+	}
+	let outcome = store_file(source, path, config, history);
+	history.record(&source.path, &source.destination, outcome);
+	outcome
+}
+
+/// Back up the included file `path` (part of the tree of `source`) into its destination.
+fn store_file(source: &Source, path: &Path, config: &BackupConfig, history: &mut History) -> Outcome {
+	if let Some(separator) = &config.flatten {
+		return store_flattened(source, path, separator, config, history);
+	}
+	if let Some(sink) = &source.sink {
+		// "path" is a file inside the source directory, so it has a parent
+		let relative_dir = path.parent().unwrap().strip_prefix(&source.path).unwrap();
+		let directory: Vec<_> = relative_dir.iter().map(|component| component.to_string_lossy()).collect();
+		return backup::upload(path, sink.as_ref(), &directory.join("/"), config, history);
+	}
+
+	// "path" is a file inside the source directory, so it has a parent
+	let destination_dir = mirrored_path(source, path.parent().unwrap(), config);
+	if !backup::create_destination_dir(&destination_dir, config) {
+		return Outcome::Failed;
+	}
+	backup::backup(path, &source.destination, &destination_dir, config, history)
+}
+
+/// Back up the included file `path` (part of the tree of `source`) at the top level of its
+/// destination, under its relative path joined by `separator`.
+fn store_flattened(source: &Source, path: &Path, separator: &str, config: &BackupConfig, history: &mut History) -> Outcome {
+	// Callers only pass paths inside the source directory, so unwrap() here is safe
+	let named = path.with_file_name(flattened_name(path.strip_prefix(&source.path).unwrap(), separator));
+	if let Some(sink) = &source.sink {
+		return backup::upload_named(path, &named, sink.as_ref(), "", config, history);
+	}
+
+	let destination_dir = backup::dated_directory(&source.destination, config);
+	if !backup::create_destination_dir(&destination_dir, config) {
+		return Outcome::Failed;
+	}
+	backup::backup_named(path, &named, &source.destination, &destination_dir, config, history)
+}
+
+/// Name of the flattened backup of `relative_path`: its components joined by `separator`.
+///
+/// The separator (and `%`) found in the components is percent-encoded, so that `a_b/c` and `a/b_c`
+/// become `a%5Fb_c` and `a_b%5Fc` instead of sharing a backup.
+fn flattened_name(relative_path: &Path, separator: &str) -> String {
+	let encoded_separator: String = separator.bytes().map(|byte| format!("%{:02X}", byte)).collect();
+	let components: Vec<_> = relative_path.iter()
+		.map(|component| component.to_string_lossy().replace('%', "%25").replace(separator, &encoded_separator))
+		.collect();
+	components.join(separator)
+}
+
+/// Path of the mirror of `path` (part of the tree of `source`) inside its destination, in the
+/// directory of the day with [`BackupConfig::destination_by_date`].
+fn mirrored_path(source: &Source, path: &Path, config: &BackupConfig) -> PathBuf {
+	// Callers only pass paths inside the source directory, so unwrap() here is safe
+	backup::dated_directory(&source.destination, config).join(path.strip_prefix(&source.path).unwrap())
+}
@@ -0,0 +1,68 @@
+//! Desktop notifications, for users running the tool in the background without a terminal.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use notify_rust::Notification;
+
+use crate::limiter::Summary;
+
+/// Minimum time between two notifications of failed backups
+const FAILURE_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether the first successful backup has been notified already
+static BACKED_UP: AtomicBool = AtomicBool::new(false);
+
+/// When the last failure was notified
+static LAST_FAILURE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Notify the first successful backup (only that one, to show that the tool is working).
+pub(crate) fn backed_up(source: &Path) {
+	if !BACKED_UP.swap(true, Ordering::SeqCst) {
+		show(&format!("Backed up {}", file_name(source)), "Watching for further changes");
+	}
+}
+
+/// Notify a failed backup, unless another failure was notified less than a minute ago.
+pub(crate) fn failed(source: &Path, message: &str) {
+	// A poisoned lock only means that another notification panicked: the timestamp is still fine
+	let mut last_failure = LAST_FAILURE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	let now = Instant::now();
+	if last_failure.is_some_and(|last_failure| now.duration_since(last_failure) < FAILURE_NOTIFICATION_INTERVAL) {
+		debug!("Not notifying the failed backup of `{:?}`, too soon after the previous one", source);
+		return;
+	}
+	*last_failure = Some(now);
+	show(&format!("Backup of {} failed", file_name(source)), message);
+}
+
+/// Notify the failed backups of `summary`, made during the last `interval` (the successful ones aren't notified).
+pub(crate) fn summary(summary: &Summary, interval: Duration) {
+	if summary.failures > 0 {
+		show(
+			&format!("{} backups failed", summary.failures),
+			&format!("In the last {} seconds, while {} succeeded", interval.as_secs(), summary.backups)
+		);
+	}
+}
+
+/// Name of `source` as shown in the notifications.
+fn file_name(source: &Path) -> String {
+	source.file_name().unwrap_or(source.as_os_str()).to_string_lossy().into_owned()
+}
+
+/// Show a notification, reporting (but otherwise ignoring) failures: they are never worth stopping the backups.
+fn show(summary: &str, body: &str) {
+	let result = Notification::new()
+		.appname("file-watcher-backup")
+		.summary(summary)
+		.body(body)
+		.show();
+	if let Err(error) = result {
+		debug!("{:?}", error);
+		warn!("Could not show a desktop notification. Reason: {}", error);
+	}
+}
@@ -0,0 +1,73 @@
+//! Expansion of the paths given by the user, as a shell would do.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use dirs::home_dir;
+
+/// Expand a leading `~` into the home directory of the user and the `$VAR`, `${VAR}` and
+/// `%VAR%` references into the value of the environment variables.
+///
+/// References to variables that aren't defined are left untouched, as are paths that
+/// aren't valid Unicode.
+pub fn expand_path(path: &Path) -> PathBuf {
+	let path = match path.to_str() {
+		Some(path) => path,
+		None => return path.to_path_buf()
+	};
+
+	let expanded = expand_variables(path);
+	match (expanded.strip_prefix('~'), home_dir()) {
+		(Some(rest), Some(home)) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+			// Joining an absolute path would discard the home directory
+			home.join(rest.trim_start_matches(['/', '\\']))
+		},
+		_ => PathBuf::from(expanded)
+	}
+}
+
+/// Absolute version of `path`, resolved against the current directory.
+pub fn absolute_path(path: &Path) -> PathBuf {
+	match env::current_dir() {
+		Ok(current_dir) => current_dir.join(path),
+		Err(_) => path.to_path_buf()
+	}
+}
+
+/// Replace the `$VAR`, `${VAR}` and `%VAR%` references in `text` with the values of the variables.
+fn expand_variables(text: &str) -> String {
+	let mut expanded = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(index) = rest.find(['$', '%']) {
+		expanded.push_str(&rest[..index]);
+		let reference = &rest[index..];
+
+		// Name of the variable and length of the whole reference to it
+		let (name, length) = if let Some(braced) = reference.strip_prefix("${") {
+			match braced.find('}') {
+				Some(end) => (&braced[..end], end + 3),
+				None => ("", 1)
+			}
+		} else if let Some(percent) = reference.strip_prefix('%') {
+			match percent.find('%') {
+				Some(end) if is_variable_name(&percent[..end]) => (&percent[..end], end + 2),
+				_ => ("", 1)
+			}
+		} else {
+			let end = reference[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(reference.len() - 1);
+			(&reference[1..=end], end + 1)
+		};
+
+		match env::var(name) {
+			Ok(value) if is_variable_name(name) => expanded.push_str(&value),
+			_ => expanded.push_str(&reference[..length])
+		}
+		rest = &reference[length..];
+	}
+	expanded.push_str(rest);
+	expanded
+}
+
+fn is_variable_name(name: &str) -> bool {
+	!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,126 @@
+//! Sources given as glob patterns (like `~/configs/*.toml`), standing for every file they match.
+
+use std::fs::{canonicalize, symlink_metadata};
+use std::path::{Component, Path, PathBuf};
+
+use globset::{GlobBuilder, GlobMatcher};
+use log::{debug, error, info, warn};
+use walkdir::WalkDir;
+
+use crate::Watch;
+use crate::paths;
+
+/// Characters telling a glob pattern from a literal path
+const GLOB_METACHARACTERS: &[char] = &['*', '?', '[', '{'];
+
+/// A watch whose source is a glob pattern.
+pub(crate) struct SourcePattern {
+	/// The watch, as configured
+	pub(crate) watch: Watch,
+	/// Matcher of the absolute (and canonical, up to the first pattern component) paths
+	matcher: GlobMatcher
+}
+
+impl SourcePattern {
+	/// The pattern of `watch`, unless its source is a literal path (or an invalid pattern, reported here).
+	///
+	/// An existing file is always a literal path, even with glob metacharacters in its name (`photo[1].jpg`).
+	fn of(watch: &Watch) -> Option<SourcePattern> {
+		let pattern = watch.source.to_str()?;
+		if !pattern.contains(GLOB_METACHARACTERS) || symlink_metadata(&watch.source).is_ok() {
+			return None;
+		}
+		// The events of the watcher come with canonical paths
+		let (base, rest) = split_pattern(Path::new(pattern));
+		let base = canonicalize(&base).unwrap_or_else(|_| paths::absolute_path(&base));
+		let absolute_pattern = base.join(rest);
+		match GlobBuilder::new(&absolute_pattern.to_string_lossy()).literal_separator(true).build() {
+			Ok(glob) => Some(SourcePattern { watch: watch.clone(), matcher: glob.compile_matcher() }),
+			Err(error) => {
+				debug!("{:?}", error);
+				error!("Invalid source pattern `{}`: {}. It is taken as a literal path", pattern, error);
+				None
+			}
+		}
+	}
+
+	/// Check whether the file `path` (canonical) matches the pattern.
+	pub(crate) fn matches(&self, path: &Path) -> bool {
+		self.matcher.is_match(path)
+	}
+
+	/// Check whether any file matches the pattern.
+	pub(crate) fn has_matches(&self) -> bool {
+		!self.expand().is_empty()
+	}
+
+	/// The files matching the pattern, sorted.
+	fn expand(&self) -> Vec<PathBuf> {
+		let (base, rest) = split_pattern(Path::new(self.matcher.glob().glob()));
+		// Only `**` can match any number of directories
+		let recursive = rest.components().any(|component| component.as_os_str() == "**");
+		let mut walker = WalkDir::new(&base).min_depth(1);
+		if !recursive {
+			walker = walker.max_depth(rest.components().count());
+		}
+		let mut files: Vec<PathBuf> = walker.into_iter()
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| !entry.file_type().is_dir() && self.matches(entry.path()))
+			.map(|entry| entry.into_path())
+			.collect();
+		files.sort();
+		files
+	}
+}
+
+/// The patterns among the sources of `watches`.
+pub(crate) fn patterns(watches: &[Watch]) -> Vec<SourcePattern> {
+	watches.iter().filter_map(SourcePattern::of).collect()
+}
+
+/// Replace the watches of `watches` whose source is a pattern with a watch of each file it matches.
+///
+/// A pattern matching no file is kept as it is, to be reported as a missing source.
+pub(crate) fn expand_watches(watches: &[Watch]) -> Vec<Watch> {
+	let mut expanded = Vec::new();
+	for watch in watches {
+		let files = match SourcePattern::of(watch) {
+			Some(pattern) => pattern.expand(),
+			None => {
+				expanded.push(watch.clone());
+				continue;
+			}
+		};
+		if files.is_empty() {
+			warn!("No file matches the source pattern `{}`", watch.source.display());
+			expanded.push(watch.clone());
+			continue;
+		}
+		info!("The source pattern `{}` matches {} file(s)", watch.source.display(), files.len());
+		expanded.extend(files.into_iter().map(|source| Watch { source, ..watch.clone() }));
+	}
+	expanded
+}
+
+/// Split `pattern` into the directory before its first component with a glob metacharacter, and the rest.
+fn split_pattern(pattern: &Path) -> (PathBuf, PathBuf) {
+	let mut base = PathBuf::new();
+	let mut components = pattern.components();
+	for component in components.by_ref() {
+		if let Component::Normal(name) = component {
+			if name.to_string_lossy().contains(GLOB_METACHARACTERS) {
+				// Joining an empty path would add a trailing separator
+				let mut rest = PathBuf::from(name);
+				if !components.as_path().as_os_str().is_empty() {
+					rest.push(components.as_path());
+				}
+				if base.as_os_str().is_empty() {
+					base.push(".");
+				}
+				return (base, rest);
+			}
+		}
+		base.push(component);
+	}
+	(base, PathBuf::new())
+}
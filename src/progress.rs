@@ -0,0 +1,63 @@
+//! Feedback on the duration of the copies, for the large files.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+/// How often the progress of a copy is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A reader logging how much of `source` (`total` bytes) has been read from `inner`, every [`PROGRESS_INTERVAL`].
+pub(crate) struct ProgressReader<R> {
+	inner: R,
+	source: PathBuf,
+	total: u64,
+	bytes_read: u64,
+	last_report: Instant
+}
+
+impl<R: Read> ProgressReader<R> {
+	/// Read `total` bytes of `source` from `inner`, starting to count from now.
+	pub(crate) fn new(inner: R, source: &Path, total: u64) -> ProgressReader<R> {
+		ProgressReader { inner, source: source.to_path_buf(), total, bytes_read: 0, last_report: Instant::now() }
+	}
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		self.bytes_read += read as u64;
+		if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+			self.last_report = Instant::now();
+			// The source may grow during the copy
+			let percent = self.bytes_read * 100 / self.total.max(self.bytes_read).max(1);
+			info!("Backing up `{:?}`: {} of {} ({}%)", self.source, format_bytes(self.bytes_read), format_bytes(self.total), percent);
+		}
+		Ok(read)
+	}
+}
+
+/// Log how long the backup of `bytes` bytes of `source` into `destination` took, and the resulting throughput.
+pub(crate) fn log_throughput(source: &Path, destination: &str, bytes: u64, elapsed: Duration) {
+	let seconds = elapsed.as_secs_f64();
+	// Small files are copied in no measurable time
+	let rate = if seconds > 0.0 { (bytes as f64 / seconds) as u64 } else { bytes };
+	info!("Backed up `{:?}` into `{}`: {} in {:.1}s ({}/s)", source, destination, format_bytes(bytes), seconds, format_bytes(rate));
+}
+
+/// Format `bytes` with the largest unit (as accepted by the size options) that keeps it above 1.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes);
+	}
+	let mut value = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,104 @@
+//! Pruning of the versioned backups on a timer, with [`BackupConfig::prune_interval`], away from the
+//! copies: the backups only tell which sources have new backups, pruned together once the interval is over.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::BackupConfig;
+use crate::retention;
+
+/// Sources with new backups waiting to be pruned by the pruner thread, and the thread itself, once started
+static QUEUE: Mutex<Option<(Sender<Target>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// The backups of a source to prune.
+struct Target {
+	/// The destination of the watch, that `destination_dir` is part of
+	root: PathBuf,
+	destination_dir: PathBuf,
+	source: PathBuf,
+	/// The retention policy of the source
+	config: BackupConfig
+}
+
+/// Start the thread pruning the backups every `interval`.
+pub(crate) fn start(interval: Duration) {
+	let mut queue = lock_queue();
+	if queue.is_some() {
+		debug!("The pruner thread is already running");
+		return;
+	}
+	let (sender, receiver) = channel();
+	let thread = thread::spawn(move || prune_periodically(&receiver, interval));
+	*queue = Some((sender, thread));
+}
+
+/// Prune what is left to prune, and stop the pruner thread.
+pub(crate) fn stop() {
+	let queue = lock_queue().take();
+	if let Some((sender, thread)) = queue {
+		// The thread stops once the queue is empty and closed
+		drop(sender);
+		if thread.join().is_err() {
+			debug!("The pruner thread panicked");
+		}
+	}
+}
+
+/// Lock the queue of the sources to prune.
+fn lock_queue() -> MutexGuard<'static, Option<(Sender<Target>, JoinHandle<()>)>> {
+	// A poisoned lock only means that another pruning panicked: the queue is still fine
+	QUEUE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Prune the backups of `source` in `destination_dir` (part of the destination `root`), right away without
+/// [`BackupConfig::prune_interval`] (or when the pruner thread isn't running), otherwise once the interval is over.
+pub(crate) fn prune(root: &Path, destination_dir: &Path, source: &Path, config: &BackupConfig) {
+	if config.prune_interval.is_some() {
+		if let Some((sender, _)) = &*lock_queue() {
+			let target = Target {
+				root: root.to_path_buf(),
+				destination_dir: destination_dir.to_path_buf(),
+				source: source.to_path_buf(),
+				config: config.clone()
+			};
+			if sender.send(target).is_ok() {
+				return;
+			}
+			debug!("The pruner thread has stopped");
+		}
+	}
+	retention::prune_backups(root, destination_dir, source, config);
+}
+
+/// Prune the backups of the sources received from `receiver` every `interval`.
+fn prune_periodically(receiver: &Receiver<Target>, interval: Duration) {
+	// A source backed up many times during the interval is only pruned once
+	let mut pending: HashMap<(PathBuf, PathBuf), (PathBuf, BackupConfig)> = HashMap::new();
+	let mut next_pruning = Instant::now() + interval;
+	loop {
+		let closed = match receiver.recv_timeout(next_pruning.saturating_duration_since(Instant::now())) {
+			Ok(target) => {
+				pending.insert((target.destination_dir, target.source), (target.root, target.config));
+				continue;
+			},
+			Err(RecvTimeoutError::Timeout) => false,
+			Err(RecvTimeoutError::Disconnected) => true
+		};
+		if !pending.is_empty() {
+			debug!("Pruning the backups of {} source(s)", pending.len());
+		}
+		for ((destination_dir, source), (root, config)) in pending.drain() {
+			retention::prune_backups(&root, &destination_dir, &source, &config);
+		}
+		next_pruning = Instant::now() + interval;
+		if closed {
+			break;
+		}
+	}
+}
@@ -0,0 +1,63 @@
+//! Rolling a source back to one of its versioned backups.
+
+use std::fs::{File, copy};
+use std::io;
+use std::path::Path;
+
+use log::{info, debug};
+
+use crate::{BackupConfig, BackupError, Passphrase};
+use crate::backup;
+use crate::versions::{self, Version};
+
+/// Copy the versioned backup of `source` labeled `version` (see [`Version::label`]), or the most
+/// recent one, from `destination_dir` back over `source`, returning the restored version.
+///
+/// With `save_current`, the current content of `source` (if any) is backed up as a new version first,
+/// so that the restore can be undone. Encrypted backups are decrypted with `passphrase`.
+pub fn restore(
+	source: &Path,
+	destination_dir: &Path,
+	version: Option<&str>,
+	save_current: bool,
+	passphrase: Option<&Passphrase>
+) -> Result<Version, BackupError> {
+	let versions = versions::list(destination_dir, source).map_err(|error| {
+		debug!("{:?}", error);
+		BackupError::DestUnreadable(destination_dir.to_path_buf(), error)
+	})?;
+	let chosen = match version {
+		Some(label) => versions.into_iter().find(|candidate| candidate.label() == label),
+		None => versions.into_iter().last()
+	};
+	let chosen = chosen.ok_or_else(|| BackupError::NoBackup(source.to_path_buf(), version.map(str::to_string)))?;
+
+	if save_current && source.is_file() {
+		let mut config = BackupConfig::new(Vec::new());
+		config.versioned = true;
+		let safety_backup = backup::backup_file_path(destination_dir, source, &config);
+		copy(source, &safety_backup).map_err(|error| {
+			debug!("{:?}", error);
+			BackupError::RestoreFailed(source.to_path_buf(), error)
+		})?;
+		info!("Current content of `{:?}` saved into `{:?}`", source, safety_backup);
+	}
+
+	copy_back(&chosen, source, passphrase).map_err(|error| {
+		debug!("{:?}", error);
+		BackupError::RestoreFailed(source.to_path_buf(), error)
+	})?;
+	info!("Restored `{:?}` from `{:?}`", source, chosen.path);
+	Ok(chosen)
+}
+
+/// Write the content of `version` over `source`, through a temporary file so that a failure
+/// never leaves the source half-written.
+fn copy_back(version: &Version, source: &Path, passphrase: Option<&Passphrase>) -> io::Result<()> {
+	let mut backup_content = version.open(passphrase)?;
+	backup::write_atomically(source, |temp_path| {
+		let mut temp_file = File::create(temp_path)?;
+		io::copy(&mut backup_content, &mut temp_file)?;
+		temp_file.sync_all()
+	})
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, remove_file};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use chrono::NaiveDate;
+use log::{info, debug, error, warn};
+
+use crate::BackupConfig;
+use crate::backup;
+use crate::versions::{self, Version};
+
+/// List the versioned backups of `source` in `destination_dir`, part of the destination `root` of
+/// its watch, oldest first.
+///
+/// With [`BackupConfig::destination_by_date`] the backups of every day are listed: those found at the
+/// same place as `destination_dir` in the directories of the other days.
+fn list_backups(root: &Path, destination_dir: &Path, source: &Path, config: &BackupConfig) -> io::Result<Vec<Version>> {
+	let dated_path = match destination_dir.strip_prefix(root) {
+		Ok(dated_path) if config.destination_by_date => dated_path,
+		_ => return versions::list(destination_dir, source)
+	};
+	// The directory of the day, then the mirrored tree inside it
+	let mut components = dated_path.iter();
+	if components.next().is_none() {
+		return versions::list(destination_dir, source);
+	}
+	let inner_path = components.as_path();
+
+	let mut backups = Vec::new();
+	for entry in read_dir(root)? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() || !backup::is_dated_directory(&entry.file_name()) {
+			continue;
+		}
+		match versions::list(&entry.path().join(inner_path), source) {
+			Ok(versions) => backups.extend(versions),
+			// Not backed up that day
+			Err(error) if error.kind() == ErrorKind::NotFound => (),
+			Err(error) => return Err(error)
+		}
+	}
+	backups.sort_by_key(|version| (version.timestamp, version.counter));
+	Ok(backups)
+}
+
+/// Delete the oldest versioned backups of `source` (never the most recent one) until `destination_dir`
+/// (part of the destination `root`) has `needed` bytes of free space, returning whether it has.
+pub(crate) fn make_room(root: &Path, destination_dir: &Path, source: &Path, needed: u64, config: &BackupConfig) -> bool {
+	let backups = match list_backups(root, destination_dir, source, config) {
+		Ok(versions) => versions,
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not scan `{:?}` for old backups. Reason: {}", destination_dir, error);
+			return false;
+		}
+	};
+
+	// Oldest first
+	let evictable = backups.len().saturating_sub(1);
+	for version in backups.into_iter().take(evictable) {
+		if fs2::available_space(destination_dir).is_ok_and(|available| available >= needed) {
+			return true;
+		}
+		if config.dry_run {
+			info!("DRY RUN: would delete old backup `{:?}` ({} bytes) to make room", version.path, version.size);
+			continue;
+		}
+		match remove_file(&version.path) {
+			Ok(()) => warn!("Deleted old backup `{:?}` ({} bytes) to make room", version.path, version.size),
+			Err(error) => {
+				debug!("{:?}", error);
+				error!("Could not delete old backup `{:?}`. Reason: {}", version.path, error);
+			}
+		}
+	}
+	fs2::available_space(destination_dir).is_ok_and(|available| available >= needed)
+}
+
+/// Delete the oldest versioned backups of each day beyond the most recent [`BackupConfig::keep_per_day`]
+/// of that day, then the oldest remaining ones so that only the most recent [`BackupConfig::keep`] remain,
+/// then keep deleting the oldest ones while they total more than [`BackupConfig::max_size`] bytes.
+///
+/// The backups are the ones of `destination_dir`, part of the destination `root` (see [`list_backups`]).
+/// The most recent backup is never deleted to fit the size budget, even if it exceeds it alone.
+pub(crate) fn prune_backups(root: &Path, destination_dir: &Path, source: &Path, config: &BackupConfig) {
+	// Oldest first
+	let backups = match list_backups(root, destination_dir, source, config) {
+		Ok(versions) => versions,
+		Err(error) => {
+			debug!("{:?}", error);
+			error!("Could not scan `{:?}` for old backups. Reason: {}", destination_dir, error);
+			return;
+		}
+	};
+
+	let (mut kept, mut pruned): (Vec<Version>, Vec<Version>) = match config.keep_per_day {
+		Some(keep_per_day) => {
+			// Newest first, counting the backups of each day
+			let mut per_day: HashMap<NaiveDate, usize> = HashMap::new();
+			let (mut kept, pruned): (Vec<Version>, Vec<Version>) = backups.into_iter().rev().partition(|version| {
+				let count = per_day.entry(version.timestamp.date()).or_insert(0);
+				*count += 1;
+				*count <= keep_per_day
+			});
+			kept.reverse();
+			(kept, pruned)
+		},
+		None => (backups, Vec::new())
+	};
+
+	let excess = config.keep.map(|keep| kept.len().saturating_sub(keep)).unwrap_or(0);
+	pruned.extend(kept.drain(..excess));
+	if let Some(max_size) = config.max_size {
+		let mut total_size: u64 = kept.iter().map(|version| version.size).sum();
+		while total_size > max_size && kept.len() > 1 {
+			let oldest = kept.remove(0);
+			total_size -= oldest.size;
+			pruned.push(oldest);
+		}
+		if total_size > max_size {
+			warn!("The latest backup of `{:?}` alone exceeds the size limit of {} bytes", source, max_size);
+		}
+	}
+
+	for version in pruned {
+		if config.dry_run {
+			info!("DRY RUN: would delete old backup `{:?}` ({} bytes)", version.path, version.size);
+			continue;
+		}
+		match remove_file(&version.path) {
+			Ok(()) => info!("Deleted old backup `{:?}` ({} bytes)", version.path, version.size),
+			Err(error) => {
+				debug!("{:?}", error);
+				error!("Could not delete old backup `{:?}`. Reason: {}", version.path, error);
+			}
+		}
+	}
+}
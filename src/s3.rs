@@ -0,0 +1,66 @@
+//! Backups uploaded to an S3 bucket.
+
+use std::fs::metadata;
+use std::io;
+use std::path::Path;
+
+use aws_sdk_s3::Client;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::sink::BackupSink;
+
+/// Uploads the backups to `bucket`, under `prefix`, with the credentials of the standard chain
+/// (environment variables, shared configuration files, instance metadata...).
+pub(crate) struct S3Sink {
+	/// The SDK is asynchronous, while the backups are made one at a time: every upload blocks on this runtime
+	runtime: Runtime,
+	client: Client,
+	bucket: String,
+	prefix: String
+}
+
+impl S3Sink {
+	pub(crate) fn new(bucket: &str, prefix: &str) -> io::Result<S3Sink> {
+		let runtime = Builder::new_current_thread().enable_all().build()?;
+		let sdk_config = runtime.block_on(aws_config::load_from_env());
+		Ok(S3Sink {
+			client: Client::new(&sdk_config),
+			runtime,
+			bucket: bucket.to_string(),
+			prefix: prefix.trim_matches('/').to_string()
+		})
+	}
+
+	/// Key of the object named `name`.
+	fn key(&self, name: &Path) -> String {
+		let components: Vec<_> = name.iter().map(|component| component.to_string_lossy()).collect();
+		if self.prefix.is_empty() {
+			components.join("/")
+		} else {
+			format!("{}/{}", self.prefix, components.join("/"))
+		}
+	}
+}
+
+impl BackupSink for S3Sink {
+	fn store(&self, source: &Path, name: &Path) -> io::Result<u64> {
+		let size = metadata(source)?.len();
+		self.runtime.block_on(async {
+			let body = ByteStream::from_path(source).await.map_err(io::Error::other)?;
+			self.client.put_object()
+				.bucket(&self.bucket)
+				.key(self.key(name))
+				.body(body)
+				.send()
+				.await
+				.map_err(|error| io::Error::other(DisplayErrorContext(error).to_string()))?;
+			Ok(size)
+		})
+	}
+
+	fn location(&self, name: &Path) -> String {
+		format!("s3://{}/{}", self.bucket, self.key(name))
+	}
+}
@@ -0,0 +1,174 @@
+//! Backups uploaded to a server over SFTP.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use dirs::home_dir;
+use log::{info, debug};
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+use crate::sink::BackupSink;
+
+/// Port of the SSH servers, unless the URL tells otherwise
+const DEFAULT_PORT: u16 = 22;
+
+/// Keys tried, from the `.ssh` directory of the user, when no key is given and the agent has none that works
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Uploads the backups into the directory `root` of an SSH server.
+///
+/// The session is opened with the first upload and kept for the next ones: when an upload fails
+/// it's dropped, so that the next attempt (or the retry) connects again.
+pub(crate) struct SftpSink {
+	user: String,
+	host: String,
+	port: u16,
+	root: PathBuf,
+	/// Private key to authenticate with, instead of the agent and the default keys
+	key: Option<PathBuf>,
+	session: Mutex<Option<Sftp>>
+}
+
+impl SftpSink {
+	/// Parse `location`, the part of a `sftp://[USER@]HOST[:PORT]/PATH` URL after the scheme
+	/// (`HOST:/PATH`, as for scp, is accepted too).
+	pub(crate) fn new(location: &str, key: Option<&Path>) -> Result<SftpSink, String> {
+		let (authority, root) = match location.find('/') {
+			Some(index) => location.split_at(index),
+			None => return Err("no remote directory given".to_string())
+		};
+		let (user, host_port) = match authority.split_once('@') {
+			Some((user, host_port)) => (user.to_string(), host_port),
+			None => (env::var("USER").or_else(|_| env::var("USERNAME")).map_err(|_| "no user given".to_string())?, authority)
+		};
+		let (host, port) = match host_port.rsplit_once(':') {
+			Some((host, "")) => (host, DEFAULT_PORT),
+			Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port `{}`", port))?),
+			None => (host_port, DEFAULT_PORT)
+		};
+		if host.is_empty() {
+			return Err("no host given".to_string());
+		}
+		Ok(SftpSink {
+			user,
+			host: host.to_string(),
+			port,
+			root: PathBuf::from(root),
+			key: key.map(Path::to_path_buf),
+			session: Mutex::new(None)
+		})
+	}
+
+	/// Open an authenticated session, checking the server against the known hosts of the user.
+	fn connect(&self) -> io::Result<Sftp> {
+		debug!("Connecting to {}:{}", self.host, self.port);
+		let mut session = Session::new()?;
+		session.set_tcp_stream(TcpStream::connect((self.host.as_str(), self.port))?);
+		session.handshake()?;
+		self.check_host_key(&session)?;
+
+		match &self.key {
+			Some(key) => session.userauth_pubkey_file(&self.user, None, key, None)?,
+			None => {
+				if let Err(error) = session.userauth_agent(&self.user) {
+					debug!("Authentication with the agent failed: {}", error);
+				}
+				let ssh_dir = home_dir().unwrap_or_default().join(".ssh");
+				for key in DEFAULT_KEYS.iter().map(|name| ssh_dir.join(name)).filter(|key| key.is_file()) {
+					if session.authenticated() {
+						break;
+					}
+					if let Err(error) = session.userauth_pubkey_file(&self.user, None, &key, None) {
+						debug!("Authentication with `{:?}` failed: {}", key, error);
+					}
+				}
+			}
+		}
+		if !session.authenticated() {
+			return Err(io::Error::new(ErrorKind::PermissionDenied, format!("could not authenticate as `{}`", self.user)));
+		}
+
+		info!("Connected to {}:{}", self.host, self.port);
+		Ok(session.sftp()?)
+	}
+
+	/// Refuse servers whose key isn't the one recorded in `~/.ssh/known_hosts`.
+	fn check_host_key(&self, session: &Session) -> io::Result<()> {
+		let (key, _) = session.host_key().ok_or_else(|| io::Error::other("the server sent no host key"))?;
+		let mut known_hosts = session.known_hosts()?;
+		let known_hosts_file = home_dir().unwrap_or_default().join(".ssh").join("known_hosts");
+		if let Err(error) = known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH) {
+			debug!("Could not read `{:?}`: {}", known_hosts_file, error);
+		}
+		match known_hosts.check_port(&self.host, self.port, key) {
+			CheckResult::Match => Ok(()),
+			CheckResult::Mismatch => Err(io::Error::new(
+				ErrorKind::PermissionDenied,
+				format!("the host key of {} doesn't match the one in `{}`", self.host, known_hosts_file.display())
+			)),
+			CheckResult::NotFound | CheckResult::Failure => Err(io::Error::new(
+				ErrorKind::PermissionDenied,
+				format!("{} is not a known host: connect to it once with ssh to add it to `{}`", self.host, known_hosts_file.display())
+			))
+		}
+	}
+
+	/// Upload `source` to `destination` over `sftp`, creating its directories first.
+	///
+	/// The content is written to a temporary file renamed into place once complete, so that an
+	/// interrupted upload never leaves a truncated backup behind.
+	fn upload(&self, sftp: &Sftp, source: &Path, destination: &Path) -> io::Result<u64> {
+		// "destination" is inside the root directory, so it has a parent
+		let mut directory = PathBuf::new();
+		for component in destination.parent().unwrap() {
+			directory.push(component);
+			if sftp.stat(&directory).is_err() {
+				debug!("Creating remote directory `{:?}`", directory);
+				sftp.mkdir(&directory, 0o755)?;
+			}
+		}
+
+		let mut temp_name = destination.as_os_str().to_owned();
+		temp_name.push(".tmp");
+		let temp_path = PathBuf::from(temp_name);
+		let mut remote_file = sftp.create(&temp_path)?;
+		let bytes = io::copy(&mut File::open(source)?, &mut remote_file)?;
+		remote_file.flush()?;
+		drop(remote_file);
+		// libssh2 only sends the rename flags from SFTP v5, and OpenSSH speaks v3: without OVERWRITE, its rename
+		// refuses an existing target, so the previous backup is removed first
+		if let Err(error) = sftp.unlink(destination) {
+			let error = io::Error::from(error);
+			if error.kind() != ErrorKind::NotFound {
+				return Err(error);
+			}
+		}
+		sftp.rename(&temp_path, destination, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))?;
+		Ok(bytes)
+	}
+}
+
+impl BackupSink for SftpSink {
+	fn store(&self, source: &Path, name: &Path) -> io::Result<u64> {
+		// A poisoned lock only means a previous upload panicked: the session is reset anyway
+		let mut session = self.session.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		if session.is_none() {
+			*session = Some(self.connect()?);
+		}
+		// Just set if it wasn't, so the unwrap is secure
+		let result = self.upload(session.as_ref().unwrap(), source, &self.root.join(name));
+		if result.is_err() {
+			// The session may have been dropped by the server (or the network): start over the next time
+			*session = None;
+		}
+		result
+	}
+
+	fn location(&self, name: &Path) -> String {
+		format!("sftp://{}@{}:{}{}", self.user, self.host, self.port, self.root.join(name).display())
+	}
+}
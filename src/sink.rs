@@ -0,0 +1,89 @@
+//! The places backups are stored into: local directories, and remote destinations that backups
+//! are uploaded to instead.
+//!
+//! Only the storing itself goes through a sink. The options working on the stored backups
+//! (retention, deduplication, verification...) need a local directory, so local and remote
+//! backups are still told apart before it.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{BackupConfig, BackupError};
+use crate::backup;
+
+/// A place backups can be stored into.
+pub(crate) trait BackupSink {
+	/// Store `source` as `name` (a relative path), returning the number of bytes of `source` stored.
+	fn store(&self, source: &Path, name: &Path) -> io::Result<u64>;
+
+	/// Where the backup named `name` is stored, as a path or an URL.
+	fn location(&self, name: &Path) -> String;
+}
+
+/// The local directory `directory`, that backups are copied (or compressed, or encrypted) into as `config` says.
+pub(crate) struct LocalSink<'a> {
+	directory: &'a Path,
+	config: &'a BackupConfig
+}
+
+impl LocalSink<'_> {
+	pub(crate) fn new<'a>(directory: &'a Path, config: &'a BackupConfig) -> LocalSink<'a> {
+		LocalSink { directory, config }
+	}
+
+	/// Path of the backup named `name`.
+	pub(crate) fn path(&self, name: &Path) -> PathBuf {
+		self.directory.join(name)
+	}
+}
+
+impl BackupSink for LocalSink<'_> {
+	fn store(&self, source: &Path, name: &Path) -> io::Result<u64> {
+		backup::store(source, &self.path(name), self.config)
+	}
+
+	fn location(&self, name: &Path) -> String {
+		self.path(name).display().to_string()
+	}
+}
+
+/// Set up the sink of `destination` if it's the URL of a remote destination (`s3://bucket/prefix`
+/// or `sftp://user@host/path`), or return `None` for local directories.
+pub(crate) fn remote_sink(destination: &Path, config: &BackupConfig) -> Option<Result<Box<dyn BackupSink>, BackupError>> {
+	let url = destination.to_str()?;
+	if let Some(location) = url.strip_prefix("s3://") {
+		return Some(s3_sink(destination, location));
+	}
+	if let Some(location) = url.strip_prefix("sftp://") {
+		return Some(sftp_sink(destination, location, config));
+	}
+	None
+}
+
+#[cfg(feature = "s3")]
+fn s3_sink(destination: &Path, location: &str) -> Result<Box<dyn BackupSink>, BackupError> {
+	let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+	if bucket.is_empty() {
+		return Err(BackupError::InvalidDestination(destination.to_path_buf(), "no bucket given".to_string()));
+	}
+	crate::s3::S3Sink::new(bucket, prefix)
+		.map(|sink| Box::new(sink) as Box<dyn BackupSink>)
+		.map_err(|error| BackupError::DestSetupFailed(destination.to_path_buf(), error))
+}
+
+#[cfg(not(feature = "s3"))]
+fn s3_sink(destination: &Path, _location: &str) -> Result<Box<dyn BackupSink>, BackupError> {
+	Err(BackupError::InvalidDestination(destination.to_path_buf(), "built without S3 support (the `s3` feature)".to_string()))
+}
+
+#[cfg(feature = "sftp")]
+fn sftp_sink(destination: &Path, location: &str, config: &BackupConfig) -> Result<Box<dyn BackupSink>, BackupError> {
+	crate::sftp::SftpSink::new(location, config.ssh_key.as_deref())
+		.map(|sink| Box::new(sink) as Box<dyn BackupSink>)
+		.map_err(|reason| BackupError::InvalidDestination(destination.to_path_buf(), reason))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn sftp_sink(destination: &Path, _location: &str, _config: &BackupConfig) -> Result<Box<dyn BackupSink>, BackupError> {
+	Err(BackupError::InvalidDestination(destination.to_path_buf(), "built without SFTP support (the `sftp` feature)".to_string()))
+}
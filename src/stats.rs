@@ -0,0 +1,102 @@
+//! Summary of the backups stored in a destination directory.
+
+use std::collections::HashMap;
+use std::fs::metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, TimeZone};
+use walkdir::WalkDir;
+
+use crate::{backup, manifest, versions};
+
+/// Figures about the backups of a destination directory.
+#[derive(Debug, Clone)]
+pub struct Stats {
+	/// Number of distinct sources backed up
+	pub sources: usize,
+	/// Number of backups, all versions included
+	pub backups: usize,
+	/// Size of the backups on disk
+	pub bytes: u64,
+	/// When the oldest backup was made, if there is any
+	pub oldest: Option<DateTime<Local>>,
+	/// When the newest backup was made, if there is any
+	pub newest: Option<DateTime<Local>>,
+	/// Source with the most backups, and how many it has
+	pub most_backups: Option<(PathBuf, usize)>,
+	/// Whether the figures come from the manifest of the directory, rather than from its files
+	pub from_manifest: bool
+}
+
+/// A backup, as found in the manifest or among the files.
+struct Backup {
+	/// Source of the backup: its path when read from the manifest, otherwise the path of the backup
+	/// relative to the destination directory, with the name of the source
+	source: PathBuf,
+	size: u64,
+	made: Option<DateTime<Local>>
+}
+
+/// Compute the figures about the backups of `destination_dir`, from its manifest if it has one
+/// (see [`BackupConfig::manifest`](crate::BackupConfig::manifest)), otherwise from the files it holds.
+pub fn compute(destination_dir: &Path) -> io::Result<Stats> {
+	let recorded = manifest::read(destination_dir)?;
+	let from_manifest = !recorded.is_empty();
+	let backups = if from_manifest {
+		// Backups deleted since they were recorded don't count
+		recorded.into_iter()
+			.filter_map(|entry| {
+				let size = metadata(destination_dir.join(&entry.backup_file)).ok()?.len();
+				let made = DateTime::parse_from_rfc3339(&entry.timestamp).ok().map(|made| made.with_timezone(&Local));
+				Some(Backup { source: entry.source, size, made })
+			})
+			.collect()
+	} else {
+		scan(destination_dir)?
+	};
+
+	let mut by_source: HashMap<&Path, usize> = HashMap::new();
+	for backup in &backups {
+		*by_source.entry(&backup.source).or_insert(0) += 1;
+	}
+	// Ties go to the first source in alphabetical order, to be stable across runs
+	let most_backups = by_source.iter()
+		.max_by(|(source, count), (other_source, other_count)| count.cmp(other_count).then(other_source.cmp(source)))
+		.map(|(source, count)| (source.to_path_buf(), *count));
+
+	Ok(Stats {
+		sources: by_source.len(),
+		backups: backups.len(),
+		bytes: backups.iter().map(|backup| backup.size).sum(),
+		oldest: backups.iter().filter_map(|backup| backup.made).min(),
+		newest: backups.iter().filter_map(|backup| backup.made).max(),
+		most_backups,
+		from_manifest
+	})
+}
+
+/// Find the backups among the files of `destination_dir` (mirrored trees included), skipping the
+/// lock files, the manifest, the temporary files and the bad backups moved aside.
+///
+/// Versioned backups are dated by their name, the others by their modification time.
+fn scan(destination_dir: &Path) -> io::Result<Vec<Backup>> {
+	let mut backups = Vec::new();
+	for entry in WalkDir::new(destination_dir).min_depth(1) {
+		let entry = entry.map_err(io::Error::from)?;
+		let file_name = entry.file_name().to_string_lossy();
+		if !entry.file_type().is_file() || file_name.starts_with('.') || file_name == manifest::MANIFEST_FILE_NAME || backup::is_temp_file(&file_name)
+			|| file_name.ends_with(backup::QUARANTINE_SUFFIX) || file_name.ends_with(backup::LATEST_SUFFIX) {
+			continue;
+		}
+		let metadata = entry.metadata().map_err(io::Error::from)?;
+		let (source_name, made) = match versions::parse_backup_name(&file_name) {
+			(source_name, Some(timestamp)) => (source_name, Local.from_local_datetime(&timestamp).earliest()),
+			(source_name, None) => (source_name, metadata.modified().ok().map(DateTime::<Local>::from))
+		};
+		// "entry" is inside "destination_dir", so it has a parent
+		let directory = entry.path().parent().unwrap().strip_prefix(destination_dir).unwrap();
+		backups.push(Backup { source: directory.join(source_name), size: metadata.len(), made });
+	}
+	Ok(backups)
+}
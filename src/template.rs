@@ -0,0 +1,120 @@
+//! Templates for the names of the backup files, like `{stem}-{date:%Y%m%d}{ext}`.
+//!
+//! The available placeholders are:
+//!
+//! - `{name}`: the file name of the source (`notes.txt`)
+//! - `{stem}`: the file name without its extension (`notes`)
+//! - `{ext}`: the extension, including the dot (`.txt`, or nothing when there's none)
+//! - `{parent}`: the name of the directory of the source (`projA` for `projA/notes.txt`)
+//! - `{date:FORMAT}`: the time of the backup, formatted with the `strftime`-like `FORMAT`
+//!
+//! Templates can contain `/`, like `{parent}/{name}`: the backups then go into subdirectories of
+//! the destination, created as needed.
+
+use std::path::Path;
+
+use chrono::Local;
+use chrono::format::{Item, StrftimeItems};
+
+use crate::BackupError;
+
+/// A piece of a template.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+	Literal(String),
+	Name,
+	Stem,
+	Extension,
+	Parent,
+	Date(String)
+}
+
+/// Name standing for the parent of the sources at the root of the filesystem, that doesn't have one
+const ROOT_NAME: &str = "root";
+
+/// A validated template for the names of the backup files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestTemplate {
+	parts: Vec<Part>
+}
+
+impl DestTemplate {
+	/// Parse `template`, rejecting unknown placeholders and invalid date formats.
+	pub fn parse(template: &str) -> Result<DestTemplate, BackupError> {
+		let invalid = |reason: String| BackupError::InvalidTemplate(template.to_string(), reason);
+
+		let mut parts = Vec::new();
+		let mut rest = template;
+		while !rest.is_empty() {
+			match rest.find(['{', '}']) {
+				Some(index) if rest[index..].starts_with('}') => return Err(invalid("unmatched `}`".to_string())),
+				Some(index) => {
+					if index > 0 {
+						parts.push(Part::Literal(rest[..index].to_string()));
+					}
+					let end = match rest[index..].find('}') {
+						Some(end) => index + end,
+						None => return Err(invalid("unmatched `{`".to_string()))
+					};
+					parts.push(parse_placeholder(&rest[index + 1..end]).map_err(invalid)?);
+					rest = &rest[end + 1..];
+				},
+				None => {
+					parts.push(Part::Literal(rest.to_string()));
+					rest = "";
+				}
+			}
+		}
+
+		// Subdirectories are fine, as long as the backups stay inside the destination
+		let escapes = template.starts_with(['/', '\\']) || template.split(['/', '\\']).any(|component| component == "..");
+		let ends_with_directory = template.ends_with(['/', '\\']) || template.contains("//");
+		let date_has_separator = parts.iter().any(|part| matches!(part, Part::Date(format) if format.contains(['/', '\\'])));
+		if parts.is_empty() || escapes || ends_with_directory || date_has_separator {
+			return Err(invalid("the template must expand to a file name, optionally inside subdirectories of the destination".to_string()));
+		}
+		Ok(DestTemplate { parts })
+	}
+
+	/// Check whether the template puts the backups into subdirectories of the destination.
+	pub(crate) fn has_directories(&self) -> bool {
+		self.parts.iter().any(|part| matches!(part, Part::Literal(literal) if literal.contains(['/', '\\'])))
+	}
+
+	/// Name of the backup of `source` made right now, relative to the destination.
+	pub(crate) fn expand(&self, source: &Path) -> String {
+		let name = source.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+		let stem = source.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+		let parent = source.parent().and_then(Path::file_name).map(|parent| parent.to_string_lossy()).unwrap_or(ROOT_NAME.into());
+		let now = Local::now();
+
+		self.parts.iter().map(|part| match part {
+			Part::Literal(literal) => literal.clone(),
+			Part::Name => name.to_string(),
+			Part::Stem => stem.to_string(),
+			Part::Extension => source.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default(),
+			Part::Parent => parent.to_string(),
+			// Some formats (like `%D`) contain separators, which would turn the name into a path
+			Part::Date(format) => now.format(format).to_string().replace(['/', '\\'], "-")
+		}).collect()
+	}
+}
+
+/// Parse the content of a `{...}` placeholder.
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+	match placeholder {
+		"name" => Ok(Part::Name),
+		"stem" => Ok(Part::Stem),
+		"ext" => Ok(Part::Extension),
+		"parent" => Ok(Part::Parent),
+		_ if placeholder.starts_with("date:") => {
+			let format = &placeholder["date:".len()..];
+			if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) {
+				Err(format!("invalid date format `{}`", format))
+			} else {
+				Ok(Part::Date(format.to_string()))
+			}
+		},
+		_ => Err(format!("unknown placeholder `{{{}}}`", placeholder))
+	}
+}
@@ -0,0 +1,39 @@
+//! Bandwidth limiting of the copies.
+
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the chunks read at once, small enough for the rate to be smooth
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A reader yielding at most `rate` bytes per second (on average) from `inner`.
+pub(crate) struct ThrottledReader<R> {
+	inner: R,
+	rate: u64,
+	start: Instant,
+	bytes_read: u64
+}
+
+impl<R: Read> ThrottledReader<R> {
+	/// Read from `inner`, starting to count from now.
+	pub(crate) fn new(inner: R, rate: u64) -> ThrottledReader<R> {
+		ThrottledReader { inner, rate, start: Instant::now(), bytes_read: 0 }
+	}
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		// Wait until the bytes read so far fit in the rate
+		let expected = Duration::from_secs_f64(self.bytes_read as f64 / self.rate as f64);
+		let elapsed = self.start.elapsed();
+		if expected > elapsed {
+			thread::sleep(expected - elapsed);
+		}
+
+		let len = buf.len().min(CHUNK_SIZE).min(self.rate.max(1) as usize);
+		let read = self.inner.read(&mut buf[..len])?;
+		self.bytes_read += read as u64;
+		Ok(read)
+	}
+}
@@ -0,0 +1,172 @@
+//! The versioned backups of a source (see [`BackupConfig::versioned`](crate::BackupConfig::versioned)).
+
+use std::fs::{File, read_dir};
+use std::io::{self, Read};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::NaiveDateTime;
+use flate2::read::GzDecoder;
+
+use crate::{ChecksumAlgo, Passphrase};
+use crate::backup::{self, COMPRESSED_SUFFIX};
+use crate::encryption::{self, ENCRYPTED_SUFFIX};
+
+/// Format of the timestamp in the names of versioned backups
+pub(crate) const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// A versioned backup of a source.
+#[derive(Debug, Clone)]
+pub struct Version {
+	/// Path of the backup file
+	pub path: PathBuf,
+	/// When the backup was made, as written in its name
+	pub timestamp: NaiveDateTime,
+	/// Counter telling apart the backups made in the same second (0 for the first one)
+	pub counter: u32,
+	/// Last modification time of the backup file
+	pub modified: SystemTime,
+	/// Size of the backup file
+	pub size: u64,
+	/// Whether the backup is gzipped
+	pub compressed: bool,
+	/// Whether the backup is encrypted
+	pub encrypted: bool
+}
+
+impl Version {
+	/// The part of the file name identifying the version, as in `2019-07-14_21-05-09` or `2019-07-14_21-05-09-1`.
+	pub fn label(&self) -> String {
+		let timestamp = self.timestamp.format(TIMESTAMP_FORMAT);
+		if self.counter == 0 {
+			timestamp.to_string()
+		} else {
+			format!("{}-{}", timestamp, self.counter)
+		}
+	}
+
+	/// Reader of the original content of the backup, once decrypted (with `passphrase`) and decompressed.
+	pub fn open(&self, passphrase: Option<&Passphrase>) -> io::Result<Box<dyn Read>> {
+		open_backup(&self.path, self.compressed, self.encrypted, passphrase)
+	}
+
+	/// SHA-256 hash (in hexadecimal) of the original content of the backup.
+	pub fn checksum(&self, passphrase: Option<&Passphrase>) -> io::Result<String> {
+		Ok(backup::hex(&ChecksumAlgo::Sha256.hash_reader(self.open(passphrase)?)?))
+	}
+}
+
+/// Reader of the original content of the backup at `path`, decrypting it (with `passphrase`) and
+/// decompressing it as needed.
+pub(crate) fn open_backup(path: &Path, compressed: bool, encrypted: bool, passphrase: Option<&Passphrase>) -> io::Result<Box<dyn Read>> {
+	let file = File::open(path)?;
+	let reader: Box<dyn Read> = if encrypted {
+		Box::new(encryption::decrypt(file, passphrase.ok_or_else(encryption::missing_passphrase)?)?)
+	} else {
+		Box::new(file)
+	};
+	if compressed {
+		Ok(Box::new(GzDecoder::new(reader)))
+	} else {
+		Ok(reader)
+	}
+}
+
+/// List the versioned backups of `source` found in `destination_dir`, oldest first.
+///
+/// Only the name of `source` matters, so it's fine for the source not to exist (anymore).
+pub fn list(destination_dir: &Path, source: &Path) -> io::Result<Vec<Version>> {
+	let mut versions: Vec<Version> = read_dir(destination_dir)?
+		.filter_map(|entry| entry.ok())
+		.filter_map(|entry| {
+			let file_name = entry.file_name();
+			let (timestamp, counter, compressed, encrypted) = parse_version(file_name.to_str()?, source)?;
+			let metadata = entry.metadata().ok()?;
+			if !metadata.is_file() {
+				return None;
+			}
+			Some(Version {
+				path: entry.path(),
+				timestamp,
+				counter,
+				modified: metadata.modified().ok()?,
+				size: metadata.len(),
+				compressed,
+				encrypted
+			})
+		})
+		.collect();
+	versions.sort_by_key(|version| (version.timestamp, version.counter));
+	Ok(versions)
+}
+
+/// Parse `file_name` as the name of a versioned backup (see [`backup_file_path`](crate::backup::backup_file_path))
+/// of `source`, into its timestamp, its counter, whether it's compressed and whether it's encrypted.
+fn parse_version(file_name: &str, source: &Path) -> Option<(NaiveDateTime, u32, bool, bool)> {
+	// Compressed and encrypted backups are versions as well, but the source may have one of their extensions too:
+	// the name is tried without both suffixes, then without the encrypted one, then as it is
+	let encrypted_only = file_name.strip_suffix(ENCRYPTED_SUFFIX).map(|name| (name, false, true));
+	iter::once(strip_suffixes(file_name))
+		.chain(encrypted_only)
+		.chain(iter::once((file_name, false, false)))
+		.find_map(|(name, compressed, encrypted)| {
+			parse_plain_version(name, source).map(|(timestamp, counter)| (timestamp, counter, compressed, encrypted))
+		})
+}
+
+/// Parse `file_name`, stripped of the suffixes of compressed and encrypted backups, as the name of a versioned backup
+/// of `source`, into its timestamp and its counter.
+fn parse_plain_version(file_name: &str, source: &Path) -> Option<(NaiveDateTime, u32)> {
+	let source_name = source.file_name()?;
+	let stem = format!("{}.", source.file_stem().unwrap_or(source_name).to_string_lossy());
+	let extension = source.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+	if !file_name.starts_with(&stem) || !file_name.ends_with(&extension) || file_name.len() < stem.len() + extension.len() {
+		return None;
+	}
+
+	// What's left is the timestamp, optionally followed by the collision counter
+	let version = &file_name[stem.len()..file_name.len() - extension.len()];
+	let (timestamp, counter) = (version.get(..19)?, &version[19..]);
+	let counter = match counter.strip_prefix('-') {
+		None if counter.is_empty() => 0,
+		Some(digits) if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) => digits.parse().ok()?,
+		_ => return None
+	};
+	let timestamp = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+	Some((timestamp, counter))
+}
+
+/// Strip the extensions of compressed and encrypted backups from `file_name`, telling whether it had them.
+fn strip_suffixes(file_name: &str) -> (&str, bool, bool) {
+	let (file_name, encrypted) = match file_name.strip_suffix(ENCRYPTED_SUFFIX) {
+		Some(file_name) => (file_name, true),
+		None => (file_name, false)
+	};
+	match file_name.strip_suffix(COMPRESSED_SUFFIX) {
+		Some(file_name) => (file_name, true, encrypted),
+		None => (file_name, false, encrypted)
+	}
+}
+
+/// Parse `file_name` as the name of a backup of any source, into the name of that source and, for
+/// versioned backups, the timestamp of the version.
+pub(crate) fn parse_backup_name(file_name: &str) -> (String, Option<NaiveDateTime>) {
+	let (name, _, _) = strip_suffixes(file_name);
+	// The timestamp follows the stem of the source, which can have dots of its own
+	for (index, _) in name.match_indices('.') {
+		let (stem, version) = (&name[..index], &name[index + 1..]);
+		let timestamp = match version.get(..19).and_then(|timestamp| NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()) {
+			Some(timestamp) => timestamp,
+			None => continue
+		};
+		let rest = &version[19..];
+		let counter_len = rest.strip_prefix('-').map_or(0, |digits| 1 + digits.bytes().take_while(u8::is_ascii_digit).count());
+		let source_name = format!("{}{}", stem, &rest[counter_len..]);
+		// Confirmed by the parser of the versions of a known source
+		if parse_version(file_name, Path::new(&source_name)).is_some() {
+			return (source_name, Some(timestamp));
+		}
+	}
+	(name.to_string(), None)
+}
@@ -0,0 +1,133 @@
+//! The filesystem watchers, native or polling.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// A kind of event that can trigger the backup of the path it's about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+	/// The file was written to
+	Write,
+	/// The file was created
+	Create,
+	/// The file was moved into place, as atomic saves do
+	Rename,
+	/// The permissions (or other attributes) of the file changed
+	Chmod
+}
+
+impl TriggerEvent {
+	/// The events that trigger a backup by default: all of them but [`TriggerEvent::Chmod`], [`TriggerEvent::Rename`]
+	/// included for the atomic saves.
+	pub const DEFAULT: [TriggerEvent; 3] = [TriggerEvent::Write, TriggerEvent::Create, TriggerEvent::Rename];
+
+	/// The event named `name` (`write`, `create`, `rename` or `chmod`, case-insensitive).
+	pub fn parse(name: &str) -> Option<TriggerEvent> {
+		match name.to_lowercase().as_str() {
+			"write" => Some(TriggerEvent::Write),
+			"create" => Some(TriggerEvent::Create),
+			"rename" => Some(TriggerEvent::Rename),
+			"chmod" => Some(TriggerEvent::Chmod),
+			_ => None
+		}
+	}
+}
+
+/// A change of a path, as reported by the watchers.
+#[derive(Debug)]
+pub(crate) enum Change {
+	/// The path was written to, or moved into place
+	Updated(PathBuf),
+	/// The path was removed, or moved away
+	Removed(PathBuf)
+}
+
+impl Change {
+	/// The changes `event` stands for, updates being only reported for the `triggers` events.
+	///
+	/// Removals are always reported, whatever the triggers.
+	pub(crate) fn of(event: DebouncedEvent, triggers: &[TriggerEvent]) -> Vec<Change> {
+		let triggered = |trigger| triggers.contains(&trigger);
+		match event {
+			DebouncedEvent::Write(path) if triggered(TriggerEvent::Write) => vec![Change::Updated(path)],
+			// New files show up in watched directories, and sources deleted and then recreated
+			// (by atomic saves or build tools) come back as new files too
+			DebouncedEvent::Create(path) if triggered(TriggerEvent::Create) => vec![Change::Updated(path)],
+			DebouncedEvent::Chmod(path) if triggered(TriggerEvent::Chmod) => vec![Change::Updated(path)],
+			// Atomic saves show up as a temporary file being renamed over the source
+			DebouncedEvent::Rename(from, to) if triggered(TriggerEvent::Rename) => vec![Change::Removed(from), Change::Updated(to)],
+			DebouncedEvent::Rename(from, _) => vec![Change::Removed(from)],
+			DebouncedEvent::Remove(path) => vec![Change::Removed(path)],
+			_ => Vec::new()
+		}
+	}
+
+	/// The changed path.
+	pub(crate) fn path(&self) -> &Path {
+		match self {
+			Change::Updated(path) | Change::Removed(path) => path
+		}
+	}
+}
+
+/// A watcher reporting debounced events, using the native notifications of the platform or
+/// polling the watched paths (for filesystems that never report changes, such as network shares).
+pub(crate) enum SourceWatcher {
+	Native(RecommendedWatcher),
+	Polling(PollWatcher)
+}
+
+impl SourceWatcher {
+	/// Create a watcher sending its events to `tx` once settled for `debounce`, or polling every
+	/// `poll` interval if given (the poll interval then acts as the debounce as well).
+	pub(crate) fn new(tx: Sender<DebouncedEvent>, debounce: Duration, poll: Option<Duration>) -> notify::Result<SourceWatcher> {
+		match poll {
+			Some(interval) => PollWatcher::new(tx, interval).map(SourceWatcher::Polling),
+			None => RecommendedWatcher::new(tx, debounce).map(SourceWatcher::Native)
+		}
+	}
+
+	/// Check whether the watcher polls the watched paths.
+	pub(crate) fn is_polling(&self) -> bool {
+		matches!(self, SourceWatcher::Polling(_))
+	}
+
+	/// Start watching `path`.
+	pub(crate) fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+		match self {
+			SourceWatcher::Native(watcher) => watcher.watch(path, mode),
+			SourceWatcher::Polling(watcher) => watcher.watch(path, mode)
+		}
+	}
+
+	/// Stop watching `path`.
+	pub(crate) fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+		match self {
+			SourceWatcher::Native(watcher) => watcher.unwatch(path),
+			SourceWatcher::Polling(watcher) => watcher.unwatch(path)
+		}
+	}
+}
+
+/// The inotify limit that `error` reports reaching, as the name of the sysctl raising it.
+#[cfg(target_os = "linux")]
+pub(crate) fn exhausted_limit(error: &notify::Error) -> Option<&'static str> {
+	match error {
+		// Every watched directory takes a watch, every watcher an instance
+		notify::Error::Io(error) => match error.raw_os_error() {
+			Some(libc::ENOSPC) => Some("fs.inotify.max_user_watches"),
+			Some(libc::EMFILE) => Some("fs.inotify.max_user_instances"),
+			_ => None
+		},
+		_ => None
+	}
+}
+
+/// The native watchers of the other platforms have no such limits.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn exhausted_limit(_error: &notify::Error) -> Option<&'static str> {
+	None
+}
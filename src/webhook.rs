@@ -0,0 +1,147 @@
+//! HTTP POST requests reporting every backup to a monitoring service.
+
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::mpsc::{SyncSender, TrySendError, sync_channel};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::Local;
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::limiter;
+
+/// How many reports can wait to be sent: when the webhook is slower than the backups, the newer ones are dropped
+const QUEUE_SIZE: usize = 100;
+
+/// Maximum time for a request to complete, not to keep the queue stuck on an unresponsive server
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reports waiting to be sent by the webhook thread, and the thread itself, once started
+static QUEUE: Mutex<Option<(SyncSender<String>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// What is sent for every backup.
+#[derive(Serialize)]
+struct Report<'a> {
+	source: &'a Path,
+	destination: Option<&'a str>,
+	bytes: Option<u64>,
+	timestamp: String,
+	status: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	message: Option<&'a str>
+}
+
+/// What is sent instead of the backups of the last interval, with
+/// [`BackupConfig::notify_interval`](crate::BackupConfig::notify_interval).
+#[derive(Serialize)]
+struct SummaryReport<'a> {
+	backups: usize,
+	failures: usize,
+	bytes: u64,
+	/// Length of the interval, in seconds
+	interval: u64,
+	timestamp: String,
+	status: &'a str
+}
+
+/// Start the thread posting the reports to `url`, in the background so that a slow (or unreachable)
+/// webhook never holds the backups back.
+pub(crate) fn start(url: &str) {
+	let mut queue = lock_queue();
+	if queue.is_some() {
+		debug!("The webhook thread is already running");
+		return;
+	}
+	let (sender, receiver) = sync_channel::<String>(QUEUE_SIZE);
+	let url = url.to_string();
+	let thread = thread::spawn(move || {
+		let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+		for body in receiver {
+			let result = agent.post(&url)
+				.set("Content-Type", "application/json")
+				.send_string(&body);
+			if let Err(error) = result {
+				debug!("{:?}", error);
+				warn!("Could not report the backup to the webhook `{}`. Reason: {}", url, error);
+			}
+		}
+	});
+	*queue = Some((sender, thread));
+}
+
+/// Wait for the queued reports to be sent, and stop the webhook thread.
+pub(crate) fn stop() {
+	let queue = lock_queue().take();
+	if let Some((sender, thread)) = queue {
+		// The thread stops once the queue is empty and closed
+		drop(sender);
+		if thread.join().is_err() {
+			debug!("The webhook thread panicked");
+		}
+	}
+}
+
+/// Lock the queue of the reports.
+fn lock_queue() -> MutexGuard<'static, Option<(SyncSender<String>, JoinHandle<()>)>> {
+	// A poisoned lock only means that another report panicked: the queue is still fine
+	QUEUE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Report the successful backup of `source` into `destination`.
+pub(crate) fn backed_up(source: &Path, destination: &str, bytes: u64) {
+	send(&Report {
+		source,
+		destination: Some(destination),
+		bytes: Some(bytes),
+		timestamp: Local::now().to_rfc3339(),
+		status: "success",
+		message: None
+	}, &format!("`{:?}`", source));
+}
+
+/// Report the failed backup of `source`.
+pub(crate) fn failed(source: &Path, message: &str) {
+	send(&Report {
+		source,
+		destination: None,
+		bytes: None,
+		timestamp: Local::now().to_rfc3339(),
+		status: "failure",
+		message: Some(message)
+	}, &format!("`{:?}`", source));
+}
+
+/// Report the backups of `summary`, made during the last `interval`.
+pub(crate) fn summary(summary: &limiter::Summary, interval: Duration) {
+	send(&SummaryReport {
+		backups: summary.backups,
+		failures: summary.failures,
+		bytes: summary.bytes,
+		interval: interval.as_secs(),
+		timestamp: Local::now().to_rfc3339(),
+		status: "summary"
+	}, "the last backups");
+}
+
+/// Queue `report` (of the backups of `subject`) for the webhook thread, if it's running.
+fn send<T: Serialize>(report: &T, subject: &str) {
+	let queue = lock_queue();
+	let sender = match &*queue {
+		Some((sender, _)) => sender,
+		None => return
+	};
+	let body = match serde_json::to_string(report) {
+		Ok(body) => body,
+		Err(error) => {
+			debug!("Could not serialize the webhook report: {:?}", error);
+			return;
+		}
+	};
+	match sender.try_send(body) {
+		Ok(()) => (),
+		Err(TrySendError::Full(_)) => warn!("Too many backups to report, the webhook is not keeping up: dropping the report of {}", subject),
+		Err(TrySendError::Disconnected(_)) => debug!("The webhook thread has stopped")
+	}
+}
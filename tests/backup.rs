@@ -0,0 +1,927 @@
+//! Backups of real files, made through [`run`] in `once` mode.
+
+use std::fs::read_dir;
+use std::path::Path;
+use std::time::Duration;
+
+use assert_fs::TempDir;
+use assert_fs::prelude::*;
+use chrono::Local;
+use fs2::FileExt;
+use tempfile::NamedTempFile;
+
+use file_watcher_backup::{BackupConfig, BackupError, ChecksumAlgo, DestTemplate, LineEnding, Passphrase, Watch, manifest, restore, run, versions};
+
+/// Settings backing up `source` into `destination` once, the defaults otherwise.
+fn once_config(source: &Path, destination: &Path) -> BackupConfig {
+	let mut config = BackupConfig::new(vec![Watch::new(source.to_path_buf(), destination.to_path_buf())]);
+	config.once = true;
+	config
+}
+
+/// Back up `source` into `destination` once, with the default settings.
+fn backup_once(source: &Path, destination: &Path) -> Result<(), BackupError> {
+	run(once_config(source, destination))
+}
+
+/// Number of files of `dir` whose name starts with `prefix`.
+fn count_backups(dir: &Path, prefix: &str) -> usize {
+	read_dir(dir).unwrap()
+		.filter_map(Result::ok)
+		.filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+		.count()
+}
+
+#[test]
+fn backs_up_the_changes_of_the_source() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("first").unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("notes.txt").assert("first");
+
+	source.write_str("second").unwrap();
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("notes.txt").assert("second");
+}
+
+#[test]
+fn keeps_the_previous_backups() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	let destination = temp.child("backup");
+	let mut config = once_config(source.path(), destination.path());
+	config.keep_previous = Some(2);
+
+	for content in ["first", "second", "third", "fourth"] {
+		source.write_str(content).unwrap();
+		run(config.clone()).unwrap();
+	}
+	destination.child("notes.txt").assert("fourth");
+	destination.child("notes.txt.prev").assert("third");
+	destination.child("notes.txt.prev.1").assert("second");
+	assert!(!destination.child("notes.txt.prev.2").path().exists());
+}
+
+#[test]
+fn backs_up_into_every_destination() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let (local, share) = (temp.child("local"), temp.child("share"));
+
+	let mut config = BackupConfig::new(vec![
+		Watch::new(source.to_path_buf(), local.to_path_buf()),
+		Watch::new(source.to_path_buf(), share.to_path_buf())
+	]);
+	config.once = true;
+	run(config).unwrap();
+	local.child("notes.txt").assert("content");
+	share.child("notes.txt").assert("content");
+}
+
+#[test]
+fn mirrors_the_tree_of_directories() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("a/config.toml").write_str("a").unwrap();
+	source.child("b/config.toml").write_str("b").unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("a/config.toml").assert("a");
+	destination.child("b/config.toml").assert("b");
+}
+
+#[test]
+fn flattens_the_tree_of_directories() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("sub/dir/config.toml").write_str("same").unwrap();
+	source.child("sub_dir/config.toml").write_str("same").unwrap();
+	source.child("top.toml").write_str("top").unwrap();
+	let destination = temp.child("backup");
+	let mut config = once_config(source.path(), destination.path());
+	config.flatten = Some("_".to_string());
+
+	run(config).unwrap();
+	destination.child("sub_dir_config.toml").assert("same");
+	destination.child("sub%5Fdir_config.toml").assert("same");
+	destination.child("top.toml").assert("top");
+	assert_eq!(count_backups(destination.path(), ""), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn links_the_identical_backups_of_each_destination_only() {
+	use std::os::unix::fs::MetadataExt;
+
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("a.txt").write_str("same").unwrap();
+	source.child("b.txt").write_str("same").unwrap();
+	source.child("c.txt").write_str("other").unwrap();
+	let (local, share) = (temp.child("local"), temp.child("share"));
+
+	let mut config = BackupConfig::new(vec![
+		Watch::new(source.to_path_buf(), local.to_path_buf()),
+		Watch::new(source.to_path_buf(), share.to_path_buf())
+	]);
+	config.once = true;
+	config.dedup = true;
+	run(config).unwrap();
+	for destination in [&local, &share] {
+		let links = |name| destination.child(name).path().metadata().unwrap().nlink();
+		assert_eq!(links("a.txt"), 2);
+		assert_eq!(links("b.txt"), 2);
+		assert_eq!(links("c.txt"), 1);
+		destination.child("c.txt").assert("other");
+	}
+}
+
+#[test]
+fn backs_up_the_files_matching_a_pattern() {
+	let temp = TempDir::new().unwrap();
+	let configs = temp.child("configs");
+	configs.child("a.toml").write_str("a").unwrap();
+	configs.child("b.toml").write_str("b").unwrap();
+	configs.child("notes.txt").write_str("notes").unwrap();
+	configs.child("nested/c.toml").write_str("c").unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(&configs.path().join("*.toml"), destination.path()).unwrap();
+	destination.child("a.toml").assert("a");
+	destination.child("b.toml").assert("b");
+	assert!(!destination.child("notes.txt").path().exists());
+	assert!(!destination.child("c.toml").path().exists());
+}
+
+#[test]
+fn backs_up_the_existing_files_named_like_patterns() {
+	let temp = TempDir::new().unwrap();
+	let literal = temp.child("photo[1].jpg");
+	literal.write_str("literal").unwrap();
+	temp.child("photo1.jpg").write_str("match").unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(literal.path(), destination.path()).unwrap();
+	destination.child("photo[1].jpg").assert("literal");
+	assert!(!destination.child("photo1.jpg").path().exists());
+}
+
+#[test]
+fn separates_the_backups_by_the_directory_of_their_source() {
+	let temp = TempDir::new().unwrap();
+	let (first, second) = (temp.child("projA/config.toml"), temp.child("projB/config.toml"));
+	first.write_str("a").unwrap();
+	second.write_str("b").unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = BackupConfig::new(vec![
+		Watch::new(first.to_path_buf(), destination.to_path_buf()),
+		Watch::new(second.to_path_buf(), destination.to_path_buf())
+	]);
+	config.once = true;
+	config.dest_template = Some(DestTemplate::parse("{parent}/{name}").unwrap());
+	run(config).unwrap();
+	destination.child("projA/config.toml").assert("a");
+	destination.child("projB/config.toml").assert("b");
+}
+
+/// The known digests of `abc`, in hexadecimal.
+const ABC_DIGESTS: [(ChecksumAlgo, &str); 3] = [
+	(ChecksumAlgo::Blake3, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+	(ChecksumAlgo::Sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+	(ChecksumAlgo::Xxhash, "78af5f94892f3950")
+];
+
+/// `bytes` in hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn computes_the_known_digests_of_every_checksum_algorithm() {
+	for (algo, digest) in ABC_DIGESTS {
+		assert_eq!(hex(&algo.hash_reader(&b"abc"[..]).unwrap()), digest, "{:?}", algo);
+	}
+}
+
+#[test]
+fn verifies_the_backups_with_every_checksum_algorithm() {
+	for (algo, digest) in ABC_DIGESTS {
+		let temp = TempDir::new().unwrap();
+		let source = temp.child("notes.txt");
+		source.write_str("abc").unwrap();
+		let destination = temp.child("backup");
+
+		let mut config = once_config(source.path(), destination.path());
+		config.verify = true;
+		config.checksum_algo = Some(algo);
+		run(config).unwrap();
+		destination.child("notes.txt").assert("abc");
+		assert_eq!(hex(&algo.hash_reader(std::fs::File::open(destination.child("notes.txt").path()).unwrap()).unwrap()), digest);
+	}
+}
+
+#[test]
+fn only_records_the_sha256_of_the_sources_in_the_manifest() {
+	for algo in [ChecksumAlgo::Blake3, ChecksumAlgo::Sha256, ChecksumAlgo::Xxhash] {
+		let temp = TempDir::new().unwrap();
+		let source = temp.child("notes.txt");
+		source.write_str("abc").unwrap();
+		let destination = temp.child("backup");
+
+		let mut config = once_config(source.path(), destination.path());
+		config.manifest = true;
+		config.checksum_algo = Some(algo);
+		run(config).unwrap();
+		let entries = manifest::read(destination.path()).unwrap();
+		let expected = if algo == ChecksumAlgo::Sha256 { Some(ABC_DIGESTS[1].1.to_string()) } else { None };
+		assert_eq!(entries[0].sha256, expected, "{:?}", algo);
+	}
+}
+
+#[test]
+fn only_compresses_the_files_above_the_minimum_size() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("small.txt").write_str("tiny").unwrap();
+	source.child("large.txt").write_str("large enough to be compressed").unwrap();
+	let destination = temp.child("backup");
+	let mut config = once_config(source.path(), destination.path());
+	config.compress = true;
+	config.compress_min_size = Some(10);
+
+	run(config.clone()).unwrap();
+	destination.child("small.txt").assert("tiny");
+	assert!(destination.child("large.txt.gz").path().exists());
+	assert!(!destination.child("large.txt").path().exists());
+
+	// Grown past the minimum size, the uncompressed backup is replaced
+	source.child("small.txt").write_str("not that tiny anymore").unwrap();
+	run(config).unwrap();
+	assert!(destination.child("small.txt.gz").path().exists());
+	assert!(!destination.child("small.txt").path().exists());
+}
+
+#[test]
+fn records_the_backups_of_concurrent_runs_in_the_manifest() {
+	let temp = TempDir::new().unwrap();
+	let destination = temp.child("backup");
+	let runs: Vec<_> = ["a", "b"].iter().map(|name| {
+		let source = temp.child(name);
+		for index in 0..20 {
+			source.child(format!("{}{}.txt", name, index)).write_str(name).unwrap();
+		}
+		let mut config = once_config(source.path(), destination.path());
+		config.manifest = true;
+		std::thread::spawn(move || run(config).unwrap())
+	}).collect();
+	for run in runs {
+		run.join().unwrap();
+	}
+	assert_eq!(manifest::read(destination.path()).unwrap().len(), 40);
+}
+
+/// Back up `source` into `destination` once, versioned and encrypted with `passphrase` (and compressed with `compress`).
+fn backup_encrypted(source: &Path, destination: &Path, passphrase: &str, compress: bool) {
+	let mut config = once_config(source, destination);
+	config.versioned = true;
+	config.compress = compress;
+	config.encryption = Some(Passphrase::new(passphrase.to_string()));
+	run(config).unwrap();
+}
+
+#[test]
+fn restores_the_encrypted_backups_with_their_passphrase() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("secret").unwrap();
+	let destination = temp.child("backup");
+
+	backup_encrypted(source.path(), destination.path(), "passphrase", false);
+	let backup = &versions::list(destination.path(), source.path()).unwrap()[0];
+	assert!(backup.encrypted && !backup.compressed);
+	assert!(!std::fs::read(&backup.path).unwrap().windows(6).any(|window| window == b"secret"));
+
+	source.write_str("changed").unwrap();
+	restore::restore(source.path(), destination.path(), None, false, Some(&Passphrase::new("passphrase".to_string()))).unwrap();
+	source.assert("secret");
+}
+
+#[test]
+fn refuses_to_restore_with_the_wrong_passphrase() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("secret").unwrap();
+	let destination = temp.child("backup");
+
+	backup_encrypted(source.path(), destination.path(), "passphrase", false);
+	source.write_str("changed").unwrap();
+	let result = restore::restore(source.path(), destination.path(), None, false, Some(&Passphrase::new("wrong".to_string())));
+	assert!(matches!(result, Err(BackupError::RestoreFailed(..))));
+	source.assert("changed");
+}
+
+#[test]
+fn restores_the_compressed_and_encrypted_backups() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str(&"compressible ".repeat(100)).unwrap();
+	let destination = temp.child("backup");
+
+	backup_encrypted(source.path(), destination.path(), "passphrase", true);
+	let backup = &versions::list(destination.path(), source.path()).unwrap()[0];
+	assert!(backup.encrypted && backup.compressed);
+	assert!(backup.path.to_string_lossy().ends_with(".gz.enc"));
+	assert!(backup.size < 1300);
+
+	source.write_str("changed").unwrap();
+	restore::restore(source.path(), destination.path(), None, false, Some(&Passphrase::new("passphrase".to_string()))).unwrap();
+	source.assert("compressible ".repeat(100));
+}
+
+#[test]
+fn only_deletes_its_own_leftover_temporary_files() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	// Written by a process that isn't running
+	destination.child(".notes.txt.fwb-tmp.999999999").write_str("leftover").unwrap();
+	destination.child("report.tmp.2024").write_str("user file").unwrap();
+
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("notes.txt").assert("content");
+	assert!(!destination.child(".notes.txt.fwb-tmp.999999999").path().exists());
+	destination.child("report.tmp.2024").assert("user file");
+}
+
+/// Name of the lock file of the existing `source`.
+fn lock_file_name(source: &Path) -> String {
+	use sha2::{Digest, Sha256};
+
+	let hash = Sha256::digest(source.canonicalize().unwrap().to_string_lossy().as_bytes());
+	let name: String = hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+	format!(".file-watcher-backup-{}.lock", name)
+}
+
+#[test]
+fn waits_for_the_pid_of_a_lock_being_created() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	// Created and locked by another instance, that writes its PID right after
+	let lock = destination.child(lock_file_name(source.path()));
+	lock.touch().unwrap();
+	let file = std::fs::File::open(lock.path()).unwrap();
+	file.lock_exclusive().unwrap();
+	let pid = 999_999_999;
+	let lock_path = lock.to_path_buf();
+	let writer = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(300));
+		std::fs::write(lock_path, pid.to_string()).unwrap();
+	});
+
+	let result = backup_once(source.path(), destination.path());
+	writer.join().unwrap();
+	drop(file);
+	assert!(matches!(result, Err(BackupError::AlreadyRunning(_, owner_pid, _)) if owner_pid == pid));
+	assert!(!destination.child("notes.txt").path().exists());
+}
+
+#[test]
+fn takes_over_the_locks_left_by_crashed_instances() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	// Not locked anymore, whatever PID it holds (reused by another process maybe)
+	let lock = destination.child(lock_file_name(source.path()));
+	lock.write_str(&std::process::id().to_string()).unwrap();
+
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("notes.txt").assert("content");
+	assert!(!lock.path().exists());
+}
+
+#[test]
+fn reports_missing_sources() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("missing.txt");
+
+	let result = backup_once(source.path(), temp.child("backup").path());
+	assert!(matches!(result, Err(BackupError::SourceNotFound(path)) if path == source.path()));
+}
+
+#[test]
+fn waits_for_the_missing_sources() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	let destination = temp.child("backup");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.wait_for_source = Some(Duration::from_secs(10));
+	let (written, path) = (temp.child("notes.tmp").to_path_buf(), source.to_path_buf());
+	let creator = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(300));
+		// Moved into place, not to be backed up half-written
+		std::fs::write(&written, "content").unwrap();
+		std::fs::rename(written, path).unwrap();
+	});
+	run(config).unwrap();
+	creator.join().unwrap();
+	destination.child("notes.txt").assert("content");
+}
+
+#[test]
+fn reports_unusable_destinations() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	// A directory can't be created inside a file
+	let file = NamedTempFile::new().unwrap();
+
+	let result = backup_once(source.path(), &file.path().join("backup"));
+	assert!(matches!(result, Err(BackupError::DestSetupFailed(..))));
+}
+
+#[test]
+fn refuses_files_as_destination() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let file = temp.child("backup.txt");
+	file.write_str("other").unwrap();
+
+	let result = backup_once(source.path(), file.path());
+	assert!(matches!(result, Err(BackupError::DestinationIsFile(path)) if path == file.path()));
+	file.assert("other");
+}
+
+#[test]
+fn skips_the_files_out_of_the_size_limits() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("empty.txt").touch().unwrap();
+	source.child("notes.txt").write_str("content").unwrap();
+	source.child("dump.sql").write_str("a much larger content").unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.min_file_size = Some(1);
+	config.max_file_size = Some(10);
+	run(config).unwrap();
+	destination.child("notes.txt").assert("content");
+	assert!(!destination.child("empty.txt").path().exists());
+	assert!(!destination.child("dump.sql").path().exists());
+}
+
+#[test]
+fn refuses_to_back_up_a_file_over_itself() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+
+	let result = backup_once(source.path(), temp.path());
+	assert!(matches!(result, Err(BackupError::BackupIsSource(_))));
+	source.assert("content");
+}
+
+#[test]
+fn applies_the_settings_of_each_watch() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let (plain, versioned) = (temp.child("plain"), temp.child("versioned"));
+
+	let mut config = BackupConfig::new(vec![
+		Watch::new(source.to_path_buf(), plain.to_path_buf()),
+		Watch { versioned: Some(true), ..Watch::new(source.to_path_buf(), versioned.to_path_buf()) }
+	]);
+	config.once = true;
+	run(config).unwrap();
+	plain.child("notes.txt").assert("content");
+	// Only the versioned backup, named after its timestamp, is there
+	assert!(!versioned.child("notes.txt").path().exists());
+	assert_eq!(count_backups(versioned.path(), "notes"), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn backs_up_linked_sources_under_the_name_of_the_link() {
+	let temp = TempDir::new().unwrap();
+	let target = temp.child("notes.txt");
+	target.write_str("content").unwrap();
+	let link = temp.child("link.txt");
+	link.symlink_to_file(target.path()).unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(link.path(), destination.path()).unwrap();
+	destination.child("link.txt").assert("content");
+	assert!(!destination.child("notes.txt").path().exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn backs_up_the_targets_of_linked_sources_when_following_them() {
+	let temp = TempDir::new().unwrap();
+	let target = temp.child("notes.txt");
+	target.write_str("content").unwrap();
+	let link = temp.child("link.txt");
+	link.symlink_to_file(target.path()).unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = once_config(link.path(), destination.path());
+	config.follow_symlinks = true;
+	run(config).unwrap();
+	destination.child("notes.txt").assert("content");
+	assert!(!destination.child("link.txt").path().exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn skips_the_linked_directories_of_watched_directories() {
+	let temp = TempDir::new().unwrap();
+	temp.child("shared/config.toml").write_str("shared").unwrap();
+	let source = temp.child("src");
+	source.child("notes.txt").write_str("notes").unwrap();
+	source.child("linked").symlink_to_dir(temp.child("shared").path()).unwrap();
+	let destination = temp.child("backup");
+
+	backup_once(source.path(), destination.path()).unwrap();
+	destination.child("notes.txt").assert("notes");
+	assert!(!destination.child("linked").path().exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn backs_up_the_linked_directories_of_watched_directories_when_following_them() {
+	let temp = TempDir::new().unwrap();
+	temp.child("shared/config.toml").write_str("shared").unwrap();
+	let source = temp.child("src");
+	source.child("notes.txt").write_str("notes").unwrap();
+	source.child("linked").symlink_to_dir(temp.child("shared").path()).unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.follow_symlinks = true;
+	run(config).unwrap();
+	destination.child("notes.txt").assert("notes");
+	destination.child("linked/config.toml").assert("shared");
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_the_command_after_each_backup() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	let output = temp.child("hook.txt");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.on_change_command = Some(format!(
+		"echo \"$FWB_BACKUP_BYTES $(basename \"$FWB_BACKUP\") $(basename \"$FWB_BACKUP_SOURCE\")\" > '{}'",
+		output.path().display()
+	));
+	// The commands left to run are waited for when stopping
+	run(config).unwrap();
+	output.assert("7 notes.txt notes.txt\n");
+
+	// The commands share a thread: the ones still running when stopping are killed, not to hold it up
+	// (in the same test, as they would also kill the commands of the others)
+	source.write_str("changed").unwrap();
+	let mut config = once_config(source.path(), destination.path());
+	config.on_change_command = Some("sleep 60".to_string());
+	let started = std::time::Instant::now();
+	run(config).unwrap();
+	assert!(started.elapsed() < Duration::from_secs(30), "{:?}", started.elapsed());
+}
+
+#[test]
+fn keeps_the_latest_backups_of_each_day() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	for name in ["notes.2019-07-14_09-00-00.txt", "notes.2019-07-14_21-05-09.txt", "notes.2019-07-15_08-30-00.txt"] {
+		destination.child(name).write_str("old").unwrap();
+	}
+
+	let mut config = once_config(source.path(), destination.path());
+	config.versioned = true;
+	config.keep_per_day = Some(1);
+	run(config).unwrap();
+	assert!(!destination.child("notes.2019-07-14_09-00-00.txt").path().exists());
+	destination.child("notes.2019-07-14_21-05-09.txt").assert("old");
+	destination.child("notes.2019-07-15_08-30-00.txt").assert("old");
+	assert_eq!(count_backups(destination.path(), "notes"), 3);
+}
+
+#[test]
+fn prunes_the_backups_in_the_background() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	destination.child("notes.2019-07-14_21-05-09.txt").write_str("old").unwrap();
+
+	let mut config = once_config(source.path(), destination.path());
+	config.versioned = true;
+	config.keep = Some(1);
+	// Long after the run, the pending pruning is done when stopping
+	config.prune_interval = Some(Duration::from_secs(3600));
+	run(config).unwrap();
+	assert!(!destination.child("notes.2019-07-14_21-05-09.txt").path().exists());
+	assert_eq!(count_backups(destination.path(), "notes"), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn links_the_latest_versioned_backup() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("first").unwrap();
+	let destination = temp.child("backup");
+	let mut config = once_config(source.path(), destination.path());
+	config.versioned = true;
+	config.latest_link = true;
+
+	run(config.clone()).unwrap();
+	source.write_str("second").unwrap();
+	run(config).unwrap();
+	let link = destination.child("notes.txt.latest");
+	assert!(link.path().symlink_metadata().unwrap().file_type().is_symlink());
+	link.assert("second");
+}
+
+#[test]
+fn stores_the_backups_in_the_directory_of_the_day() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.destination_by_date = true;
+	run(config).unwrap();
+	let today = Local::now().format("%Y-%m-%d").to_string();
+	destination.child(today).child("notes.txt").assert("content");
+	assert!(!destination.child("notes.txt").path().exists());
+}
+
+#[test]
+fn prunes_the_backups_of_every_day() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	destination.child("2019-07-14/notes.2019-07-14_21-05-09.txt").write_str("old").unwrap();
+	destination.child("2019-07-15/notes.2019-07-15_08-30-00.txt").write_str("old").unwrap();
+
+	let mut config = once_config(source.path(), destination.path());
+	config.versioned = true;
+	config.destination_by_date = true;
+	config.keep = Some(2);
+	run(config).unwrap();
+	assert!(!destination.child("2019-07-14/notes.2019-07-14_21-05-09.txt").path().exists());
+	destination.child("2019-07-15/notes.2019-07-15_08-30-00.txt").assert("old");
+	let today = Local::now().format("%Y-%m-%d").to_string();
+	assert_eq!(count_backups(destination.child(today).path(), "notes"), 1);
+}
+
+#[test]
+fn normalizes_the_line_endings_of_text_files() {
+	let temp = TempDir::new().unwrap();
+	let text = temp.child("notes.txt");
+	text.write_str("first\r\nsecond\nthird\r\n").unwrap();
+	let binary = temp.child("image.bin");
+	binary.write_binary(b"\0\r\n\x89").unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = BackupConfig::new(vec![
+		Watch::new(text.to_path_buf(), destination.to_path_buf()),
+		Watch::new(binary.to_path_buf(), destination.to_path_buf())
+	]);
+	config.once = true;
+	config.normalize_eol = Some(LineEnding::Lf);
+	run(config).unwrap();
+	destination.child("notes.txt").assert("first\nsecond\nthird\n");
+	destination.child("image.bin").assert(&b"\0\r\n\x89"[..]);
+}
+
+/// The backup of `content` with its line endings normalized to `ending`.
+fn normalized_backup(content: &[u8], ending: LineEnding) -> Vec<u8> {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_binary(content).unwrap();
+	let destination = temp.child("backup");
+
+	let mut config = once_config(source.path(), destination.path());
+	config.normalize_eol = Some(ending);
+	run(config).unwrap();
+	std::fs::read(destination.child("notes.txt").path()).unwrap()
+}
+
+#[test]
+fn normalizes_the_line_endings_split_across_chunks() {
+	// The chunks read from the source are 8 KB long
+	let mut content = vec![b'a'; 8191];
+	content.extend_from_slice(b"\r\nb");
+	let mut expected = vec![b'a'; 8191];
+	expected.extend_from_slice(b"\nb");
+	assert_eq!(normalized_backup(&content, LineEnding::Lf), expected);
+}
+
+#[test]
+fn keeps_the_lone_carriage_returns() {
+	assert_eq!(normalized_backup(b"first\r\r\nlast\r", LineEnding::Lf), b"first\r\nlast\r");
+	assert_eq!(normalized_backup(b"first\r\r\nlast\r", LineEnding::Crlf), b"first\r\r\nlast\r");
+}
+
+#[test]
+fn only_looks_for_binary_content_at_the_start_of_files() {
+	// A NUL byte past the first 8 KB doesn't make a binary file
+	let mut content = vec![b'a'; 9000];
+	content.extend_from_slice(b"\0\r\n");
+	let mut expected = vec![b'a'; 9000];
+	expected.extend_from_slice(b"\0\n");
+	assert_eq!(normalized_backup(&content, LineEnding::Lf), expected);
+}
+
+/// Run the program in `dir` with `args` and the environment `variables` only, and whether it succeeded.
+fn run_program(dir: &Path, args: &[&str], variables: &[(&str, &str)]) -> bool {
+	std::process::Command::new(env!("CARGO_BIN_EXE_file-watcher-backup"))
+		.current_dir(dir)
+		.args(args)
+		.arg("--once")
+		.arg("--no-file-log")
+		.env_clear()
+		.env("HOME", dir)
+		.envs(variables.iter().cloned())
+		.status()
+		.unwrap()
+		.success()
+}
+
+#[test]
+fn takes_the_sources_and_destinations_of_the_command_line_over_the_environment() {
+	let temp = TempDir::new().unwrap();
+	temp.child("notes.txt").write_str("notes").unwrap();
+	temp.child("other.txt").write_str("other").unwrap();
+	temp.child("backup").create_dir_all().unwrap();
+	temp.child("other").create_dir_all().unwrap();
+
+	assert!(run_program(temp.path(), &["notes.txt", "backup"], &[("FWB_SOURCE", "other.txt"), ("FWB_DESTINATION", "other"), ("FWB_CONFIG", "missing.toml")]));
+	temp.child("backup/notes.txt").assert("notes");
+	assert_eq!(count_backups(temp.child("backup").path(), "other"), 0);
+	assert_eq!(count_backups(temp.child("other").path(), ""), 0);
+
+	// Missing on the command line, they're taken from the environment
+	assert!(run_program(temp.path(), &[], &[("FWB_SOURCE", "other.txt"), ("FWB_DESTINATION", "other")]));
+	temp.child("other/other.txt").assert("other");
+}
+
+#[test]
+fn ignores_the_environment_the_command_line_leaves_no_room_for() {
+	let temp = TempDir::new().unwrap();
+	temp.child("notes.txt").write_str("notes").unwrap();
+	temp.child("backup").create_dir_all().unwrap();
+	temp.child("watch.toml").write_str("[[watch]]\nsource = \"notes.txt\"\ndestination = \"backup\"\n").unwrap();
+
+	assert!(run_program(temp.path(), &["--config", "watch.toml"], &[("FWB_DESTINATION", "other")]));
+	temp.child("backup/notes.txt").assert("notes");
+	assert!(!temp.child("other").path().exists());
+
+	// --keep requires --versioned
+	assert!(run_program(temp.path(), &["notes.txt", "versions"], &[("FWB_KEEP", "1")]));
+	temp.child("versions/notes.txt").assert("notes");
+	assert!(!run_program(temp.path(), &["notes.txt", "versions", "--versioned", "--keep", "0"], &[("FWB_KEEP", "1")]));
+}
+
+/// Connect to the control socket `path` of a starting instance, waiting for it to listen.
+#[cfg(unix)]
+fn connect_control_socket(path: &Path) -> std::os::unix::net::UnixStream {
+	for _ in 0..50 {
+		if let Ok(stream) = std::os::unix::net::UnixStream::connect(path) {
+			return stream;
+		}
+		std::thread::sleep(Duration::from_millis(100));
+	}
+	panic!("Nothing listens on `{:?}`", path);
+}
+
+/// Send `command` to the control socket `stream`, and its answer.
+#[cfg(unix)]
+fn send_command(stream: &mut std::os::unix::net::UnixStream, command: &str) -> String {
+	use std::io::{BufRead, BufReader, Write};
+
+	writeln!(stream, "{}", command).unwrap();
+	let mut answer = String::new();
+	for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+		let line = line.unwrap();
+		if line.is_empty() {
+			break;
+		}
+		answer.push_str(&line);
+		answer.push('\n');
+	}
+	answer
+}
+
+#[cfg(unix)]
+#[test]
+fn answers_the_commands_of_the_control_socket() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let destination = temp.child("backup");
+	let socket = temp.child("control.sock");
+
+	let mut config = BackupConfig::new(vec![Watch::new(source.path().to_path_buf(), destination.path().to_path_buf())]);
+	config.control_socket = Some(socket.path().to_path_buf());
+	config.max_runtime = Some(Duration::from_secs(3));
+	let running = std::thread::spawn(move || run(config));
+	let mut stream = connect_control_socket(socket.path());
+
+	{
+		use std::os::unix::fs::PermissionsExt;
+		assert_eq!(std::fs::metadata(socket.path()).unwrap().permissions().mode() & 0o777, 0o600);
+	}
+	let status = send_command(&mut stream, "status");
+	assert!(status.contains("sources: 1\n"), "{}", status);
+	assert!(status.contains(": 1 ok, 0 failed"), "{}", status);
+	// The source is backed up already
+	assert_eq!(send_command(&mut stream, "backup-now"), "backed up 1 source(s): 0 copied, 1 unchanged, 0 failed\n");
+	assert!(send_command(&mut stream, "restart").starts_with("error: unknown command"));
+	drop(stream);
+
+	running.join().unwrap().unwrap();
+	destination.child("notes.txt").assert("content");
+	assert!(!socket.path().exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn never_replaces_a_file_by_the_control_socket() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("notes.txt");
+	source.write_str("content").unwrap();
+	let not_socket = temp.child("control.sock");
+	not_socket.write_str("precious").unwrap();
+
+	let mut config = BackupConfig::new(vec![Watch::new(source.path().to_path_buf(), temp.child("backup").path().to_path_buf())]);
+	config.control_socket = Some(not_socket.path().to_path_buf());
+	assert!(matches!(run(config), Err(BackupError::ControlSocketFailed(_, _))));
+	not_socket.assert("precious");
+}
+
+#[cfg(unix)]
+#[test]
+fn removes_the_compressed_backups_of_deleted_files() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("src");
+	source.child("notes.txt").write_str("content").unwrap();
+	let destination = temp.child("backup");
+	let socket = temp.child("control.sock");
+
+	let mut config = BackupConfig::new(vec![Watch::new(source.path().to_path_buf(), destination.path().to_path_buf())]);
+	config.compress = true;
+	config.mirror_deletes = true;
+	config.debounce = Duration::from_millis(100);
+	config.control_socket = Some(socket.path().to_path_buf());
+	config.max_runtime = Some(Duration::from_secs(3));
+	let running = std::thread::spawn(move || run(config));
+	let mut stream = connect_control_socket(socket.path());
+	// Answered once the sources are watched
+	send_command(&mut stream, "status");
+	assert!(destination.child("notes.txt.gz").path().exists());
+
+	std::fs::remove_file(source.child("notes.txt").path()).unwrap();
+	running.join().unwrap().unwrap();
+	assert!(!destination.child("notes.txt.gz").path().exists());
+}
+
+#[test]
+fn prunes_the_versions_of_sources_named_like_compressed_backups() {
+	let temp = TempDir::new().unwrap();
+	let source = temp.child("log.gz");
+	let destination = temp.child("backup");
+	for content in ["first", "second", "third"] {
+		source.write_str(content).unwrap();
+		let mut config = once_config(source.path(), destination.path());
+		config.versioned = true;
+		config.keep = Some(1);
+		run(config).unwrap();
+	}
+	assert_eq!(count_backups(destination.path(), "log."), 1);
+	let versions = versions::list(destination.path(), source.path()).unwrap();
+	assert_eq!(versions.len(), 1);
+	assert!(!versions[0].compressed);
+	assert_eq!(std::fs::read_to_string(&versions[0].path).unwrap(), "third");
+}